@@ -1,76 +1,606 @@
+pub mod durable;
+pub mod registry;
+
+pub use registry::{Callback, CallbackPanic, PendingRegistry, Progress, ReplyMode, StreamCallback};
+
+use crate::actor_env;
+use crate::correlation::{self, CallKind};
+use crate::error::Error;
+use crate::executor;
+use crate::intercom::envelope;
+use crate::transport;
 use crate::wascc_actor as actor;
 use actor::prelude::*;
 use codec::messaging;
 use codec::messaging::BrokerMessage;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
 use tea_codec;
 
-// pub type ActionCallback = FnMut(&BrokerMessage) -> HandlerResult<()>;
+/// Seconds a pending call waits for its reply before `sweep_expired` gives up on it.
+pub const DEFAULT_TIMEOUT_SECONDS: u64 = 60;
+
 lazy_static! {
-    pub static ref MAP_HANDLER: Mutex<
-        HashMap<
-            String,
-            Box<dyn FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static>,
-        >,
-    > = Mutex::new(HashMap::new());
+    pub static ref MAP_HANDLER: Mutex<PendingRegistry> = Mutex::new(PendingRegistry::new());
+    static ref DEAD_LETTER_HOOK: Mutex<Option<DeadLetterHook>> = Mutex::new(None);
+    static ref DRAIN: Mutex<Option<Drain>> = Mutex::new(None);
+}
+
+#[cfg(test)]
+lazy_static! {
+    static ref PENDING_CALLS_TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Serializes the tests that depend on the global pending calls, such as a drain.
+#[cfg(test)]
+pub(crate) fn lock_pending_calls() -> MutexGuard<'static, ()> {
+    PENDING_CALLS_TEST_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Receives the replies whose correlation uuid matches no pending, cancelled or durable call.
+pub type DeadLetterHook =
+    Arc<dyn Fn(&BrokerMessage, &str) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+/// Lock `MAP_HANDLER`. Callbacks never run while it is locked, so a registry poisoned by a
+/// panic is still consistent and is used as it is.
+pub fn pending_registry() -> MutexGuard<'static, PendingRegistry> {
+    MAP_HANDLER.lock().unwrap_or_else(|poisoned| {
+        warn!("pending call registry was poisoned, recovering");
+        MAP_HANDLER.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+/// Hand dead letters to `hook` instead of logging them, replaces the previous hook.
+pub fn set_dead_letter_hook<F>(hook: F)
+where
+    F: Fn(&BrokerMessage, &str) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    *DEAD_LETTER_HOOK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(hook));
 }
 
+pub fn clear_dead_letter_hook() {
+    *DEAD_LETTER_HOOK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+}
+
+fn dead_letter(msg: &BrokerMessage, uuid: &str) -> anyhow::Result<()> {
+    let hook = DEAD_LETTER_HOOK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone();
+    match hook {
+        Some(hook) => registry::isolate(|| hook(msg, uuid)),
+        None => {
+            error!("Cannot find callback function from hashmap. Cannot callbck");
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallOptions {
+    /// Seconds to wait for the reply, counted from the moment the call is sent.
+    pub timeout_seconds: u64,
+    /// When sending the call fails, hand the error to the callback and return `Ok(())` instead
+    /// of returning the error to the caller.
+    pub errors_to_callback: bool,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        CallOptions {
+            timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
+            errors_to_callback: false,
+        }
+    }
+}
+
+/// Handle of a call waiting for its reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingCall {
+    uuid: String,
+}
+
+impl PendingCall {
+    pub fn new(uuid: String) -> Self {
+        PendingCall { uuid }
+    }
+
+    /// Correlation uuid of the call.
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Abandon the call: its callback is dropped without being invoked and a late reply is
+    /// ignored. Returns false if the call has already been settled.
+    pub fn cancel(&self) -> bool {
+        let cancelled = pending_registry().cancel(&self.uuid);
+        if cancelled {
+            durable::forget(&self.uuid);
+        }
+        cancelled
+    }
+}
+
+/// New correlation id for a host call, see `correlation` for the format.
 pub fn get_uuid() -> String {
-    let extras = extras::default();
-    extras.get_sequence_number().unwrap().to_string()
+    correlation::next(CallKind::Call).to_string()
+}
+
+/// Number of calls still waiting for a reply.
+pub fn outstanding() -> usize {
+    pending_registry().len()
 }
 
+/// Settle the pending call `uuid` with the reply `msg`. `uuid` may be the last token of an
+/// intercom reply subject, its `intercom::envelope` suffix is ignored.
+///
+/// A panicking callback is reported as `CallbackPanic` where panics unwind, on
+/// `wasm32-unknown-unknown` it aborts the actor, see `registry::isolate`.
 pub fn result_handler(msg: &BrokerMessage, uuid: &str) -> anyhow::Result<()> {
     trace!("action result_handler received message: {:?}", msg);
-    let callback = {
-        match MAP_HANDLER.lock() {
-            Ok(mut hash_map) => hash_map.remove(uuid),
-            Err(e) => {
-                error!("Result handler lock failed, details: {:?}", e);
-                None
+    let uuid = envelope::reply_uuid(uuid);
+    let (entry, cancelled) = {
+        let mut hash_map = pending_registry();
+        let entry = hash_map.take(uuid);
+        let cancelled = entry.is_none() && hash_map.take_cancelled(uuid);
+        (entry, cancelled)
+    };
+    let result = match entry {
+        Some(mut entry) => {
+            let (result, done) = entry.deliver(msg);
+            let drained = {
+                let mut hash_map = pending_registry();
+                if done {
+                    hash_map.finish(uuid);
+                    None
+                } else {
+                    hash_map.restore(uuid.to_string(), entry)
+                }
+            };
+            // the drain finished while the reply was delivered, the call ends with it
+            if let Some(entry) = drained {
+                warn!("abandon pending call {} at shutdown", uuid);
+                let shutdown = Error::shutdown(entry.capability(), entry.operation(), uuid);
+                if let Err(e) = entry.fail(shutdown.into()) {
+                    error!("callback of abandoned call {} returned error: {}", uuid, e);
+                }
             }
+            result
+        }
+        None if cancelled => {
+            debug!("drop reply of cancelled call {}", uuid);
+            Ok(())
         }
+        None => match durable::rehydrate(uuid, msg) {
+            Some(result) => result,
+            None => dead_letter(msg, uuid),
+        },
     };
-    match callback {
-        Some(mut callback) => callback(&msg),
-        None => {
-            error!("Cannot find callback function from hashmap. Cannot callbck");
+    if let Err(e) = sweep_expired() {
+        warn!("sweep expired pending calls failed: {}", e);
+    }
+    executor::run_until_stalled();
+    if let Err(e) = check_drain() {
+        error!("drain pending calls failed: {}", e);
+    }
+    result
+}
+
+/// Remove every pending call whose deadline has passed and invoke its callback with an
+/// `Error::Timeout`. Returns the number of calls swept.
+///
+/// `result_handler` sweeps after each reply, actors that need a tighter bound can also call
+/// this from a timer.
+pub fn sweep_expired() -> anyhow::Result<usize> {
+    let now = actor_env::get_system_time()?;
+    let expired = pending_registry().take_expired(now);
+    let count = expired.len();
+    for (uuid, entry) in expired {
+        warn!("pending call {} expired without reply", uuid);
+        let timeout = Error::timeout(entry.capability(), entry.operation(), &uuid);
+        if let Err(e) = entry.fail(timeout.into()) {
+            error!("callback of expired call {} returned error: {}", uuid, e);
+        }
+    }
+    if count > 0 {
+        executor::run_until_stalled();
+    }
+    check_drain()?;
+    Ok(count)
+}
+
+/// Pending call handed `Error::Shutdown` by a drain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AbandonedCall {
+    pub uuid: String,
+    pub capability: String,
+    pub operation: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DrainReport {
+    /// Calls pending when the drain started.
+    pub pending_at_start: usize,
+    /// Calls still pending at the deadline, in uuid order.
+    pub abandoned: Vec<AbandonedCall>,
+}
+
+type DrainCallback = Box<dyn FnOnce(DrainReport) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+struct Drain {
+    deadline: SystemTime,
+    pending_at_start: usize,
+    callback: DrainCallback,
+    // delayed call firing at the deadline, not one of the calls being drained
+    timer: Option<PendingCall>,
+}
+
+impl Drain {
+    fn settled(&self) -> bool {
+        let registry = pending_registry();
+        let timer = match &self.timer {
+            Some(timer) if registry.contains(timer.uuid()) => 1,
+            _ => 0,
+        };
+        registry.len() <= timer
+    }
+}
+
+/// Prepare the actor for unloading: new calls are refused with `Error::Shutdown` from now on,
+/// replies to pending calls are still delivered. Once no call is pending, or at `deadline`, the
+/// calls left are handed `Error::Shutdown` and `callback` receives the report. Durable calls
+/// keep their record for the next instance.
+///
+/// The deadline is armed as a delayed call on `delay_subject`, as for any delayed call the actor
+/// has to route `{delay_subject}.{uuid}` to `result_handler`. A deadline already passed settles
+/// the drain before returning.
+pub fn drain<F>(deadline: SystemTime, delay_subject: &str, callback: F) -> anyhow::Result<()>
+where
+    F: FnOnce(DrainReport) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    if is_draining() {
+        return Err(anyhow::anyhow!("pending calls are already draining"));
+    }
+    let pending_at_start = pending_registry().len();
+    let timer = match actor_env::get_system_time() {
+        Ok(now) => match deadline.duration_since(now) {
+            Ok(left) if left > Duration::from_secs(0) => {
+                // rounded up, firing early would leave the drain waiting for the next reply
+                let delay_seconds = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+                Some(delay_call_ex(
+                    delay_subject,
+                    Vec::new(),
+                    delay_seconds,
+                    CallOptions::default(),
+                    |_| Ok(()),
+                )?)
+            }
+            _ => None,
+        },
+        Err(e) => {
+            warn!("cannot get system time, drain deadline is not armed: {}", e);
+            None
+        }
+    };
+    pending_registry().set_draining(true);
+    info!("draining {} pending call(s)", pending_at_start);
+    *DRAIN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Drain {
+        deadline,
+        pending_at_start,
+        callback: Box::new(callback),
+        timer,
+    });
+    check_drain()
+}
+
+/// Returns true once `drain` has been called.
+pub fn is_draining() -> bool {
+    pending_registry().is_draining()
+}
+
+/// Finish a drain now instead of waiting for its deadline.
+pub fn abandon_pending() -> anyhow::Result<()> {
+    let drain = DRAIN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .take();
+    match drain {
+        Some(drain) => finish_drain(drain),
+        None => Err(anyhow::anyhow!("pending calls are not draining")),
+    }
+}
+
+fn check_drain() -> anyhow::Result<()> {
+    let mut state = DRAIN
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let due = match state.as_ref() {
+        Some(drain) => {
+            drain.settled()
+                || actor_env::get_system_time()
+                    .map(|now| now >= drain.deadline)
+                    .unwrap_or(false)
+        }
+        None => false,
+    };
+    match state.take() {
+        Some(drain) if due => {
+            drop(state);
+            finish_drain(drain)
+        }
+        drain => {
+            *state = drain;
             Ok(())
         }
     }
 }
 
+fn finish_drain(drain: Drain) -> anyhow::Result<()> {
+    if let Some(timer) = &drain.timer {
+        timer.cancel();
+    }
+    let entries = pending_registry().take_all();
+    let mut abandoned = Vec::with_capacity(entries.len());
+    for (uuid, entry) in entries {
+        warn!("abandon pending call {} at shutdown", uuid);
+        abandoned.push(AbandonedCall {
+            uuid: uuid.clone(),
+            capability: entry.capability().to_string(),
+            operation: entry.operation().to_string(),
+        });
+        let shutdown = Error::shutdown(entry.capability(), entry.operation(), &uuid);
+        if let Err(e) = entry.fail(shutdown.into()) {
+            error!("callback of abandoned call {} returned error: {}", uuid, e);
+        }
+    }
+    executor::run_until_stalled();
+    (drain.callback)(DrainReport {
+        pending_at_start: drain.pending_at_start,
+        abandoned,
+    })
+}
+
+// a deadline beyond what `SystemTime` can hold, e.g. `u64::MAX` seconds, means no deadline
+fn deadline_after(seconds: u64) -> Option<SystemTime> {
+    match actor_env::get_system_time() {
+        Ok(now) => now.checked_add(Duration::from_secs(seconds)),
+        Err(e) => {
+            warn!(
+                "cannot get system time, pending call will not expire: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+fn register(
+    uuid: String,
+    capid: &str,
+    operation: &str,
+    callback: StreamCallback,
+    mode: ReplyMode,
+    timeout_seconds: u64,
+) -> anyhow::Result<()> {
+    let deadline = deadline_after(timeout_seconds);
+    let mut registry = pending_registry();
+    // registered all the same, so that the callback can be handed the error
+    let refused = registry.is_draining();
+    registry.insert_stream(uuid, capid, operation, callback, mode, deadline);
+    if refused {
+        return Err(Error::shutdown(capid, operation, "").into());
+    }
+    Ok(())
+}
+
+/// Roll back the registration of a call that could not be sent, then report `error` the way
+/// `options` asks for.
+fn settle_send_failure(
+    uuid: String,
+    error: anyhow::Error,
+    options: &CallOptions,
+) -> anyhow::Result<PendingCall> {
+    let entry = pending_registry().remove(&uuid);
+    match entry {
+        Some(entry) if options.errors_to_callback => {
+            entry.fail(error)?;
+            Ok(PendingCall::new(uuid))
+        }
+        _ => Err(error),
+    }
+}
+
+/// Adapt a callback that only handles replies, errors such as timeouts are returned to the
+/// caller of the callback and logged there, e.g. by `sweep_expired`.
+fn on_reply<F>(mut callback: F) -> Callback
+where
+    F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    Box::new(move |reply| callback(reply?))
+}
+
+/// The callback only sees replies: when none arrives in time, or the call is abandoned by
+/// `drain`, the error is only logged and the callback is never told. Use `call_async_ex` to be told.
 pub fn call_async<F, P>(
     capid: &str,
     operation: &str,
-    mut gen_payload: P,
+    gen_payload: P,
     callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<PendingCall>
 where
     F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
     P: FnMut(&str) -> anyhow::Result<Vec<u8>> + Sync + Send + 'static,
 {
-    let uuid = get_uuid();
-    MAP_HANDLER
-        .lock()
-        .unwrap()
-        .insert(uuid.clone(), Box::new(callback));
-    if let Err(e) = untyped::default().call(capid, operation, gen_payload(&uuid)?) {
-        error!("actor calls {} {} error: {}", capid, operation, e);
+    call_async_ex(
+        capid,
+        operation,
+        gen_payload,
+        CallOptions::default(),
+        on_reply(callback),
+    )
+}
+
+/// Like `call_async`, but the callback is also invoked with the error when the reply does not
+/// arrive within `options.timeout_seconds`.
+pub fn call_async_ex<F, P>(
+    capid: &str,
+    operation: &str,
+    gen_payload: P,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<PendingCall>
+where
+    F: FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
+    P: FnMut(&str) -> anyhow::Result<Vec<u8>> + Sync + Send + 'static,
+{
+    send_async(
+        capid,
+        operation,
+        gen_payload,
+        options,
+        registry::single_shot(Box::new(callback)),
+        ReplyMode::Count(1),
+        CallKind::Call,
+    )
+}
+
+/// Like `call_async_ex`, but the callback stays registered for as many replies as `mode`
+/// allows. Each delivery comes with its `Progress`.
+pub fn call_async_stream<F, P>(
+    capid: &str,
+    operation: &str,
+    gen_payload: P,
+    mode: ReplyMode,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<PendingCall>
+where
+    F: FnMut(anyhow::Result<&BrokerMessage>, Progress) -> anyhow::Result<()>
+        + Sync
+        + Send
+        + 'static,
+    P: FnMut(&str) -> anyhow::Result<Vec<u8>> + Sync + Send + 'static,
+{
+    send_async(
+        capid,
+        operation,
+        gen_payload,
+        options,
+        Box::new(callback),
+        mode,
+        CallKind::Call,
+    )
+}
+
+/// Register a multi-shot callback for replies correlated by `uuid` to a request the caller
+/// sends on its own, e.g. the `reply_to` of `ipfs_p2p::async_pull_cid_data` or a layer1 event
+/// subscription.
+pub fn expect_replies<F>(
+    uuid: &str,
+    mode: ReplyMode,
+    options: CallOptions,
+    callback: F,
+) -> PendingCall
+where
+    F: FnMut(anyhow::Result<&BrokerMessage>, Progress) -> anyhow::Result<()>
+        + Sync
+        + Send
+        + 'static,
+{
+    let registered = register(
+        uuid.to_string(),
+        "",
+        "",
+        Box::new(callback),
+        mode,
+        options.timeout_seconds,
+    );
+    if let Err(e) = registered {
+        let entry = pending_registry().remove(uuid);
+        if let Some(Err(e)) = entry.map(|entry| entry.fail(e)) {
+            error!("callback of refused call {} returned error: {}", uuid, e);
+        }
     }
+    PendingCall::new(uuid.to_string())
+}
 
-    Ok(())
+fn send_async<P>(
+    capid: &str,
+    operation: &str,
+    mut gen_payload: P,
+    options: CallOptions,
+    callback: StreamCallback,
+    mode: ReplyMode,
+    kind: CallKind,
+) -> anyhow::Result<PendingCall>
+where
+    P: FnMut(&str) -> anyhow::Result<Vec<u8>> + Sync + Send + 'static,
+{
+    let uuid = correlation::next(kind).to_string();
+    if let Err(e) = register(
+        uuid.clone(),
+        capid,
+        operation,
+        callback,
+        mode,
+        options.timeout_seconds,
+    ) {
+        return settle_send_failure(uuid, e, &options);
+    }
+    let sent = gen_payload(&uuid).and_then(|payload| {
+        transport::default()
+            .request(capid, operation, payload)
+            .map_err(Into::into)
+    });
+    match sent {
+        Ok(_) => Ok(PendingCall::new(uuid)),
+        Err(e) => settle_send_failure(uuid, e, &options),
+    }
 }
 
-pub fn call<F>(subject: &str, reply_to: &str, param: Vec<u8>, callback: F) -> anyhow::Result<()>
+/// The callback only sees replies: when none arrives in time, or the call is abandoned by
+/// `drain`, the error is only logged and the callback is never told. Use `call_ex` to be told.
+pub fn call<F>(
+    subject: &str,
+    reply_to: &str,
+    param: Vec<u8>,
+    callback: F,
+) -> anyhow::Result<PendingCall>
 where
     F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    call_ex(
+        subject,
+        reply_to,
+        param,
+        CallOptions::default(),
+        on_reply(callback),
+    )
+}
+
+pub fn call_ex<F>(
+    subject: &str,
+    reply_to: &str,
+    param: Vec<u8>,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<PendingCall>
+where
+    F: FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
     let subject = subject.to_string();
     let reply_to = reply_to.to_string();
-    call_async(
+    send_async(
         "wascc:messaging",
         messaging::OP_PUBLISH_MESSAGE,
         move |uuid| {
@@ -80,78 +610,134 @@ where
                 reply_to: reply,
                 body: param.clone(),
             })
-            .map_err(|e| anyhow::anyhow!("{}", e))?;
+            .map_err(|e| {
+                Error::invalid_input("wascc:messaging", messaging::OP_PUBLISH_MESSAGE, e)
+            })?;
             Ok(payload)
         },
-        callback,
+        options,
+        registry::single_shot(Box::new(callback)),
+        ReplyMode::Count(1),
+        CallKind::Publish,
     )
 }
 
+/// The callback only sees replies: when none arrives in time, or the call is abandoned by
+/// `drain`, the error is only logged and the callback is never told. Use `call_async_intercom_ex` to be told.
 pub fn call_async_intercom<F>(
     send_to_actor: &str,
     reply_actor: &str,
     msg: BrokerMessage,
     callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<PendingCall>
 where
     F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
 {
-    let uuid = get_uuid();
-    MAP_HANDLER
-        .lock()
-        .unwrap()
-        .insert(uuid.clone(), Box::new(callback));
+    call_async_intercom_ex(
+        send_to_actor,
+        reply_actor,
+        msg,
+        CallOptions::default(),
+        on_reply(callback),
+    )
+}
 
-    if let Err(e) = request_intercom(send_to_actor, reply_actor, uuid, msg) {
-        error!("calls asyc intercom error {}", e);
+pub fn call_async_intercom_ex<F>(
+    send_to_actor: &str,
+    reply_actor: &str,
+    msg: BrokerMessage,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<PendingCall>
+where
+    F: FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let uuid = correlation::next(CallKind::Intercom).to_string();
+    if let Err(e) = register(
+        uuid.clone(),
+        "tea:intercom",
+        tea_codec::OP_INTERCOM_MESSAGE,
+        registry::single_shot(Box::new(callback)),
+        ReplyMode::Count(1),
+        options.timeout_seconds,
+    ) {
+        return settle_send_failure(uuid, e, &options);
+    }
+
+    let sent = send_intercom_request(
+        send_to_actor,
+        reply_actor,
+        uuid.clone(),
+        msg,
+        options.timeout_seconds,
+    );
+    match sent {
+        Ok(_) => Ok(PendingCall::new(uuid)),
+        Err(e) => settle_send_failure(uuid, e, &options),
     }
-    Ok(())
 }
 
+/// Send `msg` to `actor_name` without expecting a reply. Posts carry no
+/// `intercom::envelope::Envelope`, the receiving actor handles them outside of any chain.
 pub fn post_intercom(actor_name: &str, msg: &BrokerMessage) -> anyhow::Result<Vec<u8>> {
     let subject = format!("post.{}", actor_name);
-    match untyped::default().call(
+    Ok(transport::default().request(
         "tea:intercom",
         tea_codec::OP_INTERCOM_MESSAGE,
         serialize_msg(subject, "".into(), msg)?,
-    ) {
-        Err(e) => Err(anyhow::anyhow!(
-            "actor calls intercom provider publish error {}",
-            e
-        )),
-        Ok(r) => Ok(r),
-    }
+    )?)
 }
 
+/// Send `msg` to `actor_name` with a reply subject ending in `uuid`, normally an id from
+/// `correlation::next(CallKind::Intercom)`.
+///
+/// The reply subject also carries an `intercom::envelope::Envelope`. Within a chain of requests
+/// the request is refused once the hop limit or the deadline of the chain is exceeded.
 pub fn request_intercom(
+    actor_name: &str,
+    my_actor_name: &str,
+    uuid: String,
+    msg: BrokerMessage,
+) -> anyhow::Result<Vec<u8>> {
+    send_intercom_request(
+        actor_name,
+        my_actor_name,
+        uuid,
+        msg,
+        DEFAULT_TIMEOUT_SECONDS,
+    )
+}
+
+fn send_intercom_request(
     actor_name: &str,
     my_actor_name: &str,
     uuid: String,
     mut msg: BrokerMessage,
+    timeout_seconds: u64,
 ) -> anyhow::Result<Vec<u8>> {
     if !msg.reply_to.is_empty() {
-        return Err(anyhow::anyhow!("When calling request_intercom, always leave reply_to empty, because it is used for response socket"));
+        return Err(Error::invalid_input(
+            "tea:intercom",
+            tea_codec::OP_INTERCOM_MESSAGE,
+            "When calling request_intercom, always leave reply_to empty, because it is used for response socket",
+        )
+        .into());
     }
+    let envelope = envelope::outgoing(tea_codec::OP_INTERCOM_MESSAGE, timeout_seconds)?;
     let subject = format!("request.{}", actor_name);
-    msg.reply_to = format!("reply.{}.{}", my_actor_name, uuid);
-    match untyped::default().call(
+    msg.reply_to = format!("reply.{}.{}", my_actor_name, envelope.reply_token(&uuid));
+    Ok(transport::default().request(
         "tea:intercom",
         tea_codec::OP_INTERCOM_MESSAGE,
         serialize_msg(subject, msg.reply_to.clone(), &msg)?,
-    ) {
-        Err(e) => Err(anyhow::anyhow!(
-            "actor calls intercom provider publish error {}",
-            e
-        )),
-        Ok(r) => Ok(r),
-    }
+    )?)
 }
 
-//to avoid endless ping-pong. we have to have a reply_intercom to end
-//when reply an incoming intercom, you cannot call another intercom, you can only
-//call reply_intercom so that it will end because no callback function as input parameter
+/// Answer an intercom request on its `reply_to` subject. Replies do not count as hops, a handler
+/// may send further requests before replying as long as it handles the message within the scope
+/// of `intercom::envelope::accept`, which bounds the chain.
 pub fn reply_intercom(subject: &str, body: Vec<u8>) -> anyhow::Result<()> {
-    if let Err(e) = untyped::default().call(
+    if let Err(e) = transport::default().call(
         "tea:intercom",
         tea_codec::OP_INTERCOM_MESSAGE,
         serialize(BrokerMessage {
@@ -159,7 +745,7 @@ pub fn reply_intercom(subject: &str, body: Vec<u8>) -> anyhow::Result<()> {
             reply_to: "".into(),
             body, // the body content is the msg to be delivered
         })
-        .map_err(|e| anyhow::anyhow!("{}", e))?,
+        .map_err(|e| Error::invalid_input("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, e))?,
     ) {
         error!("actor calls intercom provider publish error {}", e);
     }
@@ -171,42 +757,435 @@ fn serialize_msg(
     reply_to: String,
     msg: &BrokerMessage,
 ) -> anyhow::Result<Vec<u8>> {
-    let body = serialize(msg).map_err(|e| anyhow::anyhow!("{}", e))?;
+    let invalid = |e| Error::invalid_input("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, e);
+    let body = serialize(msg).map_err(invalid)?;
     Ok(serialize(BrokerMessage {
         subject,
         reply_to,
         body, // the body content is the msg to be delivered
     })
-    .map_err(|e| anyhow::anyhow!("{}", e))?)
+    .map_err(invalid)?)
 }
 
+/// The callback only sees replies: when none arrives in time, or the call is abandoned by
+/// `drain`, the error is only logged and the callback is never told. Use `delay_call_ex` to be told.
 pub fn delay_call<F>(
     subject: &str,
     param: Vec<u8>,
     delay_seconds: u64,
     callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<PendingCall>
 where
     F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
 {
-    let uuid = get_uuid();
+    delay_call_ex(
+        subject,
+        param,
+        delay_seconds,
+        CallOptions::default(),
+        on_reply(callback),
+    )
+}
 
-    let subject = format!("{}.{}", subject, uuid);
-    // TODO error here when tpm & layer1 both call, how to fix?
-    MAP_HANDLER.lock().unwrap().insert(uuid, Box::new(callback));
+/// Like `delay_call`, `options.timeout_seconds` is counted after the delay has elapsed.
+pub fn delay_call_ex<F>(
+    subject: &str,
+    param: Vec<u8>,
+    delay_seconds: u64,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<PendingCall>
+where
+    F: FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    // namespaced by kind, and by actor once `correlation::set_actor_name` is called
+    let uuid = correlation::next(CallKind::Delay).to_string();
 
-    if let Err(e) = untyped::default().call(
+    let subject = format!("{}.{}", subject, uuid);
+    if let Err(e) = register(
+        uuid.clone(),
         "wascc:messaging",
         tea_codec::OP_DELAY_PUBLISH,
-        serialize(tea_codec::DelayMessage {
-            delay_seconds,
-            subject: subject.to_string(),
-            reply_to: "".to_string(),
-            body: param,
-        })
-        .map_err(|e| anyhow::anyhow!("{}", e))?,
+        registry::single_shot(Box::new(callback)),
+        ReplyMode::Count(1),
+        delay_seconds.saturating_add(options.timeout_seconds),
     ) {
-        error!("actor ra calls nats provider publish error {}", e);
+        return settle_send_failure(uuid, e, &options);
+    }
+
+    let sent = serialize(tea_codec::DelayMessage {
+        delay_seconds,
+        subject,
+        reply_to: "".to_string(),
+        body: param,
+    })
+    .map_err(|e| Error::invalid_input("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, e).into())
+    .and_then(|payload| {
+        transport::default()
+            .request("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, payload)
+            .map_err(Into::into)
+    });
+    match sent {
+        Ok(_) => Ok(PendingCall::new(uuid)),
+        Err(e) => settle_send_failure(uuid, e, &options),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        abandon_pending, call_async, call_async_ex, call_async_intercom_ex, clear_dead_letter_hook,
+        delay_call_ex, drain, durable, expect_replies, lock_pending_calls, pending_registry,
+        request_intercom, result_handler, set_dead_letter_hook, sweep_expired, AbandonedCall,
+        CallOptions, CallbackPanic, DrainReport, ReplyMode,
+    };
+    use crate::error::Error;
+    use crate::intercom::envelope::{self, Envelope};
+    use crate::transport::{self, KvpSimulator, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::deserialize;
+
+    #[test]
+    fn panicking_callback_and_dead_letters_do_not_unwind() {
+        let _lock = lock_pending_calls();
+        let _guard = transport::replace(ScriptedTransport::new());
+        let reply = BrokerMessage {
+            subject: "reply".into(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+
+        expect_replies(
+            "panics-1",
+            ReplyMode::UntilDeadline,
+            CallOptions::default(),
+            |_, _| panic!("boom"),
+        );
+        let error = result_handler(&reply, "panics-1").unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CallbackPanic>(),
+            Some(&CallbackPanic("boom".into()))
+        );
+        assert!(!pending_registry().contains("panics-1"));
+
+        let dead = Arc::new(Mutex::new(Vec::new()));
+        let sink = dead.clone();
+        set_dead_letter_hook(move |_, uuid| {
+            sink.lock().unwrap().push(uuid.to_string());
+            Ok(())
+        });
+        result_handler(&reply, "panics-1").unwrap();
+        clear_dead_letter_hook();
+        assert!(dead.lock().unwrap().contains(&"panics-1".to_string()));
+    }
+
+    #[test]
+    fn send_failures_are_returned_or_handed_to_the_callback() {
+        let _lock = lock_pending_calls();
+        let scripted = ScriptedTransport::new();
+        scripted.fail("tea:ipfs", "Pin", "provider down");
+        scripted.fail("tea:ipfs", "Pin", "provider down");
+        let _guard = transport::replace(scripted.clone());
+        let pending = pending_registry().len();
+
+        let error = call_async("tea:ipfs", "Pin", |_| Ok(vec![]), |_| Ok(())).unwrap_err();
+        match error.downcast_ref::<Error>() {
+            Some(Error::HostCall { message, .. }) => assert_eq!(message, "provider down"),
+            other => panic!("unexpected error {:?}", other),
+        }
+        assert_eq!(pending_registry().len(), pending);
+
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let sink = failures.clone();
+        let options = CallOptions {
+            errors_to_callback: true,
+            ..CallOptions::default()
+        };
+        let call = call_async_ex(
+            "tea:ipfs",
+            "Pin",
+            |_| Ok(vec![]),
+            options,
+            move |reply| {
+                sink.lock().unwrap().push(reply.unwrap_err().to_string());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert!(!pending_registry().contains(call.uuid()));
+        assert_eq!(
+            *failures.lock().unwrap(),
+            vec!["tea:ipfs Pin host call failed: provider down"]
+        );
+        assert_eq!(scripted.unanswered(), 0);
+    }
+
+    #[test]
+    fn callbacks_continue_the_intercom_chain_of_their_call() {
+        let _lock = lock_pending_calls();
+        let scripted = ScriptedTransport::new();
+        let reply_tos = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..2 {
+            let sink = reply_tos.clone();
+            scripted.respond_with(
+                "tea:intercom",
+                tea_codec::OP_INTERCOM_MESSAGE,
+                move |call| {
+                    let msg: BrokerMessage = deserialize(&call.payload)?;
+                    sink.lock().unwrap().push(msg.reply_to);
+                    Ok(vec![])
+                },
+            );
+        }
+        let _guard = transport::replace(scripted.clone());
+        let outbound = || BrokerMessage {
+            subject: "actor.echo.intercom.say".into(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+
+        let inbound = BrokerMessage {
+            subject: "request.a".into(),
+            reply_to: format!(
+                "reply.x.{}",
+                Envelope {
+                    hops: 3,
+                    deadline_ms: None
+                }
+                .reply_token("x-intercom-1")
+            ),
+            body: vec![],
+        };
+        {
+            let _scope = envelope::accept(&inbound);
+            call_async_intercom_ex("b", "a", outbound(), CallOptions::default(), move |reply| {
+                reply?;
+                request_intercom("c", "a", "a-intercom-forward".into(), outbound())?;
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(envelope::current(), None);
+
+        let to_b = reply_tos.lock().unwrap()[0].clone();
+        assert!(to_b.starts_with("reply.a.") && to_b.ends_with("~h4"));
+        let token = to_b.rsplit('.').next().unwrap();
+        let reply = BrokerMessage {
+            subject: to_b.clone(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        result_handler(&reply, token).unwrap();
+        assert!(!pending_registry().contains(envelope::reply_uuid(token)));
+        assert_eq!(
+            reply_tos.lock().unwrap()[1],
+            "reply.a.a-intercom-forward~h4"
+        );
+        assert_eq!(scripted.unanswered(), 0);
+    }
+
+    // the registry stays draining after a drain, other tests need it accepting calls again
+    struct Undrain;
+
+    impl Drop for Undrain {
+        fn drop(&mut self) {
+            pending_registry().set_draining(false);
+        }
+    }
+
+    #[test]
+    fn drain_delivers_replies_until_the_deadline() {
+        let _lock = lock_pending_calls();
+        let _undrain = Undrain;
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp.clone());
+        scripted.respond("tea:ipfs", "Pin", vec![]);
+        scripted.respond("tea:ipfs", "Pin", vec![]);
+        scripted.respond("wascc:messaging", "Publish", vec![]);
+        scripted.respond("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, vec![]);
+        let _guard = transport::replace(scripted.clone());
+        durable::enable("default").unwrap();
+
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let pin = || {
+            let sink = outcomes.clone();
+            call_async_ex(
+                "tea:ipfs",
+                "Pin",
+                |_| Ok(vec![]),
+                CallOptions::default(),
+                move |reply| {
+                    let outcome = reply.map(|msg| msg.body.clone()).map_err(|e| {
+                        matches!(e.downcast_ref::<Error>(), Some(Error::Shutdown { .. }))
+                    });
+                    sink.lock().unwrap().push(outcome);
+                    Ok(())
+                },
+            )
+        };
+        let answered = pin().unwrap();
+        let left = pin().unwrap();
+        let stored = durable::call_durable(
+            "ipfs.pin",
+            "actor.me.reply",
+            vec![],
+            "pinned",
+            &"QmCid",
+            CallOptions::default(),
+        )
+        .unwrap();
+
+        let report = Arc::new(Mutex::new(None));
+        let sink = report.clone();
+        let deadline = UNIX_EPOCH + Duration::from_secs(kvp.now() + 30);
+        drain(deadline, "actor.me.drain", move |r| {
+            *sink.lock().unwrap() = Some(r);
+            Ok(())
+        })
+        .unwrap();
+
+        let refused = pin().unwrap_err();
+        assert!(matches!(
+            refused.downcast_ref::<Error>(),
+            Some(Error::Shutdown { .. })
+        ));
+        let reply = BrokerMessage {
+            subject: "reply".into(),
+            reply_to: "".into(),
+            body: vec![1],
+        };
+        result_handler(&reply, answered.uuid()).unwrap();
+        assert_eq!(*outcomes.lock().unwrap(), vec![Ok(vec![1])]);
+        assert!(report.lock().unwrap().is_none());
+
+        let timer: tea_codec::DelayMessage = scripted
+            .calls()
+            .iter()
+            .find(|call| call.operation == tea_codec::OP_DELAY_PUBLISH)
+            .map(|call| deserialize(&call.payload).unwrap())
+            .unwrap();
+        assert_eq!(timer.delay_seconds, 30);
+        kvp.advance(30);
+        let fired = BrokerMessage {
+            subject: timer.subject.clone(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        result_handler(&fired, timer.subject.rsplit('.').next().unwrap()).unwrap();
+
+        assert_eq!(*outcomes.lock().unwrap(), vec![Ok(vec![1]), Err(true)]);
+        let mut abandoned = vec![
+            AbandonedCall {
+                uuid: left.uuid().to_string(),
+                capability: "tea:ipfs".into(),
+                operation: "Pin".into(),
+            },
+            AbandonedCall {
+                uuid: stored.uuid().to_string(),
+                capability: "wascc:messaging".into(),
+                operation: "Publish".into(),
+            },
+        ];
+        abandoned.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+        assert_eq!(
+            report.lock().unwrap().take(),
+            Some(DrainReport {
+                pending_at_start: 3,
+                abandoned,
+            })
+        );
+        assert_eq!(pending_registry().len(), 0);
+        assert!(kvp
+            .keys("default")
+            .iter()
+            .any(|k| k.contains(stored.uuid())));
+        assert_eq!(scripted.unanswered(), 0);
+    }
+
+    #[test]
+    fn huge_timeouts_never_expire() {
+        let _lock = lock_pending_calls();
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp.clone());
+        scripted.respond("tea:ipfs", "Pin", vec![]);
+        scripted.respond("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, vec![]);
+        let _guard = transport::replace(scripted.clone());
+        let options = CallOptions {
+            timeout_seconds: u64::MAX,
+            ..CallOptions::default()
+        };
+
+        let call = call_async_ex(
+            "tea:ipfs",
+            "Pin",
+            |_| Ok(vec![]),
+            options.clone(),
+            |_| Ok(()),
+        )
+        .unwrap();
+        let delayed =
+            delay_call_ex("actor.me.timer", vec![], u64::MAX, options, |_| Ok(())).unwrap();
+        kvp.advance(u64::from(u32::MAX));
+        assert_eq!(sweep_expired().unwrap(), 0);
+        assert!(pending_registry().contains(call.uuid()));
+        assert!(call.cancel() && delayed.cancel());
+    }
+
+    #[test]
+    fn streams_delivering_when_the_drain_finishes_are_abandoned() {
+        let _lock = lock_pending_calls();
+        let _undrain = Undrain;
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp);
+        scripted.respond("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, vec![]);
+        let _guard = transport::replace(scripted.clone());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        expect_replies(
+            "stream-1",
+            ReplyMode::UntilDeadline,
+            CallOptions::default(),
+            move |reply, progress| {
+                let shutdown = match reply {
+                    Ok(_) => false,
+                    Err(e) => e.downcast_ref::<Error>().map(|e| e.is_shutdown()) == Some(true),
+                };
+                sink.lock().unwrap().push((progress.seq, shutdown));
+                if progress.seq == 0 && !shutdown {
+                    abandon_pending()?;
+                }
+                Ok(())
+            },
+        );
+        let report = Arc::new(Mutex::new(None));
+        let report_sink = report.clone();
+        drain(
+            UNIX_EPOCH + Duration::from_secs(60),
+            "actor.me.drain",
+            move |r| {
+                *report_sink.lock().unwrap() = Some(r);
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        let reply = BrokerMessage {
+            subject: "reply".into(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        result_handler(&reply, "stream-1").unwrap();
+        assert_eq!(
+            report.lock().unwrap().take().map(|r| r.pending_at_start),
+            Some(1)
+        );
+        assert_eq!(*seen.lock().unwrap(), vec![(0, false), (1, true)]);
+        assert!(!pending_registry().contains("stream-1"));
     }
-    Ok(())
 }
@@ -0,0 +1,458 @@
+//! Pending calls that survive the actor instance.
+//!
+//! The callbacks of `MAP_HANDLER` live in memory and are lost when the actor instance is
+//! recycled. A durable call names its continuation instead and carries a serializable context.
+//! Its record is stored in kvp under the correlation uuid before the call is sent, so a reply
+//! reaching another instance is handed by `result_handler` to the continuation registered
+//! there under the same name.
+//!
+//! ```ignore
+//! // at start, in every instance
+//! durable::enable(BINDING)?;
+//! durable::register_continuation("pin_done", |cid: String, reply| {
+//!     //code snipet...
+//!     Ok(())
+//! })?;
+//!
+//! durable::call_durable("ipfs.pin", "actor.me.reply", body, "pin_done", &cid, CallOptions::default())?;
+//! ```
+//!
+//! Records of calls whose reply never arrives are only cleared by `sweep_expired`.
+
+use super::{pending_registry, registry, send_async, CallOptions, PendingCall, ReplyMode};
+use crate::actor_env;
+use crate::actor_kvp;
+use crate::correlation::CallKind;
+use crate::error::Error;
+use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
+use wascc_actor::prelude::codec::messaging::{self, BrokerMessage};
+use wascc_actor::prelude::*;
+
+const RECORDS_KEY: &str = "durable_calls";
+
+/// Continuation of a durable call, invoked with the encoded context and the reply or the reason
+/// why no reply is going to arrive.
+pub type Continuation = Arc<
+    dyn Fn(&[u8], anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
+>;
+
+/// What is stored in kvp for a durable call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DurableRecord {
+    pub continuation: String,
+    /// Context encoded with `tea_codec::serialize`.
+    pub context: Vec<u8>,
+    pub capability: String,
+    pub operation: String,
+    /// `actor_env::current_timestamp` after which the reply is not awaited anymore.
+    pub deadline: Option<i64>,
+}
+
+lazy_static! {
+    static ref BINDING: Mutex<Option<&'static str>> = Mutex::new(None);
+    static ref CONTINUATIONS: Mutex<HashMap<String, Continuation>> = Mutex::new(HashMap::new());
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> anyhow::Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|e| anyhow::anyhow!("durable calls lock failed: {:?}", e))
+}
+
+fn record_key(uuid: &str) -> String {
+    format!("durable_call_{}", uuid)
+}
+
+fn binding() -> anyhow::Result<&'static str> {
+    lock(&BINDING)?
+        .ok_or_else(|| anyhow::anyhow!("durable calls are not enabled, see durable::enable"))
+}
+
+/// Store the records of durable calls in the kvp `binding`.
+pub fn enable(binding: &'static str) -> anyhow::Result<()> {
+    *lock(&BINDING)? = Some(binding);
+    Ok(())
+}
+
+/// Continuation `name` with its context decoded as `C`, replaces the previous one.
+pub fn register_continuation<C, F>(name: &str, continuation: F) -> anyhow::Result<()>
+where
+    C: DeserializeOwned,
+    F: Fn(C, anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let name_owned = name.to_string();
+    let continuation: Continuation = Arc::new(move |context, reply| {
+        let context = tea_codec::deserialize(context).map_err(|e| {
+            anyhow::anyhow!(
+                "cannot decode context of continuation {}: {}",
+                name_owned,
+                e
+            )
+        })?;
+        continuation(context, reply)
+    });
+    lock(&CONTINUATIONS)?.insert(name.to_string(), continuation);
+    Ok(())
+}
+
+/// `action::call_ex` whose reply is handed to the continuation `continuation` with `context`.
+pub fn call_durable<C: Serialize>(
+    subject: &str,
+    reply_to: &str,
+    param: Vec<u8>,
+    continuation: &str,
+    context: &C,
+    options: CallOptions,
+) -> anyhow::Result<PendingCall> {
+    let subject = subject.to_string();
+    let reply_to = reply_to.to_string();
+    send_durable(
+        messaging::OP_PUBLISH_MESSAGE,
+        continuation,
+        context,
+        options,
+        CallKind::Publish,
+        move |uuid| {
+            Ok(serialize(BrokerMessage {
+                subject: subject.clone(),
+                reply_to: format!("{}.{}", reply_to, uuid),
+                body: param.clone(),
+            })
+            .map_err(|e| {
+                Error::invalid_input("wascc:messaging", messaging::OP_PUBLISH_MESSAGE, e)
+            })?)
+        },
+    )
+}
+
+/// `action::delay_call_ex` whose firing is handed to the continuation `continuation` with
+/// `context`.
+pub fn delay_call_durable<C: Serialize>(
+    subject: &str,
+    param: Vec<u8>,
+    delay_seconds: u64,
+    continuation: &str,
+    context: &C,
+    options: CallOptions,
+) -> anyhow::Result<PendingCall> {
+    let subject = subject.to_string();
+    let options = CallOptions {
+        timeout_seconds: delay_seconds.saturating_add(options.timeout_seconds),
+        ..options
+    };
+    send_durable(
+        tea_codec::OP_DELAY_PUBLISH,
+        continuation,
+        context,
+        options,
+        CallKind::Delay,
+        move |uuid| {
+            Ok(serialize(tea_codec::DelayMessage {
+                delay_seconds,
+                subject: format!("{}.{}", subject, uuid),
+                reply_to: "".to_string(),
+                body: param.clone(),
+            })
+            .map_err(|e| Error::invalid_input("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, e))?)
+        },
+    )
+}
+
+fn send_durable<C, P>(
+    operation: &str,
+    continuation: &str,
+    context: &C,
+    options: CallOptions,
+    kind: CallKind,
+    mut gen_payload: P,
+) -> anyhow::Result<PendingCall>
+where
+    C: Serialize,
+    P: FnMut(&str) -> anyhow::Result<Vec<u8>> + Sync + Send + 'static,
+{
+    let binding = binding()?;
+    // a deadline that does not fit in the timestamp means the call never expires
+    let deadline = match actor_env::current_timestamp() {
+        Ok(now) => i64::try_from(options.timeout_seconds)
+            .ok()
+            .and_then(|timeout| now.checked_add(timeout)),
+        Err(e) => {
+            warn!("cannot get timestamp, durable call will not expire: {}", e);
+            None
+        }
+    };
+    let record = DurableRecord {
+        continuation: continuation.to_string(),
+        context: tea_codec::serialize(context)
+            .map_err(|e| Error::invalid_input("wascc:messaging", operation, e))?,
+        capability: "wascc:messaging".to_string(),
+        operation: operation.to_string(),
+        deadline,
+    };
+    let callback_record = record.clone();
+    let uuid = Arc::new(Mutex::new(String::new()));
+    let payload_uuid = uuid.clone();
+    let callback_uuid = uuid.clone();
+    let sent = send_async(
+        "wascc:messaging",
+        operation,
+        move |id| {
+            *lock(&payload_uuid)? = id.to_string();
+            store(binding, id, &record)?;
+            gen_payload(id)
+        },
+        options,
+        Box::new(move |reply, _| {
+            let uuid = lock(&callback_uuid)?.clone();
+            let shutdown = match &reply {
+                Err(e) => e.downcast_ref::<Error>().map(|e| e.is_shutdown()),
+                Ok(_) => None,
+            };
+            if shutdown == Some(true) && !uuid.is_empty() {
+                debug!("keep durable call {} for the next instance", uuid);
+                return Ok(());
+            }
+            if let Err(e) = remove(binding, &uuid) {
+                warn!("cannot remove durable call {}: {}", uuid, e);
+            }
+            dispatch(&callback_record, reply)
+        }),
+        ReplyMode::Count(1),
+        kind,
+    );
+    if sent.is_err() {
+        // the call was never sent, no reply will come for its record
+        forget(&lock(&uuid)?);
+    }
+    sent
+}
+
+fn store(binding: &'static str, uuid: &str, record: &DurableRecord) -> anyhow::Result<()> {
+    actor_kvp::set_forever(binding, &record_key(uuid), record)?;
+    actor_kvp::set_add(binding, RECORDS_KEY, &uuid.to_string())?;
+    Ok(())
+}
+
+fn load(binding: &'static str, uuid: &str) -> anyhow::Result<Option<DurableRecord>> {
+    Ok(actor_kvp::get(binding, &record_key(uuid))?)
+}
+
+fn remove(binding: &'static str, uuid: &str) -> anyhow::Result<()> {
+    actor_kvp::del(binding, &record_key(uuid))?;
+    actor_kvp::set_remove(binding, RECORDS_KEY, &uuid.to_string())?;
+    Ok(())
+}
+
+fn dispatch(record: &DurableRecord, reply: anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> {
+    let continuation = lock(&CONTINUATIONS)?.get(&record.continuation).cloned();
+    match continuation {
+        Some(continuation) => registry::isolate(|| continuation(&record.context, reply)),
+        None => Err(anyhow::anyhow!(
+            "continuation {} is not registered",
+            record.continuation
+        )),
+    }
+}
+
+/// Hand a reply for a call unknown to this instance to its stored continuation. Returns `None`
+/// if durable calls are not enabled or `uuid` has no record.
+pub(crate) fn rehydrate(uuid: &str, msg: &BrokerMessage) -> Option<anyhow::Result<()>> {
+    let binding = binding().ok()?;
+    let record = match load(binding, uuid) {
+        Ok(record) => record?,
+        Err(e) => {
+            warn!("cannot load durable call {}: {}", uuid, e);
+            return None;
+        }
+    };
+    debug!("rehydrate durable call {}", uuid);
+    if let Err(e) = remove(binding, uuid) {
+        return Some(Err(e));
+    }
+    Some(dispatch(&record, Ok(msg)))
+}
+
+/// Forget the record of a cancelled call, if any.
+pub(crate) fn forget(uuid: &str) {
+    if let Ok(binding) = binding() {
+        if let Err(e) = remove(binding, uuid) {
+            warn!("cannot remove durable call {}: {}", uuid, e);
+        }
+    }
+}
+
+/// Hand an `Error::Timeout` to the continuation of every stored call that is past its deadline
+/// and not pending in this instance, e.g. because the instance that sent it was recycled.
+/// Returns the number of calls swept.
+pub fn sweep_expired() -> anyhow::Result<usize> {
+    let binding = binding()?;
+    let now = actor_env::current_timestamp()?;
+    let uuids: Vec<String> = actor_kvp::set_query(binding, RECORDS_KEY)?;
+    let mut count = 0;
+    for uuid in uuids {
+        if pending_registry().contains(&uuid) {
+            continue;
+        }
+        let record = match load(binding, &uuid)? {
+            Some(record) => record,
+            None => {
+                actor_kvp::set_remove(binding, RECORDS_KEY, &uuid)?;
+                continue;
+            }
+        };
+        if record.deadline.map(|d| d > now).unwrap_or(true) {
+            continue;
+        }
+        warn!("durable call {} expired without reply", uuid);
+        remove(binding, &uuid)?;
+        let timeout = Error::timeout(&record.capability, &record.operation, &uuid);
+        if let Err(e) = dispatch(&record, Err(timeout.into())) {
+            error!(
+                "continuation of expired call {} returned error: {}",
+                uuid, e
+            );
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        call_durable, enable, load, register_continuation, store, sweep_expired, DurableRecord,
+    };
+    use crate::action::{self, lock_pending_calls, pending_registry, CallOptions};
+    use crate::error::Error;
+    use crate::transport::{self, KvpSimulator, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+    #[test]
+    fn reply_for_unknown_call_is_rehydrated() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        enable("default").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        register_continuation("pinned", move |cid: String, reply| {
+            sink.lock().unwrap().push((cid, reply?.body.clone()));
+            Ok(())
+        })
+        .unwrap();
+
+        let uuid = "actor-publish-7";
+        let record = DurableRecord {
+            continuation: "pinned".into(),
+            context: tea_codec::serialize("QmCid").unwrap(),
+            capability: "wascc:messaging".into(),
+            operation: "Publish".into(),
+            deadline: None,
+        };
+        store("default", uuid, &record).unwrap();
+        let reply = BrokerMessage {
+            subject: format!("actor.me.reply.{}", uuid),
+            reply_to: "".into(),
+            body: vec![7],
+        };
+        action::result_handler(&reply, uuid).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![("QmCid".to_string(), vec![7])]);
+        assert!(kvp.keys("default").iter().all(|k| !k.contains(uuid)));
+
+        // the record is consumed by the first reply
+        action::result_handler(&reply, uuid).unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn expired_records_are_handed_a_timeout() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        enable("default").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        register_continuation("swept", move |cid: String, reply| {
+            let timed_out = matches!(
+                reply.unwrap_err().downcast_ref::<Error>(),
+                Some(Error::Timeout { .. })
+            );
+            sink.lock().unwrap().push((cid, timed_out));
+            Ok(())
+        })
+        .unwrap();
+        let record = |deadline| DurableRecord {
+            continuation: "swept".into(),
+            context: tea_codec::serialize("QmCid").unwrap(),
+            capability: "wascc:messaging".into(),
+            operation: "Publish".into(),
+            deadline,
+        };
+        store("default", "actor-publish-8", &record(Some(5))).unwrap();
+        store("default", "actor-publish-9", &record(None)).unwrap();
+
+        assert_eq!(sweep_expired().unwrap(), 0);
+        kvp.advance(5);
+        assert_eq!(sweep_expired().unwrap(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![("QmCid".to_string(), true)]);
+        assert_eq!(load("default", "actor-publish-8").unwrap(), None);
+        assert_eq!(
+            load("default", "actor-publish-9").unwrap(),
+            Some(record(None))
+        );
+    }
+
+    #[test]
+    fn records_survive_shutdown() {
+        let _lock = lock_pending_calls();
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp.clone());
+        scripted.respond("wascc:messaging", "Publish", vec![]);
+        let _guard = transport::replace(scripted.clone());
+        enable("default").unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        register_continuation("survivor", move |cid: String, reply| {
+            sink.lock().unwrap().push((cid, reply?.body.clone()));
+            Ok(())
+        })
+        .unwrap();
+
+        let options = CallOptions {
+            timeout_seconds: u64::MAX,
+            ..CallOptions::default()
+        };
+        let call = call_durable(
+            "ipfs.pin",
+            "actor.me.reply",
+            vec![],
+            "survivor",
+            &"QmCid",
+            options,
+        )
+        .unwrap();
+        let record = load("default", call.uuid()).unwrap().unwrap();
+        assert_eq!(record.deadline, None);
+
+        let entry = pending_registry().remove(call.uuid()).unwrap();
+        let shutdown = Error::shutdown("wascc:messaging", "Publish", call.uuid());
+        entry.fail(shutdown.into()).unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+        assert_eq!(load("default", call.uuid()).unwrap(), Some(record));
+
+        // the next instance gets the reply
+        let reply = BrokerMessage {
+            subject: format!("actor.me.reply.{}", call.uuid()),
+            reply_to: "".into(),
+            body: vec![7],
+        };
+        action::result_handler(&reply, call.uuid()).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![("QmCid".to_string(), vec![7])]);
+        assert_eq!(load("default", call.uuid()).unwrap(), None);
+    }
+}
@@ -0,0 +1,542 @@
+use crate::intercom::envelope::{self, Envelope};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, SystemTime};
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+/// Continuation of a pending call. It receives either the reply message or the reason why no
+/// reply is going to arrive.
+pub type Callback =
+    Box<dyn FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+/// Continuation of a call answered by more than one message.
+pub type StreamCallback = Box<
+    dyn FnMut(anyhow::Result<&BrokerMessage>, Progress) -> anyhow::Result<()>
+        + Sync
+        + Send
+        + 'static,
+>;
+
+/// Position of a delivery within the replies of one call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Zero based index of the message. For an error it is the number of messages delivered
+    /// before the error.
+    pub seq: u32,
+    /// No more deliveries will follow for this call.
+    pub done: bool,
+}
+
+/// How long a callback stays registered.
+pub enum ReplyMode {
+    /// Until this many messages have been delivered. `Count(1)` is a plain single-shot call.
+    Count(u32),
+    /// Until a message for which the predicate returns true has been delivered.
+    UntilTerminal(Box<dyn Fn(&BrokerMessage) -> bool + Sync + Send + 'static>),
+    /// Until the deadline of the call passes, the final delivery is then the timeout error.
+    UntilDeadline,
+}
+
+pub struct PendingEntry {
+    capability: String,
+    operation: String,
+    callback: StreamCallback,
+    mode: ReplyMode,
+    delivered: u32,
+    deadline: Option<SystemTime>,
+    /// Intercom chain the call was made in, the callback continues it.
+    chain: Option<Envelope>,
+}
+
+impl PendingEntry {
+    /// Hand the next reply to the callback. Returns the callback result and whether the entry
+    /// is finished.
+    pub fn deliver(&mut self, msg: &BrokerMessage) -> (anyhow::Result<()>, bool) {
+        let seq = self.delivered;
+        self.delivered += 1;
+        let done = match &self.mode {
+            ReplyMode::Count(n) => self.delivered >= *n,
+            ReplyMode::UntilTerminal(is_terminal) => is_terminal(msg),
+            ReplyMode::UntilDeadline => false,
+        };
+        let _scope = envelope::enter(self.chain);
+        let callback = &mut self.callback;
+        let result = isolate(|| callback(Ok(msg), Progress { seq, done }));
+        // a callback that panicked is not trusted with further replies
+        let panicked = matches!(&result, Err(e) if e.is::<CallbackPanic>());
+        (result, done || panicked)
+    }
+
+    /// Hand a final error to the callback.
+    pub fn fail(mut self, error: anyhow::Error) -> anyhow::Result<()> {
+        let progress = Progress {
+            seq: self.delivered,
+            done: true,
+        };
+        let _scope = envelope::enter(self.chain);
+        isolate(|| (self.callback)(Err(error), progress))
+    }
+
+    /// Capability of the call, empty for replies registered without sending a call.
+    pub fn capability(&self) -> &str {
+        &self.capability
+    }
+
+    pub fn operation(&self) -> &str {
+        &self.operation
+    }
+}
+
+/// A callback panicked, the panic message is kept.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("callback panicked: {0}")]
+pub struct CallbackPanic(pub String);
+
+/// Run `f`, reporting a panic as `CallbackPanic` instead of unwinding into the caller.
+///
+/// This only holds where panics unwind, i.e. native builds such as tests. Actors built for
+/// `wasm32-unknown-unknown` abort on panic, whatever the profile says, so a panicking callback
+/// still traps the whole actor there and the host has to restart it.
+pub fn isolate<R>(f: impl FnOnce() -> anyhow::Result<R>) -> anyhow::Result<R> {
+    panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|payload| Err(CallbackPanic(panic_message(payload.as_ref())).into()))
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Adapt a single-shot callback to the stream form stored in the registry.
+pub fn single_shot(mut callback: Callback) -> StreamCallback {
+    Box::new(move |reply, _| callback(reply))
+}
+
+/// Cancelled calls remembered at most, the oldest cancellation is forgotten first.
+pub const MAX_CANCELLED: usize = 1024;
+
+/// Seconds a cancelled call without deadline is remembered, counted from the first
+/// `take_expired` that sees it.
+pub const CANCELLED_GRACE_SECONDS: u64 = 60;
+
+struct Cancelled {
+    // order of cancellation, for evicting the oldest
+    seq: u64,
+    deadline: Option<SystemTime>,
+}
+
+/// Callbacks waiting for a reply, keyed by correlation uuid.
+#[derive(Default)]
+pub struct PendingRegistry {
+    entries: HashMap<String, PendingEntry>,
+    // taken out by `take` while their callback runs, see `restore`
+    dispatching: HashSet<String>,
+    // cancelled calls whose late reply should be dropped quietly, kept until their deadline
+    cancelled: HashMap<String, Cancelled>,
+    cancel_seq: u64,
+    draining: bool,
+    // set by `take_all`, entries being dispatched then are handed back by `restore`
+    drained: bool,
+}
+
+impl PendingRegistry {
+    pub fn new() -> Self {
+        PendingRegistry {
+            entries: HashMap::new(),
+            dispatching: HashSet::new(),
+            cancelled: HashMap::new(),
+            cancel_seq: 0,
+            draining: false,
+            drained: false,
+        }
+    }
+
+    /// Register a single-shot `callback` for the `operation` call of `capability` under `uuid`.
+    /// An entry without deadline never expires.
+    pub fn insert(
+        &mut self,
+        uuid: String,
+        capability: &str,
+        operation: &str,
+        callback: Callback,
+        deadline: Option<SystemTime>,
+    ) {
+        self.insert_stream(
+            uuid,
+            capability,
+            operation,
+            single_shot(callback),
+            ReplyMode::Count(1),
+            deadline,
+        );
+    }
+
+    /// Register a `callback` kept according to `mode`. The callback runs in the intercom chain
+    /// current at registration, see `intercom::envelope`.
+    pub fn insert_stream(
+        &mut self,
+        uuid: String,
+        capability: &str,
+        operation: &str,
+        callback: StreamCallback,
+        mode: ReplyMode,
+        deadline: Option<SystemTime>,
+    ) {
+        self.entries.insert(
+            uuid,
+            PendingEntry {
+                capability: capability.to_string(),
+                operation: operation.to_string(),
+                callback,
+                mode,
+                delivered: 0,
+                deadline,
+                chain: envelope::current(),
+            },
+        );
+    }
+
+    pub fn remove(&mut self, uuid: &str) -> Option<PendingEntry> {
+        self.entries.remove(uuid)
+    }
+
+    /// Remove the entry of `uuid` for delivering a reply. Call `restore` afterwards if the entry
+    /// is not finished, or `finish` if it is.
+    pub fn take(&mut self, uuid: &str) -> Option<PendingEntry> {
+        let entry = self.entries.remove(uuid)?;
+        self.dispatching.insert(uuid.to_string());
+        Some(entry)
+    }
+
+    /// Put back an entry removed by `take`, unless the call was cancelled in the meantime. Once
+    /// `take_all` has run the entry is returned instead, the caller has to fail it.
+    pub fn restore(&mut self, uuid: String, entry: PendingEntry) -> Option<PendingEntry> {
+        self.dispatching.remove(&uuid);
+        match self.cancelled.get_mut(&uuid) {
+            Some(cancelled) => {
+                cancelled.deadline = entry.deadline;
+                None
+            }
+            None if self.drained => Some(entry),
+            None => {
+                self.entries.insert(uuid, entry);
+                None
+            }
+        }
+    }
+
+    /// Forget an entry removed by `take` that will receive no more replies.
+    pub fn finish(&mut self, uuid: &str) {
+        if self.dispatching.remove(uuid) {
+            self.cancelled.remove(uuid);
+        }
+    }
+
+    /// Drop the callback registered under `uuid` and remember that the call was cancelled.
+    /// Returns false if no such call is pending.
+    pub fn cancel(&mut self, uuid: &str) -> bool {
+        if let Some(entry) = self.entries.remove(uuid) {
+            self.remember_cancelled(uuid, entry.deadline);
+            true
+        } else if self.dispatching.contains(uuid) {
+            self.remember_cancelled(uuid, None);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn remember_cancelled(&mut self, uuid: &str, deadline: Option<SystemTime>) {
+        self.cancel_seq += 1;
+        let seq = self.cancel_seq;
+        self.cancelled
+            .insert(uuid.to_string(), Cancelled { seq, deadline });
+        if self.cancelled.len() > MAX_CANCELLED {
+            // a call cancelled while its callback runs must not be restored, keep it
+            let dispatching = &self.dispatching;
+            let oldest = self
+                .cancelled
+                .iter()
+                .filter(|(uuid, _)| !dispatching.contains(*uuid))
+                .min_by_key(|(_, cancelled)| cancelled.seq)
+                .map(|(uuid, _)| uuid.clone());
+            if let Some(oldest) = oldest {
+                self.cancelled.remove(&oldest);
+            }
+        }
+    }
+
+    /// Returns true, at most once, if `uuid` belongs to a cancelled call.
+    pub fn take_cancelled(&mut self, uuid: &str) -> bool {
+        self.cancelled.remove(uuid).is_some()
+    }
+
+    /// Remove and return every entry whose deadline is not later than `now`. Cancelled calls
+    /// are forgotten past their deadline, or `CANCELLED_GRACE_SECONDS` after `now` if they have
+    /// none.
+    pub fn take_expired(&mut self, now: SystemTime) -> Vec<(String, PendingEntry)> {
+        let grace = now + Duration::from_secs(CANCELLED_GRACE_SECONDS);
+        self.cancelled.retain(|_, cancelled| {
+            let deadline = *cancelled.deadline.get_or_insert(grace);
+            deadline > now
+        });
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.deadline.map(|d| d <= now).unwrap_or(false))
+            .map(|(uuid, _)| uuid.clone())
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|uuid| self.remove(&uuid).map(|entry| (uuid, entry)))
+            .collect()
+    }
+
+    /// Remove and return every entry waiting for a reply. Entries being dispatched are not
+    /// returned, `restore` hands them back afterwards.
+    pub fn take_all(&mut self) -> Vec<(String, PendingEntry)> {
+        self.drained = true;
+        let mut entries: Vec<(String, PendingEntry)> = self.entries.drain().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    /// While draining, `action` refuses to register new calls. Ending a drain also lets
+    /// `restore` put entries back again.
+    pub fn set_draining(&mut self, draining: bool) {
+        self.draining = draining;
+        if !draining {
+            self.drained = false;
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Returns true if the call `uuid` is waiting for a reply or being delivered one.
+    pub fn contains(&self, uuid: &str) -> bool {
+        self.entries.contains_key(uuid) || self.dispatching.contains(uuid)
+    }
+
+    /// Number of calls still waiting for a reply.
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.dispatching.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PendingRegistry, ReplyMode, CANCELLED_GRACE_SECONDS, MAX_CANCELLED};
+    use crate::error::Error;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+    fn reply() -> BrokerMessage {
+        BrokerMessage {
+            subject: "reply".into(),
+            reply_to: "".into(),
+            body: vec![],
+        }
+    }
+
+    fn insert_counting(
+        registry: &mut PendingRegistry,
+        uuid: &str,
+        deadline: Option<SystemTime>,
+    ) -> Arc<Mutex<u32>> {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        registry.insert(
+            uuid.to_string(),
+            "wascc:messaging",
+            "Publish",
+            Box::new(move |_| {
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            }),
+            deadline,
+        );
+        calls
+    }
+
+    #[test]
+    fn reply_modes_decide_when_an_entry_is_done() {
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let record = || {
+            let progress = progress.clone();
+            Box::new(
+                move |reply: anyhow::Result<&BrokerMessage>, p: super::Progress| {
+                    progress
+                        .lock()
+                        .unwrap()
+                        .push((reply.is_ok(), p.seq, p.done));
+                    Ok(())
+                },
+            ) as super::StreamCallback
+        };
+        let mut registry = PendingRegistry::new();
+        registry.insert_stream("count".into(), "", "", record(), ReplyMode::Count(2), None);
+        let mut entry = registry.take("count").unwrap();
+        assert!(!entry.deliver(&reply()).1);
+        assert!(entry.deliver(&reply()).1);
+
+        let terminal = BrokerMessage {
+            body: vec![1],
+            ..reply()
+        };
+        registry.insert_stream(
+            "terminal".into(),
+            "",
+            "",
+            record(),
+            ReplyMode::UntilTerminal(Box::new(|msg| msg.body == vec![1])),
+            None,
+        );
+        let mut entry = registry.take("terminal").unwrap();
+        assert!(!entry.deliver(&reply()).1);
+        assert!(entry.deliver(&terminal).1);
+
+        registry.insert_stream(
+            "deadline".into(),
+            "",
+            "",
+            record(),
+            ReplyMode::UntilDeadline,
+            None,
+        );
+        let mut entry = registry.take("deadline").unwrap();
+        assert!(!entry.deliver(&terminal).1);
+        entry.fail(anyhow::anyhow!("expired")).unwrap();
+
+        assert_eq!(
+            *progress.lock().unwrap(),
+            vec![
+                (true, 0, false),
+                (true, 1, true),
+                (true, 0, false),
+                (true, 1, true),
+                (true, 0, false),
+                (false, 1, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn expired_entries_are_taken_once() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut registry = PendingRegistry::new();
+        insert_counting(&mut registry, "due", Some(now));
+        insert_counting(&mut registry, "late", Some(now + Duration::from_secs(1)));
+        insert_counting(&mut registry, "forever", None);
+
+        let expired = registry.take_expired(now);
+        let uuids: Vec<&str> = expired.iter().map(|(uuid, _)| uuid.as_str()).collect();
+        assert_eq!(uuids, vec!["due"]);
+        assert!(registry.take_expired(now).is_empty());
+        assert_eq!(registry.len(), 2);
+
+        let later = now + Duration::from_secs(3600);
+        let expired = registry.take_expired(later);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, "late");
+        assert!(registry.contains("forever"));
+        assert!(!registry.contains("late"));
+    }
+
+    #[test]
+    fn cancelled_calls_drop_late_replies_and_are_forgotten() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut registry = PendingRegistry::new();
+        let calls = insert_counting(&mut registry, "a", Some(now + Duration::from_secs(10)));
+        assert!(registry.cancel("a"));
+        assert!(!registry.cancel("a"));
+        assert!(!registry.contains("a"));
+        assert!(registry.take("a").is_none());
+        assert!(registry.take_cancelled("a"));
+        assert!(!registry.take_cancelled("a"));
+        assert_eq!(*calls.lock().unwrap(), 0);
+
+        // cancelled while its callback runs: not restored, the next reply is dropped
+        let calls = insert_counting(&mut registry, "b", None);
+        let mut entry = registry.take("b").unwrap();
+        assert!(registry.cancel("b"));
+        entry.deliver(&reply()).0.unwrap();
+        registry.restore("b".to_string(), entry);
+        assert!(!registry.contains("b"));
+        assert!(registry.take_cancelled("b"));
+        assert_eq!(*calls.lock().unwrap(), 1);
+
+        // without deadline, a cancellation is forgotten once the grace period passed
+        insert_counting(&mut registry, "c", None);
+        insert_counting(&mut registry, "d", Some(now + Duration::from_secs(10)));
+        assert!(registry.cancel("c") && registry.cancel("d"));
+        assert!(registry.take_expired(now).is_empty());
+        let later = now + Duration::from_secs(CANCELLED_GRACE_SECONDS);
+        assert!(registry.take_expired(later).is_empty());
+        assert!(!registry.take_cancelled("c"));
+        assert!(!registry.take_cancelled("d"));
+
+        for i in 0..MAX_CANCELLED + 1 {
+            let uuid = format!("e{}", i);
+            insert_counting(&mut registry, &uuid, None);
+            registry.cancel(&uuid);
+        }
+        assert!(!registry.take_cancelled("e0"));
+        assert!(registry.take_cancelled("e1"));
+    }
+
+    #[test]
+    fn draining_registry_hands_out_every_entry() {
+        let failures = Arc::new(Mutex::new(Vec::new()));
+        let mut registry = PendingRegistry::new();
+        for uuid in &["b", "a"] {
+            let failures = failures.clone();
+            registry.insert_stream(
+                uuid.to_string(),
+                "wascc:messaging",
+                "Publish",
+                Box::new(move |reply, progress| {
+                    assert!(progress.done);
+                    failures
+                        .lock()
+                        .unwrap()
+                        .push(reply.unwrap_err().to_string());
+                    Ok(())
+                }),
+                ReplyMode::Count(1),
+                None,
+            );
+        }
+        assert!(!registry.is_draining());
+        registry.set_draining(true);
+        assert!(registry.is_draining());
+
+        let entries = registry.take_all();
+        assert!(registry.is_empty());
+        let uuids: Vec<&str> = entries.iter().map(|(uuid, _)| uuid.as_str()).collect();
+        assert_eq!(uuids, vec!["a", "b"]);
+        for (uuid, entry) in entries {
+            let shutdown = Error::shutdown(entry.capability(), entry.operation(), &uuid);
+            entry.fail(shutdown.into()).unwrap();
+        }
+        assert_eq!(
+            *failures.lock().unwrap(),
+            vec![
+                "wascc:messaging Publish call a abandoned at shutdown",
+                "wascc:messaging Publish call b abandoned at shutdown",
+            ]
+        );
+    }
+}
@@ -1,32 +1,23 @@
-use prost::Message;
-use vmh_codec::message::{encode_protobuf, structs_proto::crypto};
-use wascc_actor::untyped;
+use crate::error;
+use crate::transport;
+use vmh_codec::message::structs_proto::crypto;
 
 const CAPABILITY: &'static str = "tea:crypto";
 
-pub fn generate(key_type: String) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+pub fn generate(key_type: String) -> error::Result<(Vec<u8>, Vec<u8>)> {
     let req = crypto::KeyGenerationRequest { key_type };
-    let res = crypto::KeyGenerationResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "GenerateKeyPair", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::KeyGenerationResponse =
+        transport::default().request_proto(CAPABILITY, "GenerateKeyPair", req)?;
     Ok((res.public_key, res.private_key))
 }
 
-pub fn sign(key_type: String, private_key: Vec<u8>, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+pub fn sign(key_type: String, private_key: Vec<u8>, data: Vec<u8>) -> error::Result<Vec<u8>> {
     let req = crypto::SignRequest {
         key_type,
         private_key,
         data,
     };
-    let res = crypto::SignResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "Sign", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::SignResponse = transport::default().request_proto(CAPABILITY, "Sign", req)?;
     Ok(res.signature)
 }
 
@@ -35,48 +26,36 @@ pub fn verify(
     public_key: Vec<u8>,
     data: Vec<u8>,
     signature: Vec<u8>,
-) -> anyhow::Result<bool> {
+) -> error::Result<bool> {
     let req = crypto::VerifyRequest {
         key_type,
         data,
         public_key,
         signature,
     };
-    let res = crypto::VerifyResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "Verify", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::VerifyResponse =
+        transport::default().request_proto(CAPABILITY, "Verify", req)?;
     Ok(res.result)
 }
 
-pub fn shamir_share(n: u8, k: u8, data: Vec<u8>) -> anyhow::Result<Vec<Vec<u8>>> {
+pub fn shamir_share(n: u8, k: u8, data: Vec<u8>) -> error::Result<Vec<Vec<u8>>> {
     let req = crypto::ShamirShareRequest {
         n: n as u32,
         k: k as u32,
         data,
     };
-    let res = crypto::ShamirShareResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "ShamirShare", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::ShamirShareResponse =
+        transport::default().request_proto(CAPABILITY, "ShamirShare", req)?;
     Ok(res.slices)
 }
 
-pub fn shamir_recovery(k: u8, slices: Vec<Vec<u8>>) -> anyhow::Result<Vec<u8>> {
+pub fn shamir_recovery(k: u8, slices: Vec<Vec<u8>>) -> error::Result<Vec<u8>> {
     let req = crypto::ShamirRecoveryRequest {
         k: k as u32,
         slices,
     };
-    let res = crypto::ShamirRecoveryResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "ShamirRecovery", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::ShamirRecoveryResponse =
+        transport::default().request_proto(CAPABILITY, "ShamirRecovery", req)?;
     Ok(res.data)
 }
 
@@ -84,18 +63,14 @@ pub fn generate_multi_sig_asset(
     k: u8,
     public_keys: Vec<Vec<u8>>,
     key_type: String,
-) -> anyhow::Result<String> {
+) -> error::Result<String> {
     let req = crypto::GenerateMultiSigAssetRequest {
         key_type,
         public_keys,
         k: k as u32,
     };
-    let res = crypto::GenerateMultiSigAssetResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "GenerateMultiSigAsset", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::GenerateMultiSigAssetResponse =
+        transport::default().request_proto(CAPABILITY, "GenerateMultiSigAsset", req)?;
     Ok(res.address)
 }
 
@@ -104,145 +79,100 @@ pub fn combine_to_witness(
     public_keys: Vec<Vec<u8>>,
     signatures: Vec<Vec<u8>>,
     key_type: String,
-) -> anyhow::Result<Vec<Vec<u8>>> {
+) -> error::Result<Vec<Vec<u8>>> {
     let req = crypto::CombineToWitnessRequest {
         key_type,
         public_keys,
         signatures,
         k: k as u32,
     };
-    let res = crypto::CombineToWitnessResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "CombineToWitness", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::CombineToWitnessResponse =
+        transport::default().request_proto(CAPABILITY, "CombineToWitness", req)?;
     Ok(res.witness)
 }
 
-pub fn generate_aes_key() -> anyhow::Result<Vec<u8>> {
+pub fn generate_aes_key() -> error::Result<Vec<u8>> {
     let req = crypto::GenerateAesKeyRequest {};
-    let res = crypto::GenerateAesKeyResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "GenerateAesKey", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::GenerateAesKeyResponse =
+        transport::default().request_proto(CAPABILITY, "GenerateAesKey", req)?;
     Ok(res.key)
 }
 
-pub fn aes_encrypt(key: Vec<u8>, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+pub fn aes_encrypt(key: Vec<u8>, data: Vec<u8>) -> error::Result<Vec<u8>> {
     let req = crypto::AesEncryptRequest { key, data };
-    let res = crypto::AesEncryptResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "AesEncrypt", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::AesEncryptResponse =
+        transport::default().request_proto(CAPABILITY, "AesEncrypt", req)?;
     Ok(res.encrypted_data)
 }
 
-pub fn aes_decrypt(key: Vec<u8>, encrypted_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+pub fn aes_decrypt(key: Vec<u8>, encrypted_data: Vec<u8>) -> error::Result<Vec<u8>> {
     let req = crypto::AesDecryptRequest {
         key,
         encrypted_data,
     };
-    let res = crypto::AesDecryptResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "AesDecrypt", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::AesDecryptResponse =
+        transport::default().request_proto(CAPABILITY, "AesDecrypt", req)?;
     Ok(res.data)
 }
 
-pub fn sha256(content: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+pub fn sha256(content: Vec<u8>) -> error::Result<Vec<u8>> {
     let req = crypto::ShaRequest {
         sha_type: "sha256".to_string(),
         content,
     };
-    let res = crypto::ShaResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "Sha", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::ShaResponse = transport::default().request_proto(CAPABILITY, "Sha", req)?;
     Ok(res.hash)
 }
 
-pub fn public_key_from_ss58(address: &str) -> anyhow::Result<Vec<u8>> {
+pub fn public_key_from_ss58(address: &str) -> error::Result<Vec<u8>> {
     let req = crypto::FromSs58AddressRequest {
         address: address.to_string(),
     };
-    let res = crypto::FromSs58AddressResponse::decode(
-        untyped::default()
-            .call(CAPABILITY, "FromSS58", encode_protobuf(req)?)
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
+    let res: crypto::FromSs58AddressResponse =
+        transport::default().request_proto(CAPABILITY, "FromSS58", req)?;
     Ok(res.result)
 }
 
-pub fn public_key_to_ss58(public_key: &[u8]) -> anyhow::Result<String> {
-    let res = crypto::ToSs58AddressResponse::decode(
-        untyped::default()
-            .call(
-                CAPABILITY,
-                "ToSS58",
-                encode_protobuf(crypto::ToSs58AddressRequest {
-                    public_key: public_key.to_vec(),
-                })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
+pub fn public_key_to_ss58(public_key: &[u8]) -> error::Result<String> {
+    let res: crypto::ToSs58AddressResponse = transport::default().request_proto(
+        CAPABILITY,
+        "ToSS58",
+        crypto::ToSs58AddressRequest {
+            public_key: public_key.to_vec(),
+        },
     )?;
     Ok(res.address)
 }
 
-pub fn generate_rsa_keypair(bit_size: u32) -> anyhow::Result<(String, String)> {
-    let res = crypto::RsaKeyPairPemPcsk1Response::decode(
-        untyped::default()
-            .call(
-                CAPABILITY,
-                "GenerateRsaPkcs1",
-                encode_protobuf(crypto::RsaKeyPairPemPcsk1Request { bits: bit_size })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
+pub fn generate_rsa_keypair(bit_size: u32) -> error::Result<(String, String)> {
+    let res: crypto::RsaKeyPairPemPcsk1Response = transport::default().request_proto(
+        CAPABILITY,
+        "GenerateRsaPkcs1",
+        crypto::RsaKeyPairPemPcsk1Request { bits: bit_size },
     )?;
     Ok((res.public_key, res.private_key))
 }
 
-pub fn rsa_encrypt(public_key_pkcs1: String, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-    let res = crypto::RsaEncryptResponse::decode(
-        untyped::default()
-            .call(
-                CAPABILITY,
-                "RsaEncrypt",
-                encode_protobuf(crypto::RsaEncryptRequest {
-                    public_key_pkcs1,
-                    msg: data,
-                })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
+pub fn rsa_encrypt(public_key_pkcs1: String, data: Vec<u8>) -> error::Result<Vec<u8>> {
+    let res: crypto::RsaEncryptResponse = transport::default().request_proto(
+        CAPABILITY,
+        "RsaEncrypt",
+        crypto::RsaEncryptRequest {
+            public_key_pkcs1,
+            msg: data,
+        },
     )?;
     Ok(res.result)
 }
 
-pub fn rsa_decrypt(private_key_pkcs1: String, encrypted_data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
-    let res = crypto::RsaDecryptResponse::decode(
-        untyped::default()
-            .call(
-                CAPABILITY,
-                "RsaDecrypt",
-                encode_protobuf(crypto::RsaDecryptRequest {
-                    private_key_pkcs1,
-                    msg: encrypted_data,
-                })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
+pub fn rsa_decrypt(private_key_pkcs1: String, encrypted_data: Vec<u8>) -> error::Result<Vec<u8>> {
+    let res: crypto::RsaDecryptResponse = transport::default().request_proto(
+        CAPABILITY,
+        "RsaDecrypt",
+        crypto::RsaDecryptRequest {
+            private_key_pkcs1,
+            msg: encrypted_data,
+        },
     )?;
     Ok(res.result)
 }
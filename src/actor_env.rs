@@ -1,65 +1,55 @@
-use prost::Message;
+use crate::error::{self, Error};
+use crate::transport;
 use std::time::SystemTime;
 use tea_codec::OP_CURRENT_TIMESTAMP;
-use vmh_codec::message::encode_protobuf;
 use vmh_codec::message::structs_proto::env;
 use wascc_actor::prelude::*;
 
 const CAPABILITY: &'static str = "tea:env";
 const DEFAULT_DURATION: u128 = 10;
 
-/// Return empty string is the env var is not set by the OS
-pub fn get_env_var(env_var: &str) -> anyhow::Result<String> {
-    let response_vec = untyped::default()
-        .call(
-            CAPABILITY,
-            "GetEnvVar",
-            encode_protobuf(env::GetRequest {
-                key: env_var.to_string(),
-            })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
-
-    let res = env::GetResponse::decode(response_vec.as_slice())?;
+/// Returns `Error::NotFound` if the env var is not set by the OS
+pub fn get_env_var(env_var: &str) -> error::Result<String> {
+    let res: env::GetResponse = transport::default().request_proto(
+        CAPABILITY,
+        "GetEnvVar",
+        env::GetRequest {
+            key: env_var.to_string(),
+        },
+    )?;
     if res.exists {
         Ok(res.value)
     } else {
-        Err(anyhow::anyhow!(
-            "failed to get environment variable: {}",
-            env_var
+        Err(Error::not_found(
+            CAPABILITY,
+            "GetEnvVar",
+            format!("environment variable {}", env_var),
         ))
     }
 }
 
-pub fn get_system_time() -> anyhow::Result<SystemTime> {
-    let response_vec = untyped::default()
-        .call(
-            CAPABILITY,
-            "GetSystemTime",
-            encode_protobuf(env::GetSystemTimeRequest {})?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
-
-    let res: SystemTime =
-        deserialize(response_vec.as_slice()).map_err(|e| anyhow::anyhow!("{}", e))?;
-    Ok(res)
+pub fn get_system_time() -> error::Result<SystemTime> {
+    let response_vec = transport::default().request(
+        CAPABILITY,
+        "GetSystemTime",
+        vmh_codec::message::encode_protobuf(env::GetSystemTimeRequest {})
+            .map_err(|e| Error::invalid_input(CAPABILITY, "GetSystemTime", e))?,
+    )?;
+    deserialize(response_vec.as_slice()).map_err(|e| Error::decode(CAPABILITY, "GetSystemTime", e))
 }
 
-pub fn current_timestamp() -> anyhow::Result<i64> {
-    let response_vec = untyped::default()
-        .call(
-            CAPABILITY,
-            OP_CURRENT_TIMESTAMP,
-            encode_protobuf(env::GetCurrentTimestampRequest {})?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
-    let res = env::GetCurrentTimestampResponse::decode(response_vec.as_slice())?;
+pub fn current_timestamp() -> error::Result<i64> {
+    let res: env::GetCurrentTimestampResponse = transport::default().request_proto(
+        CAPABILITY,
+        OP_CURRENT_TIMESTAMP,
+        env::GetCurrentTimestampRequest {},
+    )?;
     Ok(res.timestamp)
 }
 
 /// calculate elapsed time in milliseconds, if calculate duration failed returns default duration
 /// instead, default duration is defined by `DEFAULT_DURATION` const.
-pub fn time_since(earlier: SystemTime) -> anyhow::Result<u128> {
+pub fn time_since(earlier: SystemTime) -> error::Result<u128> {
     let now = get_system_time()?;
     match now.duration_since(earlier) {
         Ok(d) => Ok(d.as_millis()),
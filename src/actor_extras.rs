@@ -0,0 +1,45 @@
+use crate::error::{self, Error};
+use crate::transport;
+use wascc_actor::prelude::codec::extras::{
+    GeneratorRequest, GeneratorResult, OP_REQUEST_RANDOM, OP_REQUEST_SEQUENCE,
+};
+use wascc_actor::prelude::*;
+
+const CAPABILITY: &str = "wascc:extras";
+
+fn generate(operation: &str, req: GeneratorRequest) -> error::Result<GeneratorResult> {
+    let res = transport::default().request(
+        CAPABILITY,
+        operation,
+        serialize(req).map_err(|e| Error::invalid_input(CAPABILITY, operation, e))?,
+    )?;
+    deserialize(res.as_slice()).map_err(|e| Error::decode(CAPABILITY, operation, e))
+}
+
+/// Next number of the host sequence, the same as `extras::default().get_sequence_number()` but
+/// going through the transport.
+pub fn get_sequence_number() -> error::Result<u64> {
+    let res = generate(
+        OP_REQUEST_SEQUENCE,
+        GeneratorRequest {
+            sequence: true,
+            ..Default::default()
+        },
+    )?;
+    Ok(res.sequence_number)
+}
+
+/// Random number in `min..max`, the same as `extras::default().get_random` but going through the
+/// transport.
+pub fn get_random(min: u32, max: u32) -> error::Result<u32> {
+    let res = generate(
+        OP_REQUEST_RANDOM,
+        GeneratorRequest {
+            random: true,
+            min,
+            max,
+            ..Default::default()
+        },
+    )?;
+    Ok(res.random_number)
+}
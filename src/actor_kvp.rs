@@ -1,9 +1,8 @@
-use prost::Message;
+use crate::error::{self, Error};
+use crate::transport;
 use serde::{Deserialize, Serialize};
-use tea_codec::error::TeaError;
 use tea_codec::{deserialize, serialize};
 use vmh_codec::message::structs_proto::kvp;
-use wascc_actor::prelude::*;
 
 const CAPABILITY: &'static str = "tea:keyvalue";
 
@@ -17,7 +16,7 @@ impl ShabbyLock {
         let key = format!("ShabbyLock_{}", uid);
         trace!("enter lock {}", &key);
         loop {
-            let t: anyhow::Result<Option<String>> = get(binding_name, &key);
+            let t: error::Result<Option<String>> = get(binding_name, &key);
             match t {
                 Ok(res) => match res {
                     Some(_) => {
@@ -48,73 +47,51 @@ pub fn set_forever<'de, T: Serialize + Deserialize<'de>>(
     binding_name: &'static str,
     key: &str,
     value: &T,
-) -> anyhow::Result<T> {
+) -> error::Result<T> {
     let req = kvp::SetRequest {
         key: key.to_owned(),
-        value: serialize(value)?,
+        value: encode_value("Set", value)?,
         expires_s: 0,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Set", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
-    let result: T = deserialize(res.value.as_slice())?;
+    let res: kvp::SetResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Set", req)?;
+    let result: T = decode_value("Set", res.value.as_slice())?;
     Ok(result)
 }
 
-pub fn add(binding_name: &'static str, key: &str, value: i32) -> HandlerResult<i32> {
+pub fn add(binding_name: &'static str, key: &str, value: i32) -> error::Result<i32> {
     let req = kvp::AddRequest {
         key: key.to_owned(),
         value: value,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::AddResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Add", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::AddResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Add", req)?;
     Ok(res.value)
 }
 
-pub fn del(binding_name: &'static str, key: &str) -> HandlerResult<String> {
+pub fn del(binding_name: &'static str, key: &str) -> error::Result<String> {
     let req = kvp::DelRequest {
         key: key.to_owned(),
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::DelResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Del", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::DelResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Del", req)?;
     Ok(res.key)
 }
 
 pub fn get<'de, T: Deserialize<'de>>(
     binding_name: &'static str,
     key: &str,
-) -> anyhow::Result<Option<T>> {
+) -> error::Result<Option<T>> {
     let req = kvp::GetRequest {
         key: key.to_owned(),
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::GetResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Get", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
+    let res: kvp::GetResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Get", req)?;
 
     if res.exists {
         match res.value {
             Some(kvp::get_response::Value::V(value)) => {
-                let result: T = deserialize(&value)?;
+                let result: T = decode_value("Get", &value)?;
                 Ok(Some(result))
             }
             _ => Ok(None),
@@ -124,17 +101,12 @@ pub fn get<'de, T: Deserialize<'de>>(
     }
 }
 
-pub fn list_clear(binding_name: &'static str, key: &str) -> HandlerResult<String> {
+pub fn list_clear(binding_name: &'static str, key: &str) -> error::Result<String> {
     let req = kvp::DelRequest {
         key: key.to_owned(),
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::DelResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Del", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::DelResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Del", req)?;
     Ok(res.key)
 }
 
@@ -143,24 +115,19 @@ pub fn list_range<'de, T: Serialize + Deserialize<'de>>(
     key: &str,
     start: i32,
     stop: i32,
-) -> HandlerResult<Vec<T>> {
+) -> error::Result<Vec<T>> {
     let req = kvp::ListRangeRequest {
         key: key.to_owned(),
         start: start,
         stop: stop,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::ListRangeResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Range", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::ListRangeResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Range", req)?;
     let result: Vec<T> = res
         .values
         .into_iter()
-        .map(|t| deserialize(t.as_slice()).unwrap())
-        .collect();
+        .map(|t| decode_value("Range", t.as_slice()))
+        .collect::<error::Result<_>>()?;
     Ok(result)
 }
 
@@ -168,19 +135,13 @@ pub fn list_push<'de, T: Serialize + Deserialize<'de>>(
     binding_name: &'static str,
     key: &str,
     value: &T,
-) -> anyhow::Result<i32> {
+) -> error::Result<i32> {
     let req = kvp::ListPushRequest {
         key: key.to_owned(),
-        value: serialize(value)?,
+        value: encode_value("Push", value)?,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::ListResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Push", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
+    let res: kvp::ListResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Push", req)?;
     Ok(res.new_count)
 }
 
@@ -189,21 +150,15 @@ pub fn set<'de, T: Serialize + Deserialize<'de>>(
     key: &str,
     value: &T,
     expires_s: i32,
-) -> anyhow::Result<T> {
+) -> error::Result<T> {
     let req = kvp::SetRequest {
         key: key.to_owned(),
-        value: serialize(value)?,
+        value: encode_value("Set", value)?,
         expires_s: expires_s,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "Set", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
-    let result: T = deserialize(res.value.as_slice())?;
+    let res: kvp::SetResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "Set", req)?;
+    let result: T = decode_value("Set", res.value.as_slice())?;
     Ok(result)
 }
 
@@ -211,19 +166,13 @@ pub fn list_del_item<T: Serialize>(
     binding_name: &'static str,
     key: &str,
     value: &T,
-) -> anyhow::Result<i32> {
+) -> error::Result<i32> {
     let req = kvp::ListDelItemRequest {
         key: key.to_owned(),
-        value: serialize(value)?,
+        value: encode_value("ListItemDelete", value)?,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::ListResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "ListItemDelete", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
+    let res: kvp::ListResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "ListItemDelete", req)?;
     Ok(res.new_count)
 }
 
@@ -231,19 +180,13 @@ pub fn set_add<T: Serialize>(
     binding_name: &'static str,
     key: &str,
     value: &T,
-) -> anyhow::Result<i32> {
+) -> error::Result<i32> {
     let req = kvp::SetAddRequest {
         key: key.to_owned(),
-        value: serialize(value)?,
+        value: encode_value("SetAdd", value)?,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetOperationResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "SetAdd", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
+    let res: kvp::SetOperationResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "SetAdd", req)?;
     Ok(res.new_count)
 }
 
@@ -251,97 +194,71 @@ pub fn set_remove<T: Serialize>(
     binding_name: &'static str,
     key: &str,
     value: &T,
-) -> anyhow::Result<i32> {
+) -> error::Result<i32> {
     let req = kvp::SetRemoveRequest {
         key: key.to_owned(),
-        value: serialize(value)?,
+        value: encode_value("SetRemove", value)?,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetOperationResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "SetRemove", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
+    let res: kvp::SetOperationResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "SetRemove", req)?;
     Ok(res.new_count)
 }
 
 pub fn set_union<'de, T: Deserialize<'de>>(
     binding_name: &'static str,
     keys: Vec<&str>,
-) -> HandlerResult<Vec<T>> {
+) -> error::Result<Vec<T>> {
     let keys: Vec<String> = keys.into_iter().map(|k| k.to_owned()).collect();
     let req = kvp::SetUnionRequest { keys: keys };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetQueryResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "SetUnion", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::SetQueryResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "SetUnion", req)?;
     let result: Vec<T> = res
         .values
         .into_iter()
-        .map(|t| deserialize(t.as_slice()).unwrap())
-        .collect();
+        .map(|t| decode_value("SetUnion", t.as_slice()))
+        .collect::<error::Result<_>>()?;
     Ok(result)
 }
 
 pub fn set_intersect<'de, T: Deserialize<'de>>(
     binding_name: &'static str,
     keys: Vec<&str>,
-) -> HandlerResult<Vec<T>> {
+) -> error::Result<Vec<T>> {
     let keys: Vec<String> = keys.into_iter().map(|k| k.to_owned()).collect();
     let req = kvp::SetIntersectionRequest { keys: keys };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetQueryResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "SetIntersection", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::SetQueryResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "SetIntersection", req)?;
     let result: Vec<T> = res
         .values
         .into_iter()
-        .map(|t| deserialize(t.as_slice()).unwrap())
-        .collect();
+        .map(|t| decode_value("SetIntersection", t.as_slice()))
+        .collect::<error::Result<_>>()?;
     Ok(result)
 }
 
 pub fn set_query<'de, T: Deserialize<'de>>(
     binding_name: &'static str,
     key: &str,
-) -> HandlerResult<Vec<T>> {
+) -> error::Result<Vec<T>> {
     let req = kvp::SetQueryRequest {
         key: key.to_owned(),
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::SetQueryResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "SetQuery", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::SetQueryResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "SetQuery", req)?;
     let result: Vec<T> = res
         .values
         .into_iter()
-        .map(|t| deserialize(t.as_slice()).unwrap())
-        .collect();
+        .map(|t| decode_value("SetQuery", t.as_slice()))
+        .collect::<error::Result<_>>()?;
     Ok(result)
 }
 
-pub fn exists(binding_name: &'static str, key: &str) -> HandlerResult<bool> {
+pub fn exists(binding_name: &'static str, key: &str) -> error::Result<bool> {
     let req = kvp::KeyExistsQuery {
         key: key.to_owned(),
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::GetResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "KeyExists", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::GetResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "KeyExists", req)?;
     Ok(res.exists)
 }
 
@@ -350,47 +267,36 @@ pub fn keyvec_insert<T: Serialize>(
     key: &str,
     tuple: (i32, &T),
     overwrite: bool,
-) -> anyhow::Result<bool> {
+) -> error::Result<bool> {
     let t = kvp::TupleKeyValue {
         k: tuple.0,
-        v: serialize(tuple.1)?,
+        v: encode_value("KeyVecInsert", tuple.1)?,
     };
     let req = kvp::KeyVecInsertQuery {
         key: key.to_string(),
         value: Some(t),
         overwrite: overwrite,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::KeyVecInsertResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "KeyVecInsert", buf)
-            .map_err(|e| TeaError::CommonError(format!("{}", e)))?
-            .as_slice(),
-    )?;
+    let res: kvp::KeyVecInsertResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "KeyVecInsert", req)?;
     Ok(res.success)
 }
 
 pub fn keyvec_get<'de, T: Deserialize<'de>>(
     binding_name: &'static str,
     key: &str,
-) -> HandlerResult<Vec<(i32, T)>> {
+) -> error::Result<Vec<(i32, T)>> {
     let req = kvp::KeyVecGetQuery {
         key: key.to_string(),
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::KeyVecGetResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "KeyVecGet", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::KeyVecGetResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "KeyVecGet", req)?;
 
     let result: Vec<(i32, T)> = res
         .values
         .into_iter()
-        .map(|t| (t.k, deserialize(t.v.as_slice()).unwrap()))
-        .collect();
+        .map(|t| Ok((t.k, decode_value("KeyVecGet", t.v.as_slice())?)))
+        .collect::<error::Result<_>>()?;
     Ok(result)
 }
 
@@ -398,18 +304,13 @@ pub fn keyvec_remove_item(
     binding_name: &'static str,
     key: &str,
     value_idx: i32,
-) -> HandlerResult<()> {
+) -> error::Result<()> {
     let req = kvp::KeyVecRemoveItemQuery {
         key: key.to_string(),
         value_idx: value_idx,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len());
-    req.encode(&mut buf).expect("req encoded error");
-    let _res = kvp::KeyVecRemoveItemResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "KeyVecRemoveItem", buf)?
-            .as_slice(),
-    )?;
+    let _res: kvp::KeyVecRemoveItemResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "KeyVecRemoveItem", req)?;
 
     Ok(())
 }
@@ -418,17 +319,20 @@ pub fn keyvec_tail_off(
     binding_name: &'static str,
     key: &str,
     remain: usize,
-) -> HandlerResult<usize> {
+) -> error::Result<usize> {
     let req = kvp::KeyVecTailOffQuery {
         key: key.to_string(),
         remain: remain as u32,
     };
-    let mut buf = Vec::with_capacity(req.encoded_len() as usize);
-    req.encode(&mut buf).expect("req encoded error");
-    let res = kvp::KeyVecTailOffResponse::decode(
-        untyped::host(binding_name)
-            .call(CAPABILITY, "KeyVecTailOff", buf)?
-            .as_slice(),
-    )?;
+    let res: kvp::KeyVecTailOffResponse =
+        transport::host(binding_name).request_proto(CAPABILITY, "KeyVecTailOff", req)?;
     Ok(res.len as usize)
 }
+
+fn encode_value<T: Serialize + ?Sized>(operation: &str, value: &T) -> error::Result<Vec<u8>> {
+    serialize(value).map_err(|e| Error::invalid_input(CAPABILITY, operation, e))
+}
+
+fn decode_value<'de, T: Deserialize<'de>>(operation: &str, buf: &[u8]) -> error::Result<T> {
+    deserialize(buf).map_err(|e| Error::decode(CAPABILITY, operation, e))
+}
@@ -1,11 +1,25 @@
 use crate::action;
 use crate::action::call_async_intercom;
+use crate::router::format_subject;
 use prost::Message;
 use wascc_actor::prelude::codec::messaging::BrokerMessage;
 
 const PINNER_ACTOR_NAME: &'static str = "pinner";
 
-pub fn is_node_ready<F>(reply_actor: &str, mut ready_callback: F) -> anyhow::Result<()>
+/// Intercom subjects answered by the pinner actor, in `router::SubjectRouter` pattern syntax.
+pub const SUBJECT_IS_NODE_READY: &str = "actor.pinner.intercom.is_node_ready";
+pub const SUBJECT_GET_KEY1: &str = "actor.pinner.intercom.get_key1.{deployment_id}";
+pub const SUBJECT_GET_DESCRIPTION_CID: &str =
+    "actor.pinner.intercom.get_description_cid.{deployment_id}";
+pub const SUBJECT_GET_CODE_OR_DATA_CID: &str =
+    "actor.pinner.intercom.get_code_or_data_cid.{deployment_id}";
+pub const SUBJECT_GET_DEPLOYMENT_INFO: &str =
+    "actor.pinner.intercom.get_deployment_info.{deployment_id}";
+
+pub fn is_node_ready<F>(
+    reply_actor: &str,
+    mut ready_callback: F,
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(bool) -> anyhow::Result<()> + Sync + Send + 'static,
 {
@@ -13,7 +27,7 @@ where
         PINNER_ACTOR_NAME,
         reply_actor,
         BrokerMessage {
-            subject: "actor.pinner.intercom.is_node_ready".into(),
+            subject: SUBJECT_IS_NODE_READY.into(),
             reply_to: "".into(),
             body: Vec::new(),
         },
@@ -23,10 +37,13 @@ where
             ready_callback(ready)
         },
     )
-    .map_err(|e| anyhow::anyhow!("{}", e))
 }
 
-pub fn get_key1<F>(reply_actor: &str, deployment_id: &str, mut callback: F) -> anyhow::Result<()>
+pub fn get_key1<F>(
+    reply_actor: &str,
+    deployment_id: &str,
+    mut callback: F,
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(Option<Vec<u8>>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
@@ -34,7 +51,7 @@ where
         PINNER_ACTOR_NAME,
         reply_actor,
         BrokerMessage {
-            subject: format!("actor.pinner.intercom.get_key1.{}", deployment_id),
+            subject: format_subject(SUBJECT_GET_KEY1, &[("deployment_id", deployment_id)])?,
             reply_to: "".into(),
             body: Vec::new(),
         },
@@ -43,14 +60,13 @@ where
             callback(res)
         },
     )
-    .map_err(|e| anyhow::anyhow!("{}", e))
 }
 
 pub fn get_description_cid<F>(
     reply_actor: &str,
     deployment_id: &str,
     mut callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(Option<String>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
@@ -58,10 +74,10 @@ where
         PINNER_ACTOR_NAME,
         reply_actor,
         BrokerMessage {
-            subject: format!(
-                "actor.pinner.intercom.get_description_cid.{}",
-                deployment_id
-            ),
+            subject: format_subject(
+                SUBJECT_GET_DESCRIPTION_CID,
+                &[("deployment_id", deployment_id)],
+            )?,
             reply_to: "".into(),
             body: Vec::new(),
         },
@@ -70,14 +86,13 @@ where
             callback(res)
         },
     )
-    .map_err(|e| anyhow::anyhow!("{}", e))
 }
 
 pub fn get_code_or_data_cid<F>(
     reply_actor: &str,
     deployment_id: &str,
     mut callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(Option<String>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
@@ -85,10 +100,10 @@ where
         PINNER_ACTOR_NAME,
         reply_actor,
         BrokerMessage {
-            subject: format!(
-                "actor.pinner.intercom.get_code_or_data_cid.{}",
-                deployment_id
-            ),
+            subject: format_subject(
+                SUBJECT_GET_CODE_OR_DATA_CID,
+                &[("deployment_id", deployment_id)],
+            )?,
             reply_to: "".into(),
             body: Vec::new(),
         },
@@ -97,14 +112,13 @@ where
             callback(res)
         },
     )
-    .map_err(|e| anyhow::anyhow!("{}", e))
 }
 
 pub fn get_deployment_info<F>(
     reply_actor: &str,
     deployment_id: &str,
     mut callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(Option<String>, Option<String>, Option<Vec<u8>>) -> anyhow::Result<()>
         + Sync
@@ -115,10 +129,10 @@ where
         PINNER_ACTOR_NAME,
         reply_actor,
         BrokerMessage {
-            subject: format!(
-                "actor.pinner.intercom.get_deployment_info.{}",
-                deployment_id
-            ),
+            subject: format_subject(
+                SUBJECT_GET_DEPLOYMENT_INFO,
+                &[("deployment_id", deployment_id)],
+            )?,
             reply_to: "".into(),
             body: Vec::new(),
         },
@@ -132,5 +146,4 @@ where
             )
         },
     )
-    .map_err(|e| anyhow::anyhow!("{}", e))
 }
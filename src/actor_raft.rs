@@ -1,50 +1,55 @@
-use prost::Message;
+use crate::error::{self, Error};
+use crate::transport;
 use std::collections::HashMap;
-use vmh_codec::message::encode_protobuf;
 use vmh_codec::message::structs_proto::raft;
 use wascc_actor::prelude::*;
 
+const CAPABILITY: &str = tea_codec::RAFT_CAPABILITY_ID;
+
 pub fn raft_set_value(
     key: &str,
     value: &[u8],
     storage_index: u32,
     uuid: &str,
-) -> anyhow::Result<()> {
-    let response_vec = untyped::default()
-        .call(
-            tea_codec::RAFT_CAPABILITY_ID,
-            "RaftSet",
-            encode_protobuf(raft::SetValueRequest {
-                key: key.to_string(),
-                value: value.to_vec(),
-                index: storage_index,
-                uuid: uuid.to_string(),
-            })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
-    let res = raft::SetValueResponse::decode(response_vec.as_slice())?;
+) -> error::Result<()> {
+    let res: raft::SetValueResponse = transport::default().request_proto(
+        CAPABILITY,
+        "RaftSet",
+        raft::SetValueRequest {
+            key: key.to_string(),
+            value: value.to_vec(),
+            index: storage_index,
+            uuid: uuid.to_string(),
+        },
+    )?;
     if !res.success {
-        return Err(anyhow::anyhow!("raft set value failed: {:?}", res));
+        return Err(Error::provider(
+            CAPABILITY,
+            "RaftSet",
+            format!("raft set value failed: {:?}", res),
+        ));
     }
     Ok(())
 }
 
-pub fn raft_get_value(key: &str, storage_index: u32, uuid: &str) -> anyhow::Result<Vec<u8>> {
-    let response_vec = get_value(key, storage_index, uuid, false, false)?;
-    let res = raft::GetValueResponse::decode(response_vec.as_slice())?;
+pub fn raft_get_value(key: &str, storage_index: u32, uuid: &str) -> error::Result<Vec<u8>> {
+    let res = get_value(key, storage_index, uuid, false, false)?;
     if let Some(v) = res.value {
         return Ok(v.value);
     }
-    Err(anyhow::anyhow!("raft get value failed: {:?}", res))
+    Err(Error::not_found(
+        CAPABILITY,
+        "RaftGet",
+        format!("value of key {}", key),
+    ))
 }
 
 pub fn raft_get_values(
     prefix: &str,
     storage_index: u32,
     uuid: &str,
-) -> anyhow::Result<HashMap<String, Vec<u8>>> {
-    let response_vec = get_value(prefix, storage_index, uuid, false, true)?;
-    let res = raft::GetValueResponse::decode(response_vec.as_slice())?;
+) -> error::Result<HashMap<String, Vec<u8>>> {
+    let res = get_value(prefix, storage_index, uuid, false, true)?;
     if let Some(v) = res.values {
         let mut result = HashMap::new();
         for i in 0..v.keys.len() {
@@ -52,31 +57,34 @@ pub fn raft_get_values(
         }
         return Ok(result);
     }
-    Err(anyhow::anyhow!("raft get value failed: {:?}", res))
+    Err(Error::not_found(
+        CAPABILITY,
+        "RaftGet",
+        format!("values with prefix {}", prefix),
+    ))
 }
 
-pub fn raft_is_leader() -> anyhow::Result<bool> {
-    let res = untyped::default()
-        .call(tea_codec::RAFT_CAPABILITY_ID, "RaftIsLeader", vec![])
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
-    Ok(deserialize(res.as_slice()).map_err(|e| anyhow::anyhow!("{}", e))?)
+pub fn raft_is_leader() -> error::Result<bool> {
+    let res = transport::default().request(CAPABILITY, "RaftIsLeader", vec![])?;
+    deserialize(res.as_slice()).map_err(|e| Error::decode(CAPABILITY, "RaftIsLeader", e))
 }
 
-pub fn raft_delete_value(key: &str, storage_index: u32, uuid: &str) -> anyhow::Result<()> {
-    let response_vec = untyped::default()
-        .call(
-            tea_codec::RAFT_CAPABILITY_ID,
-            "RaftDelete",
-            encode_protobuf(raft::DeleteValueRequest {
-                key: key.to_string(),
-                index: storage_index,
-                uuid: uuid.to_string(),
-            })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
-    let res = raft::DeleteValueResponse::decode(response_vec.as_slice())?;
+pub fn raft_delete_value(key: &str, storage_index: u32, uuid: &str) -> error::Result<()> {
+    let res: raft::DeleteValueResponse = transport::default().request_proto(
+        CAPABILITY,
+        "RaftDelete",
+        raft::DeleteValueRequest {
+            key: key.to_string(),
+            index: storage_index,
+            uuid: uuid.to_string(),
+        },
+    )?;
     if !res.success {
-        return Err(anyhow::anyhow!("raft delete value failed: {:?}", res));
+        return Err(Error::provider(
+            CAPABILITY,
+            "RaftDelete",
+            format!("raft delete value failed: {:?}", res),
+        ));
     }
     Ok(())
 }
@@ -87,18 +95,16 @@ fn get_value(
     uuid: &str,
     get_all: bool,
     get_by_prefix: bool,
-) -> anyhow::Result<Vec<u8>> {
-    untyped::default()
-        .call(
-            tea_codec::RAFT_CAPABILITY_ID,
-            "RaftGet",
-            encode_protobuf(raft::GetValueRequest {
-                key: key.to_string(),
-                index: storage_index,
-                uuid: uuid.to_string(),
-                get_all,
-                get_by_prefix,
-            })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))
+) -> error::Result<raft::GetValueResponse> {
+    transport::default().request_proto(
+        CAPABILITY,
+        "RaftGet",
+        raft::GetValueRequest {
+            key: key.to_string(),
+            index: storage_index,
+            uuid: uuid.to_string(),
+            get_all,
+            get_by_prefix,
+        },
+    )
 }
@@ -1,4 +1,6 @@
 use crate::action;
+use crate::error::{self, Error};
+use crate::transport;
 use prost::Message;
 use vmh_codec::message::{
     encode_protobuf,
@@ -6,9 +8,8 @@ use vmh_codec::message::{
 };
 use vmh_codec::ADAPTER_RPC_CHANNEL_NAME;
 use wascc_actor::prelude::codec::messaging::BrokerMessage;
-use wascc_actor::prelude::*;
 
-pub fn ipfs_info(peer_id: Option<String>, uuid: String) -> anyhow::Result<rpc::IpfsInfoResponse> {
+pub fn ipfs_info(peer_id: Option<String>, uuid: String) -> error::Result<rpc::IpfsInfoResponse> {
     let rpc_res_bytes = call_adapter_rpc(
         rpc::AdapterClientRequest {
             msg: Some(rpc::adapter_client_request::Msg::IpfsInfoRequest(
@@ -19,7 +20,8 @@ pub fn ipfs_info(peer_id: Option<String>, uuid: String) -> anyhow::Result<rpc::I
         },
         uuid,
     )?;
-    Ok(rpc::IpfsInfoResponse::decode(rpc_res_bytes.as_slice())?)
+    rpc::IpfsInfoResponse::decode(rpc_res_bytes.as_slice())
+        .map_err(|e| Error::decode(ADAPTER_RPC_CHANNEL_NAME, "IpfsInfo", e))
 }
 
 pub fn ipfs_block_get<F>(
@@ -29,7 +31,7 @@ pub fn ipfs_block_get<F>(
     wait_locally: bool,
     return_if_not_exist: bool,
     callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
 {
@@ -51,30 +53,54 @@ where
     )
 }
 
-pub fn call_adapter_rpc(req: rpc::AdapterClientRequest, uuid: String) -> anyhow::Result<Vec<u8>> {
-    untyped::default()
-        .call(
-            vmh_codec::VMH_CAPABILITY_ID,
-            vmh_codec::OP_OUTBOUND_MESSAGE,
-            encode_protobuf(vmh::OutboundRequest {
-                uuid,
-                channel: ADAPTER_RPC_CHANNEL_NAME.into(),
-                msg: Some(vmh::outbound_request::Msg::AdapterClientRequest(req)),
-            })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))
+pub fn call_adapter_rpc(req: rpc::AdapterClientRequest, uuid: String) -> error::Result<Vec<u8>> {
+    transport::default().request(
+        vmh_codec::VMH_CAPABILITY_ID,
+        vmh_codec::OP_OUTBOUND_MESSAGE,
+        encode_protobuf(vmh::OutboundRequest {
+            uuid,
+            channel: ADAPTER_RPC_CHANNEL_NAME.into(),
+            msg: Some(vmh::outbound_request::Msg::AdapterClientRequest(req)),
+        })
+        .map_err(|e| {
+            Error::invalid_input(
+                vmh_codec::VMH_CAPABILITY_ID,
+                vmh_codec::OP_OUTBOUND_MESSAGE,
+                e,
+            )
+        })?,
+    )
 }
 
 pub fn call_adapter_rpc_async<F, R>(
+    request_fun: R,
+    mut callback: F,
+    uuid: String,
+) -> anyhow::Result<action::PendingCall>
+where
+    F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
+    R: FnMut(&str) -> anyhow::Result<rpc::AdapterClientRequest> + Sync + Send + 'static,
+{
+    call_adapter_rpc_async_ex(
+        request_fun,
+        action::CallOptions::default(),
+        move |reply| callback(reply?),
+        uuid,
+    )
+}
+
+/// Like `call_adapter_rpc_async`, but failures such as a timeout are handed to the callback too.
+pub fn call_adapter_rpc_async_ex<F, R>(
     mut request_fun: R,
+    options: action::CallOptions,
     callback: F,
     uuid: String,
-) -> anyhow::Result<()>
+) -> anyhow::Result<action::PendingCall>
 where
-    F: FnMut(&BrokerMessage) -> anyhow::Result<()> + Sync + Send + 'static,
+    F: FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
     R: FnMut(&str) -> anyhow::Result<rpc::AdapterClientRequest> + Sync + Send + 'static,
 {
-    action::call_async(
+    action::call_async_ex(
         vmh_codec::VMH_CAPABILITY_ID,
         vmh_codec::OP_OUTBOUND_MESSAGE,
         move |callback_uuid| {
@@ -86,28 +112,37 @@ where
                 )),
             })
         },
+        options,
         callback,
     )
 }
 
-pub fn register_adapter_dispatcher(type_ids: Vec<u32>) -> anyhow::Result<()> {
-    untyped::default()
-        .call(
-            tea_codec::VMH_CAPABILITY_ID,
-            vmh_codec::OP_REG_ADAPTER_DISPATCHER_MESSAGE,
-            encode_protobuf(vmh::RegisterDispatcherRequest { type_ids })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
+pub fn register_adapter_dispatcher(type_ids: Vec<u32>) -> error::Result<()> {
+    transport::default().request(
+        tea_codec::VMH_CAPABILITY_ID,
+        vmh_codec::OP_REG_ADAPTER_DISPATCHER_MESSAGE,
+        encode_protobuf(vmh::RegisterDispatcherRequest { type_ids }).map_err(|e| {
+            Error::invalid_input(
+                tea_codec::VMH_CAPABILITY_ID,
+                vmh_codec::OP_REG_ADAPTER_DISPATCHER_MESSAGE,
+                e,
+            )
+        })?,
+    )?;
     Ok(())
 }
 
-pub fn register_adapter_http_dispatcher(actions: Vec<String>) -> anyhow::Result<()> {
-    untyped::default()
-        .call(
-            tea_codec::VMH_CAPABILITY_ID,
-            vmh_codec::OP_REG_ADAPTER_HTTP_DISPATCHER_MESSAGE,
-            encode_protobuf(vmh::RegisterHttpDispatcherRequest { actions })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
+pub fn register_adapter_http_dispatcher(actions: Vec<String>) -> error::Result<()> {
+    transport::default().request(
+        tea_codec::VMH_CAPABILITY_ID,
+        vmh_codec::OP_REG_ADAPTER_HTTP_DISPATCHER_MESSAGE,
+        encode_protobuf(vmh::RegisterHttpDispatcherRequest { actions }).map_err(|e| {
+            Error::invalid_input(
+                tea_codec::VMH_CAPABILITY_ID,
+                vmh_codec::OP_REG_ADAPTER_HTTP_DISPATCHER_MESSAGE,
+                e,
+            )
+        })?,
+    )?;
     Ok(())
 }
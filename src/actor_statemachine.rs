@@ -1,7 +1,7 @@
-use prost::Message;
+use crate::error;
+use crate::transport;
 use tea_codec::TOKENSTATE_CAPABILITY_ID;
 use vmh_codec::message::structs_proto::tokenstate::*;
-use wascc_actor::prelude::*;
 pub const OP_START_TXN: &str = "StartTxn";
 pub const OP_QUERY_STATE_TSID: &str = "QueryStateTsid";
 pub const OP_QUERY_TEA_BALANCE: &str = "QueryTeaBalance";
@@ -12,84 +12,54 @@ pub const OP_TOPUP: &str = "Topup";
 pub const OP_WITHDRAW: &str = "Withdraw";
 pub const OP_MOVE: &str = "Move";
 
-pub fn start_txn() -> HandlerResult<Vec<u8>> {
-	let res = StateOperateResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_START_TXN, Vec::new())?
-			.as_slice(),
+pub fn start_txn() -> error::Result<Vec<u8>> {
+	let res: StateOperateResponse = transport::default().request_proto(
+		TOKENSTATE_CAPABILITY_ID,
+		OP_START_TXN,
+		(),
 	)?;
 	Ok(res.ctx)
 }
 
-pub fn topup(req: TopupRequest) -> HandlerResult<Vec<u8>> {
-	let mut buf = Vec::with_capacity(req.encoded_len());
-	req.encode(&mut buf).expect("req encoded error");
-	let res = StateOperateResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_TOPUP, buf)?
-			.as_slice(),
-	)?;
+pub fn topup(req: TopupRequest) -> error::Result<Vec<u8>> {
+	let res: StateOperateResponse =
+		transport::default().request_proto(TOKENSTATE_CAPABILITY_ID, OP_TOPUP, req)?;
 	Ok(res.ctx)
 }
 
-pub fn withdraw(req: WithdrawRequest) -> HandlerResult<Vec<u8>> {
-	let mut buf = Vec::with_capacity(req.encoded_len());
-	req.encode(&mut buf).expect("req encoded error");
-	let res = StateOperateResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_WITHDRAW, buf)?
-			.as_slice(),
-	)?;
+pub fn withdraw(req: WithdrawRequest) -> error::Result<Vec<u8>> {
+	let res: StateOperateResponse =
+		transport::default().request_proto(TOKENSTATE_CAPABILITY_ID, OP_WITHDRAW, req)?;
 	Ok(res.ctx)
 }
-pub fn mov(req: MoveRequest) -> HandlerResult<Vec<u8>> {
-	let mut buf = Vec::with_capacity(req.encoded_len());
-	req.encode(&mut buf).expect("req encoded error");
-	let res = StateOperateResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_MOVE, buf)?
-			.as_slice(),
-	)?;
+pub fn mov(req: MoveRequest) -> error::Result<Vec<u8>> {
+	let res: StateOperateResponse =
+		transport::default().request_proto(TOKENSTATE_CAPABILITY_ID, OP_MOVE, req)?;
 	Ok(res.ctx)
 }
-pub fn commit(req: CommitRequest) -> HandlerResult<Vec<u8>> {
-	let mut buf = Vec::with_capacity(req.encoded_len());
-	req.encode(&mut buf).expect("req encoded error");
+pub fn commit(req: CommitRequest) -> error::Result<Vec<u8>> {
 	info!("line63");
-	let res = StateOperateResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_COMMIT_TXN, buf)?
-			.as_slice(),
-	)?;
+	let res: StateOperateResponse =
+		transport::default().request_proto(TOKENSTATE_CAPABILITY_ID, OP_COMMIT_TXN, req)?;
 	info!("line69");
 	Ok(res.ctx)
 }
-pub fn query_token_balance(req: QueryTokenBalanceRequest) -> HandlerResult<(Vec<u8>, Vec<u8>)> {
-	let mut buf = Vec::with_capacity(req.encoded_len());
-	req.encode(&mut buf).expect("req encoded error");
-	let res = QueryTokenBalanceResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_QUERY_TOKEN_BALANCE, buf)?
-			.as_slice(),
-	)?;
+pub fn query_token_balance(req: QueryTokenBalanceRequest) -> error::Result<(Vec<u8>, Vec<u8>)> {
+	let res: QueryTokenBalanceResponse =
+		transport::default().request_proto(TOKENSTATE_CAPABILITY_ID, OP_QUERY_TOKEN_BALANCE, req)?;
 	Ok((res.balance_bytes, res.state_tsid))
 }
 
-pub fn query_tea_balance(req: QueryTeaBalanceRequest) -> HandlerResult<(Vec<u8>, Vec<u8>)> {
-	let mut buf = Vec::with_capacity(req.encoded_len());
-	req.encode(&mut buf).expect("req encoded error");
-	let res = QueryTeaBalanceResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_QUERY_TEA_BALANCE, buf)?
-			.as_slice(),
-	)?;
+pub fn query_tea_balance(req: QueryTeaBalanceRequest) -> error::Result<(Vec<u8>, Vec<u8>)> {
+	let res: QueryTeaBalanceResponse =
+		transport::default().request_proto(TOKENSTATE_CAPABILITY_ID, OP_QUERY_TEA_BALANCE, req)?;
 	Ok((res.balance_bytes, res.state_tsid))
 }
-pub fn query_state_tsid() -> HandlerResult<Vec<u8>> {
-	let res = QueryStateTsidResponse::decode(
-		untyped::default()
-			.call(TOKENSTATE_CAPABILITY_ID, OP_QUERY_STATE_TSID, Vec::new())?
-			.as_slice(),
+pub fn query_state_tsid() -> error::Result<Vec<u8>> {
+	let res: QueryStateTsidResponse = transport::default().request_proto(
+		TOKENSTATE_CAPABILITY_ID,
+		OP_QUERY_STATE_TSID,
+		(),
 	)?;
 	Ok(res.state_tsid)
 }
@@ -1,23 +1,18 @@
-use ed25519_dalek::Keypair;
-#[cfg(feature = "tpm")]
-use prost::Message;
+use crate::error::{self, Error};
 #[cfg(feature = "tpm")]
-use tea_codec::error::TeaError;
-use wascc_actor::prelude::*;
+use crate::transport;
+use ed25519_dalek::Keypair;
 
-pub fn url_decode(url: &str) -> HandlerResult<String> {
-	let value = url::Url::parse(url)?;
+pub fn url_decode(url: &str) -> error::Result<String> {
+	let value = url::Url::parse(url).map_err(|e| Error::invalid_input("url", "Parse", e))?;
 	Ok(value.to_string())
 }
 
 #[cfg(feature = "tpm")]
-pub fn generate_rsa_keypair() -> anyhow::Result<crate::tpm_provider_proto::RsaKeyPairPemPcsk1> {
+pub fn generate_rsa_keypair() -> error::Result<crate::tpm_provider_proto::RsaKeyPairPemPcsk1> {
 	//HandlerResult<actor_delegate_proto::DataRegisterResponse> {
-	let res_rsa_key_pkcs1 = untyped::default()
-		.call("tea:tpm", "GenerateRsaPkcs1", Vec::new())
-		.map_err(|e| TeaError::CommonError(format!("{}", e)))?;
-	let rsa_key_pkcs1 =
-		crate::tpm_provider_proto::RsaKeyPairPemPcsk1::decode(res_rsa_key_pkcs1.as_slice())?;
+	let rsa_key_pkcs1: crate::tpm_provider_proto::RsaKeyPairPemPcsk1 =
+		transport::default().request_proto("tea:tpm", "GenerateRsaPkcs1", ())?;
 
 	// info!(
 	//     "rsa_key_pkcs1 to string is \n{}\n{}",
@@ -26,7 +21,8 @@ pub fn generate_rsa_keypair() -> anyhow::Result<crate::tpm_provider_proto::RsaKe
 	Ok(rsa_key_pkcs1)
 }
 
-pub fn get_public_key_from_bytes(key_bytes: &[u8]) -> anyhow::Result<[u8; 32]> {
-	let keypair = Keypair::from_bytes(&key_bytes)?;
+pub fn get_public_key_from_bytes(key_bytes: &[u8]) -> error::Result<[u8; 32]> {
+	let keypair =
+		Keypair::from_bytes(key_bytes).map_err(|e| Error::invalid_input("ed25519", "FromBytes", e))?;
 	Ok(keypair.public.to_bytes())
 }
@@ -0,0 +1,249 @@
+//! Correlation ids of pending calls.
+//!
+//! An id reads `{actor}-{kind}-{seq}`, e.g. `tpm-intercom-42`, so replies to different actors and
+//! different kinds of call never share an id, provided every actor calls `set_actor_name`.
+//! Actors that keep `DEFAULT_ACTOR_NAME` are only told apart by the host sequence number. An id
+//! is always a single Nats token and can be appended to a reply subject.
+//!
+//! When the host cannot provide a sequence number a local counter is used instead, marked with
+//! an `l` (`tpm-intercom-l3`) so that it cannot collide with host numbers. The counter starts
+//! from the system time in milliseconds times `LOCAL_IDS_PER_MS`, so ids of a restarted instance
+//! do not collide with the ones an earlier instance left in durable records.
+
+use crate::actor_env;
+use crate::actor_extras;
+use lazy_static::lazy_static;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+lazy_static! {
+    static ref ACTOR_NAME: Mutex<String> = Mutex::new(DEFAULT_ACTOR_NAME.to_string());
+    static ref LOCAL_SEQUENCE: AtomicU64 = AtomicU64::new(local_seed());
+}
+
+static WARNED_DEFAULT_NAME: AtomicBool = AtomicBool::new(false);
+
+/// Local ids an instance may generate per millisecond it runs before running into the ones of
+/// an instance started later.
+pub const LOCAL_IDS_PER_MS: u64 = 1024;
+
+/// Actor part of the ids generated before `set_actor_name` is called. Ids of actors that keep it
+/// are only told apart by the host sequence number.
+pub const DEFAULT_ACTOR_NAME: &str = "actor";
+
+/// Set the actor part of the generated ids, usually once when the actor starts.
+pub fn set_actor_name(name: &str) {
+    match ACTOR_NAME.lock() {
+        Ok(mut actor) => *actor = sanitize(name, false),
+        Err(e) => error!("Set actor name lock failed, details: {:?}", e),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum CallKind {
+    /// Host capability call answered asynchronously (`action::call_async`).
+    Call,
+    /// Message published with a reply subject (`action::call`).
+    Publish,
+    Intercom,
+    Delay,
+    Other(String),
+}
+
+impl CallKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            CallKind::Call => "call",
+            CallKind::Publish => "publish",
+            CallKind::Intercom => "intercom",
+            CallKind::Delay => "delay",
+            CallKind::Other(kind) => kind,
+        }
+    }
+
+    fn parse(kind: &str) -> CallKind {
+        match kind {
+            "call" => CallKind::Call,
+            "publish" => CallKind::Publish,
+            "intercom" => CallKind::Intercom,
+            "delay" => CallKind::Delay,
+            other => CallKind::Other(other.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CorrelationId {
+    pub actor: String,
+    pub kind: CallKind,
+    pub seq: u64,
+    /// `seq` comes from the local fallback counter rather than the host.
+    pub local: bool,
+}
+
+impl CorrelationId {
+    /// Parse an id produced by `next`. Returns `None` for anything else, such as the bare
+    /// sequence numbers used by older versions.
+    pub fn parse(id: &str) -> Option<CorrelationId> {
+        // the actor name may contain '-', kind and seq may not
+        let mut parts = id.rsplitn(3, '-');
+        let seq = parts.next()?;
+        let kind = parts.next()?;
+        let actor = parts.next()?;
+        if actor.is_empty() || kind.is_empty() {
+            return None;
+        }
+        let (seq, local) = match seq.strip_prefix('l') {
+            Some(seq) => (seq, true),
+            None => (seq, false),
+        };
+        Some(CorrelationId {
+            actor: actor.to_string(),
+            kind: CallKind::parse(kind),
+            seq: seq.parse().ok()?,
+            local,
+        })
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}{}",
+            self.actor,
+            sanitize(self.kind.as_str(), true),
+            if self.local { "l" } else { "" },
+            self.seq
+        )
+    }
+}
+
+/// Generate a new id for a call of `kind`.
+pub fn next(kind: CallKind) -> CorrelationId {
+    let actor = match ACTOR_NAME.lock() {
+        Ok(actor) => actor.clone(),
+        Err(e) => {
+            error!("Correlation actor name lock failed, details: {:?}", e);
+            DEFAULT_ACTOR_NAME.to_string()
+        }
+    };
+    if actor == DEFAULT_ACTOR_NAME && !WARNED_DEFAULT_NAME.swap(true, Ordering::Relaxed) {
+        warn!(
+            "generating correlation ids under the default actor name, call set_actor_name so \
+             they cannot collide with other actors"
+        );
+    }
+    let (seq, local) = match actor_extras::get_sequence_number() {
+        Ok(seq) => (seq, false),
+        Err(e) => {
+            warn!("cannot get sequence number, using local counter: {}", e);
+            (LOCAL_SEQUENCE.fetch_add(1, Ordering::SeqCst), true)
+        }
+    };
+    CorrelationId {
+        actor,
+        kind,
+        seq,
+        local,
+    }
+}
+
+fn local_seed() -> u64 {
+    let now = actor_env::get_system_time()
+        .map_err(anyhow::Error::from)
+        .and_then(|now| Ok(now.duration_since(UNIX_EPOCH)?));
+    match now {
+        Ok(now) => (now.as_millis() as u64).saturating_mul(LOCAL_IDS_PER_MS),
+        Err(e) => {
+            warn!("cannot seed local correlation ids, starting from 0: {}", e);
+            0
+        }
+    }
+}
+
+// keep ids a single Nats token; kinds may not contain '-' either so that parse can split them
+fn sanitize(part: &str, is_kind: bool) -> String {
+    let sanitized: String = part
+        .chars()
+        .map(|c| match c {
+            '.' | '*' | '>' => '_',
+            '-' if is_kind => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect();
+    if sanitized.is_empty() {
+        if is_kind { "other" } else { DEFAULT_ACTOR_NAME }.to_string()
+    } else {
+        sanitized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{local_seed, next, CallKind, CorrelationId, LOCAL_IDS_PER_MS};
+    use crate::transport::{self, ScriptedTransport};
+    use std::time::{Duration, UNIX_EPOCH};
+    use wascc_actor::prelude::codec::extras::OP_REQUEST_SEQUENCE;
+    use wascc_actor::prelude::serialize;
+
+    #[test]
+    fn parse_reverses_display() {
+        let ids = vec![
+            CorrelationId {
+                actor: "tpm".into(),
+                kind: CallKind::Intercom,
+                seq: 42,
+                local: false,
+            },
+            CorrelationId {
+                actor: "tea-layer1".into(),
+                kind: CallKind::Delay,
+                seq: 3,
+                local: true,
+            },
+            CorrelationId {
+                actor: "actor".into(),
+                kind: CallKind::Other("retry".into()),
+                seq: 0,
+                local: false,
+            },
+        ];
+        for id in ids {
+            assert_eq!(CorrelationId::parse(&id.to_string()), Some(id));
+        }
+        assert_eq!(
+            CorrelationId::parse("tea-layer1-delay-l3")
+                .unwrap()
+                .to_string(),
+            "tea-layer1-delay-l3"
+        );
+        assert_eq!(CorrelationId::parse("42"), None);
+        assert_eq!(CorrelationId::parse("tpm-call-x"), None);
+    }
+
+    #[test]
+    fn local_ids_start_after_earlier_instances() {
+        let scripted = ScriptedTransport::new();
+        let _guard = transport::replace(scripted.clone());
+        let started = UNIX_EPOCH + Duration::from_millis(1_600_000_000_000);
+
+        scripted.respond("tea:env", "GetSystemTime", serialize(started).unwrap());
+        let earlier = local_seed();
+        assert_eq!(earlier, 1_600_000_000_000 * LOCAL_IDS_PER_MS);
+        let restarted = started + Duration::from_millis(1);
+        scripted.respond("tea:env", "GetSystemTime", serialize(restarted).unwrap());
+        assert_eq!(local_seed(), earlier + LOCAL_IDS_PER_MS);
+        scripted.fail("tea:env", "GetSystemTime", "host down");
+        assert_eq!(local_seed(), 0);
+
+        scripted.fail("wascc:extras", OP_REQUEST_SEQUENCE, "host down");
+        scripted.fail("tea:env", "GetSystemTime", "host down");
+        let id = next(CallKind::Call);
+        assert!(id.local);
+        assert_eq!(CorrelationId::parse(&id.to_string()), Some(id));
+    }
+}
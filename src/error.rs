@@ -0,0 +1,196 @@
+//! Error returned by the capability wrappers of this crate.
+//!
+//! Every variant names the capability and operation involved, so callers can match on the cause:
+//!
+//! ```ignore
+//! match actor_raft::raft_get_value(key, 0, uuid) {
+//!     Err(Error::NotFound { .. }) => { /* first run */ }
+//!     Err(e) => return Err(e.into()),
+//!     Ok(value) => { /* code snipet... */ }
+//! }
+//! ```
+//! It converts into both `anyhow::Error` and the boxed error of `HandlerResult`.
+
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum Error {
+    /// The host or the provider could not be reached, or rejected the call.
+    #[error("{capability} {operation} host call failed: {message}")]
+    HostCall {
+        capability: String,
+        operation: String,
+        message: String,
+    },
+    /// The response could not be decoded.
+    #[error("{capability} {operation} response cannot be decoded: {message}")]
+    Decode {
+        capability: String,
+        operation: String,
+        message: String,
+    },
+    /// The provider answered with an error of its own.
+    #[error("{capability} {operation} failed: {message}")]
+    Provider {
+        capability: String,
+        operation: String,
+        message: String,
+    },
+    /// No reply arrived for pending call `uuid` before its deadline. Capability and operation are
+    /// empty for replies registered with `action::expect_replies`.
+    #[error("{capability} {operation} call {uuid} expired without reply")]
+    Timeout {
+        capability: String,
+        operation: String,
+        uuid: String,
+    },
+    #[error("{capability} {operation} found no {what}")]
+    NotFound {
+        capability: String,
+        operation: String,
+        what: String,
+    },
+    /// The request could not be built from the given arguments.
+    #[error("{capability} {operation} invalid input: {message}")]
+    InvalidInput {
+        capability: String,
+        operation: String,
+        message: String,
+    },
+    /// An intercom request was refused because its chain already counts `max_hops` requests,
+    /// see `intercom::envelope`.
+    #[error("{capability} {operation} refused: intercom chain exceeds {max_hops} hops")]
+    HopLimit {
+        capability: String,
+        operation: String,
+        max_hops: u32,
+    },
+    /// Another execution holds the reservation of idempotency `key`, or its outcome is unknown,
+    /// see `idempotency::once`.
+    #[error("{capability} {operation} key {key} is already in progress")]
+    InProgress {
+        capability: String,
+        operation: String,
+        key: String,
+    },
+    /// Pending call `uuid` was abandoned, or a new call refused with an empty `uuid`, because
+    /// the actor is draining, see `action::drain`.
+    #[error("{capability} {operation} call {uuid} abandoned at shutdown")]
+    Shutdown {
+        capability: String,
+        operation: String,
+        uuid: String,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn host_call(capability: &str, operation: &str, error: impl Display) -> Self {
+        Error::HostCall {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn decode(capability: &str, operation: &str, error: impl Display) -> Self {
+        Error::Decode {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn provider(capability: &str, operation: &str, error: impl Display) -> Self {
+        Error::Provider {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn timeout(capability: &str, operation: &str, uuid: &str) -> Self {
+        Error::Timeout {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            uuid: uuid.to_string(),
+        }
+    }
+
+    pub fn not_found(capability: &str, operation: &str, what: impl Display) -> Self {
+        Error::NotFound {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            what: what.to_string(),
+        }
+    }
+
+    pub fn invalid_input(capability: &str, operation: &str, error: impl Display) -> Self {
+        Error::InvalidInput {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn hop_limit(capability: &str, operation: &str, max_hops: u32) -> Self {
+        Error::HopLimit {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            max_hops,
+        }
+    }
+
+    pub fn in_progress(capability: &str, operation: &str, key: &str) -> Self {
+        Error::InProgress {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn shutdown(capability: &str, operation: &str, uuid: &str) -> Self {
+        Error::Shutdown {
+            capability: capability.to_string(),
+            operation: operation.to_string(),
+            uuid: uuid.to_string(),
+        }
+    }
+
+    pub fn capability(&self) -> &str {
+        match self {
+            Error::HostCall { capability, .. }
+            | Error::Decode { capability, .. }
+            | Error::Provider { capability, .. }
+            | Error::Timeout { capability, .. }
+            | Error::NotFound { capability, .. }
+            | Error::InvalidInput { capability, .. }
+            | Error::HopLimit { capability, .. }
+            | Error::InProgress { capability, .. }
+            | Error::Shutdown { capability, .. } => capability,
+        }
+    }
+
+    pub fn operation(&self) -> &str {
+        match self {
+            Error::HostCall { operation, .. }
+            | Error::Decode { operation, .. }
+            | Error::Provider { operation, .. }
+            | Error::Timeout { operation, .. }
+            | Error::NotFound { operation, .. }
+            | Error::InvalidInput { operation, .. }
+            | Error::HopLimit { operation, .. }
+            | Error::InProgress { operation, .. }
+            | Error::Shutdown { operation, .. } => operation,
+        }
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Error::Timeout { .. })
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        matches!(self, Error::Shutdown { .. })
+    }
+}
@@ -0,0 +1,464 @@
+//! Single-threaded executor for awaiting replies instead of nesting callbacks.
+//!
+//! A `Reply` is a future resolved by the callback of a pending call. Tasks are polled as soon as
+//! they are spawned, then again whenever a reply they wait for is delivered by
+//! `action::result_handler` or expired by `action::sweep_expired`, so actors keep routing their
+//! replies to `result_handler` as before.
+//!
+//! ```ignore
+//! executor::spawn(async move {
+//!     let info = executor::intercom("actor.pinner", "actor.me", msg).await?;
+//!     let profile = executor::lookup_node_profile(&info.body, "actor.me.reply").await?;
+//!     //code snipet...
+//!     Ok(())
+//! });
+//! ```
+//! Dropping a `Reply` before it resolves cancels its call. A task continues the intercom chain
+//! it was spawned in, see `intercom::envelope`.
+
+use crate::action::{self, registry, CallOptions, PendingCall};
+use crate::actor_rpc::adapter;
+use crate::fanout::Sink;
+use crate::intercom::envelope::{self, Envelope};
+use crate::layer1;
+use lazy_static::lazy_static;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use vmh_codec::message::structs_proto::{ra, rpc};
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+type Task = Pin<Box<dyn Future<Output = ()> + 'static>>;
+
+/// A task and the intercom chain it was spawned in, which it continues whenever it is polled.
+struct Spawned {
+    task: Task,
+    chain: Option<Envelope>,
+}
+
+lazy_static! {
+    // (executor, task) ids to poll, filled by wakers which must be Send + Sync
+    static ref READY: Mutex<VecDeque<(u64, u64)>> = Mutex::new(VecDeque::new());
+}
+
+static NEXT_EXECUTOR_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // tasks live on the thread that spawned them, each thread has its own executor
+    static EXECUTOR_ID: u64 = NEXT_EXECUTOR_ID.fetch_add(1, Ordering::SeqCst);
+    static TASKS: RefCell<HashMap<u64, Spawned>> = RefCell::new(HashMap::new());
+    static NEXT_TASK_ID: Cell<u64> = const { Cell::new(0) };
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+struct TaskWaker {
+    executor: u64,
+    task: u64,
+}
+
+impl TaskWaker {
+    fn new(task: u64) -> Arc<Self> {
+        Arc::new(TaskWaker {
+            executor: EXECUTOR_ID.with(|id| *id),
+            task,
+        })
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        match READY.lock() {
+            Ok(mut ready) => ready.push_back((self.executor, self.task)),
+            Err(e) => error!("Executor wake lock failed, details: {:?}", e),
+        }
+    }
+}
+
+/// Run `future` until it completes. An error it returns is logged.
+pub fn spawn<F>(future: F)
+where
+    F: Future<Output = anyhow::Result<()>> + 'static,
+{
+    let id = NEXT_TASK_ID.with(|next| {
+        let id = next.get();
+        next.set(id + 1);
+        id
+    });
+    let task: Task = Box::pin(async move {
+        if let Err(e) = future.await {
+            error!("executor task {} failed: {}", id, e);
+        }
+    });
+    let spawned = Spawned {
+        task,
+        chain: envelope::current(),
+    };
+    TASKS.with(|tasks| tasks.borrow_mut().insert(id, spawned));
+    TaskWaker::new(id).wake();
+    run_until_stalled();
+}
+
+/// Poll every woken task until none can make progress. Does nothing when called from inside a
+/// task, the outer call picks up whatever got woken. A task that panics is dropped, the panic is
+/// logged, see `registry::isolate` for the targets where this holds.
+pub fn run_until_stalled() {
+    if RUNNING.with(|running| running.replace(true)) {
+        return;
+    }
+    let _running = Running;
+    let executor = EXECUTOR_ID.with(|id| *id);
+    loop {
+        let next = match READY.lock() {
+            Ok(mut ready) => ready
+                .iter()
+                .position(|(e, _)| *e == executor)
+                .and_then(|i| ready.remove(i))
+                .map(|(_, task)| task),
+            Err(e) => {
+                error!("Executor run lock failed, details: {:?}", e);
+                None
+            }
+        };
+        let id = match next {
+            Some(id) => id,
+            None => break,
+        };
+        // taken out while polled so that the task can spawn others
+        let spawned = TASKS.with(|tasks| tasks.borrow_mut().remove(&id));
+        if let Some(mut spawned) = spawned {
+            let waker = Waker::from(TaskWaker::new(id));
+            let polled = {
+                let _scope = envelope::enter(spawned.chain);
+                let task = &mut spawned.task;
+                registry::isolate(|| Ok(task.as_mut().poll(&mut Context::from_waker(&waker))))
+            };
+            match polled {
+                Ok(Poll::Pending) => {
+                    TASKS.with(|tasks| tasks.borrow_mut().insert(id, spawned));
+                }
+                Ok(Poll::Ready(())) => {}
+                Err(e) => error!("executor task {} dropped: {}", id, e),
+            }
+        }
+    }
+}
+
+/// Clears `RUNNING` when `run_until_stalled` returns or unwinds.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+/// Number of spawned tasks that have not completed yet.
+pub fn pending_tasks() -> usize {
+    TASKS.with(|tasks| tasks.borrow().len())
+}
+
+struct Slot<T> {
+    value: Option<anyhow::Result<T>>,
+    waker: Option<Waker>,
+}
+
+/// Future of the outcome of one async call.
+pub struct Reply<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+    call: Option<PendingCall>,
+    send_error: Option<anyhow::Error>,
+}
+
+/// Send a call with `launch` and return the future of the outcome it hands to its sink. This is
+/// how callback based functions not wrapped below can be awaited.
+pub fn reply<T, L>(launch: L) -> Reply<T>
+where
+    T: Send + 'static,
+    L: FnOnce(Sink<T>) -> anyhow::Result<PendingCall>,
+{
+    let slot = Arc::new(Mutex::new(Slot {
+        value: None,
+        waker: None,
+    }));
+    let sink_slot = slot.clone();
+    let sink: Sink<T> = Box::new(move |result| {
+        let waker = {
+            let mut slot = sink_slot
+                .lock()
+                .map_err(|e| anyhow::anyhow!("reply slot lock failed: {:?}", e))?;
+            if slot.value.is_none() {
+                slot.value = Some(result);
+            }
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    });
+    match launch(sink) {
+        Ok(call) => Reply {
+            slot,
+            call: Some(call),
+            send_error: None,
+        },
+        Err(e) => Reply {
+            slot,
+            call: None,
+            send_error: Some(e),
+        },
+    }
+}
+
+impl<T> Future for Reply<T> {
+    type Output = anyhow::Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Some(e) = this.send_error.take() {
+            return Poll::Ready(Err(e));
+        }
+        let mut slot = match this.slot.lock() {
+            Ok(slot) => slot,
+            Err(e) => return Poll::Ready(Err(anyhow::anyhow!("reply slot lock failed: {:?}", e))),
+        };
+        match slot.value.take() {
+            Some(value) => {
+                this.call = None;
+                Poll::Ready(value)
+            }
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Reply<T> {
+    fn drop(&mut self) {
+        if let Some(call) = self.call.take() {
+            call.cancel();
+        }
+    }
+}
+
+fn forward(
+    mut sink: Sink<BrokerMessage>,
+) -> impl FnMut(anyhow::Result<&BrokerMessage>) -> anyhow::Result<()> {
+    move |reply| sink(reply.cloned())
+}
+
+/// Awaitable `action::call_ex` with default options.
+pub fn call(subject: &str, reply_to: &str, param: Vec<u8>) -> Reply<BrokerMessage> {
+    reply(|sink| {
+        action::call_ex(
+            subject,
+            reply_to,
+            param,
+            CallOptions::default(),
+            forward(sink),
+        )
+    })
+}
+
+/// Awaitable `action::call_async_intercom_ex` with default options.
+pub fn intercom(
+    send_to_actor: &str,
+    reply_actor: &str,
+    msg: BrokerMessage,
+) -> Reply<BrokerMessage> {
+    reply(|sink| {
+        action::call_async_intercom_ex(
+            send_to_actor,
+            reply_actor,
+            msg,
+            CallOptions::default(),
+            forward(sink),
+        )
+    })
+}
+
+/// Resolves with the delayed message once `delay_seconds` have elapsed.
+pub fn delay(subject: &str, param: Vec<u8>, delay_seconds: u64) -> Reply<BrokerMessage> {
+    reply(|sink| {
+        action::delay_call_ex(
+            subject,
+            param,
+            delay_seconds,
+            CallOptions::default(),
+            forward(sink),
+        )
+    })
+}
+
+/// Awaitable `adapter::call_adapter_rpc_async_ex` with default options.
+pub fn adapter_rpc<R>(request_fun: R, uuid: String) -> Reply<BrokerMessage>
+where
+    R: FnMut(&str) -> anyhow::Result<rpc::AdapterClientRequest> + Sync + Send + 'static,
+{
+    reply(|sink| {
+        adapter::call_adapter_rpc_async_ex(request_fun, CallOptions::default(), forward(sink), uuid)
+    })
+}
+
+pub fn lookup_node_profile(ephemeral_id: &[u8], reply_to: &str) -> Reply<ra::NodeProfile> {
+    reply(|sink| {
+        layer1::lookup_node_profile_ex(ephemeral_id, reply_to, CallOptions::default(), sink)
+    })
+}
+
+pub fn lookup_node_profile_by_tea_id(tea_id: &[u8], reply_to: &str) -> Reply<ra::NodeProfile> {
+    reply(|sink| {
+        layer1::lookup_node_profile_by_tea_id_ex(tea_id, reply_to, CallOptions::default(), sink)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forward, pending_tasks, reply, spawn, Reply};
+    use crate::action::{self, lock_pending_calls, pending_registry, result_handler, CallOptions};
+    use crate::transport::{self, ScriptedTransport};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+    // a `tea:ipfs Pin` call whose uuid is written to `uuid` once known
+    fn pin(uuid: &Arc<Mutex<String>>) -> Reply<BrokerMessage> {
+        let uuid = uuid.clone();
+        reply(|sink| {
+            action::call_async_ex(
+                "tea:ipfs",
+                "Pin",
+                move |id| {
+                    *uuid.lock().unwrap() = id.to_string();
+                    Ok(vec![])
+                },
+                CallOptions::default(),
+                forward(sink),
+            )
+        })
+    }
+
+    fn answer(uuid: &Arc<Mutex<String>>, body: u8) {
+        let uuid = uuid.lock().unwrap().clone();
+        let msg = BrokerMessage {
+            subject: format!("reply.{}", uuid),
+            reply_to: "".into(),
+            body: vec![body],
+        };
+        result_handler(&msg, &uuid).unwrap();
+    }
+
+    #[test]
+    fn tasks_resume_when_their_replies_arrive() {
+        let _lock = lock_pending_calls();
+        let scripted = ScriptedTransport::new();
+        for _ in 0..2 {
+            scripted.respond("tea:ipfs", "Pin", vec![]);
+        }
+        let _guard = transport::replace(scripted);
+        let tasks = pending_tasks();
+        let first = Arc::new(Mutex::new(String::new()));
+        let second = Arc::new(Mutex::new(String::new()));
+        let bodies = Rc::new(RefCell::new(Vec::new()));
+
+        let (task_first, task_second, task_bodies) =
+            (first.clone(), second.clone(), bodies.clone());
+        spawn(async move {
+            let msg = pin(&task_first).await?;
+            task_bodies.borrow_mut().push(msg.body[0]);
+            let nested_bodies = task_bodies.clone();
+            // spawned from a task, polled by the outer run
+            spawn(async move {
+                let msg = pin(&task_second).await?;
+                nested_bodies.borrow_mut().push(msg.body[0]);
+                Ok(())
+            });
+            task_bodies.borrow_mut().push(0);
+            Ok(())
+        });
+        assert_eq!(pending_tasks(), tasks + 1);
+
+        answer(&first, 1);
+        assert_eq!(*bodies.borrow(), vec![1, 0]);
+        assert_eq!(pending_tasks(), tasks + 1);
+        answer(&second, 2);
+        assert_eq!(*bodies.borrow(), vec![1, 0, 2]);
+        assert_eq!(pending_tasks(), tasks);
+    }
+
+    #[test]
+    fn dropping_a_reply_cancels_its_call() {
+        let _lock = lock_pending_calls();
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:ipfs", "Pin", vec![]);
+        scripted.fail("tea:ipfs", "Pin", "provider down");
+        let _guard = transport::replace(scripted);
+
+        let uuid = Arc::new(Mutex::new(String::new()));
+        let pending = pin(&uuid);
+        let id = uuid.lock().unwrap().clone();
+        assert!(pending_registry().contains(&id));
+        drop(pending);
+        assert!(!pending_registry().contains(&id));
+        assert!(pending_registry().take_cancelled(&id));
+
+        let failed = Rc::new(RefCell::new(None));
+        let task_failed = failed.clone();
+        spawn(async move {
+            *task_failed.borrow_mut() = Some(pin(&uuid).await.unwrap_err().to_string());
+            Ok(())
+        });
+        assert_eq!(
+            failed.borrow().as_deref(),
+            Some("tea:ipfs Pin host call failed: provider down")
+        );
+    }
+
+    #[test]
+    fn panicking_tasks_are_dropped() {
+        let _lock = lock_pending_calls();
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:ipfs", "Pin", vec![]);
+        scripted.respond("tea:ipfs", "Pin", vec![]);
+        let _guard = transport::replace(scripted);
+        let tasks = pending_tasks();
+
+        spawn(async { panic!("boom") });
+        assert_eq!(pending_tasks(), tasks);
+
+        let (first, second) = (
+            Arc::new(Mutex::new(String::new())),
+            Arc::new(Mutex::new(String::new())),
+        );
+        let task_first = first.clone();
+        spawn(async move {
+            pin(&task_first).await?;
+            panic!("boom after reply")
+        });
+        let bodies = Rc::new(RefCell::new(Vec::new()));
+        let (task_second, task_bodies) = (second.clone(), bodies.clone());
+        spawn(async move {
+            let msg = pin(&task_second).await?;
+            task_bodies.borrow_mut().push(msg.body[0]);
+            Ok(())
+        });
+        assert_eq!(pending_tasks(), tasks + 2);
+
+        answer(&first, 1);
+        assert_eq!(pending_tasks(), tasks + 1);
+        answer(&second, 2);
+        assert_eq!(*bodies.borrow(), vec![2]);
+        assert_eq!(pending_tasks(), tasks);
+    }
+}
@@ -0,0 +1,377 @@
+//! Fan-out/fan-in over async calls.
+//!
+//! Every sub-call is described by a `Launcher`: a closure that sends the call and hands its
+//! outcome to the `Sink` it is given. Launchers should send through the `_ex` functions of
+//! `action` (e.g. `call_async_intercom_ex`) so that timeouts reach the sink as errors, otherwise
+//! a lost reply keeps the combinator waiting until the call is swept.
+//!
+//! ```ignore
+//! let launchers: Vec<Launcher<BrokerMessage>> = actors
+//!     .iter()
+//!     .map(|actor| {
+//!         let actor = actor.to_string();
+//!         let msg = msg.clone();
+//!         Box::new(move |mut sink: Sink<BrokerMessage>| {
+//!             action::call_async_intercom_ex(&actor, "me", msg, CallOptions::default(), move |r| {
+//!                 sink(r.map(|m| m.clone()))
+//!             })
+//!         }) as Launcher<BrokerMessage>
+//!     })
+//!     .collect();
+//! fanout::join_all(launchers, |results| { /* one result per actor, in order */ Ok(()) })?;
+//! ```
+
+use crate::action::PendingCall;
+use std::sync::{Arc, Mutex};
+
+/// Receives the outcome of one sub-call.
+pub type Sink<T> = Box<dyn FnMut(anyhow::Result<T>) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+/// Sends one sub-call, wiring its outcome to the sink.
+pub type Launcher<T> =
+    Box<dyn FnOnce(Sink<T>) -> anyhow::Result<PendingCall> + Sync + Send + 'static>;
+
+type Continuation<T> =
+    Box<dyn FnOnce(Vec<Option<anyhow::Result<T>>>) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+enum Completion {
+    All,
+    Successes(usize),
+}
+
+struct Gather<T> {
+    completion: Completion,
+    results: Vec<Option<anyhow::Result<T>>>,
+    pending: Vec<Option<PendingCall>>,
+    successes: usize,
+    failures: usize,
+    continuation: Option<Continuation<T>>,
+}
+
+impl<T> Gather<T> {
+    fn is_complete(&self) -> bool {
+        let total = self.results.len();
+        match self.completion {
+            Completion::All => self.successes + self.failures == total,
+            Completion::Successes(k) => self.successes >= k || self.failures > total - k,
+        }
+    }
+
+    /// Record the outcome of sub-call `index`. Returns the continuation, its input and the
+    /// calls to cancel once the combinator completes, exactly once.
+    fn settle(&mut self, index: usize, result: anyhow::Result<T>) -> Option<Finish<T>> {
+        if self.continuation.is_none() || self.results[index].is_some() {
+            return None;
+        }
+        match &result {
+            Ok(_) => self.successes += 1,
+            Err(_) => self.failures += 1,
+        }
+        self.results[index] = Some(result);
+        self.pending[index] = None;
+        self.finish_if_complete()
+    }
+
+    fn finish_if_complete(&mut self) -> Option<Finish<T>> {
+        if !self.is_complete() {
+            return None;
+        }
+        let continuation = self.continuation.take()?;
+        Some(Finish {
+            continuation,
+            results: std::mem::take(&mut self.results),
+            abandoned: self.pending.iter_mut().filter_map(|p| p.take()).collect(),
+        })
+    }
+}
+
+struct Finish<T> {
+    continuation: Continuation<T>,
+    results: Vec<Option<anyhow::Result<T>>>,
+    abandoned: Vec<PendingCall>,
+}
+
+impl<T> Finish<T> {
+    fn run(self) -> anyhow::Result<()> {
+        for call in self.abandoned.iter() {
+            call.cancel();
+        }
+        (self.continuation)(self.results)
+    }
+}
+
+fn gather<T>(
+    launchers: Vec<Launcher<T>>,
+    completion: Completion,
+    continuation: Continuation<T>,
+) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+{
+    let total = launchers.len();
+    let state = Arc::new(Mutex::new(Gather {
+        completion,
+        results: (0..total).map(|_| None).collect(),
+        pending: (0..total).map(|_| None).collect(),
+        successes: 0,
+        failures: 0,
+        continuation: Some(continuation),
+    }));
+    let finish = state.lock().unwrap().finish_if_complete();
+    if let Some(finish) = finish {
+        return finish.run();
+    }
+
+    for (index, launch) in launchers.into_iter().enumerate() {
+        if state.lock().unwrap().continuation.is_none() {
+            // completed by calls that settled while being sent, no need to send the rest
+            break;
+        }
+        let sink_state = state.clone();
+        let sink: Sink<T> = Box::new(move |result| {
+            let finish = sink_state.lock().unwrap().settle(index, result);
+            match finish {
+                Some(finish) => finish.run(),
+                None => Ok(()),
+            }
+        });
+        let launched = launch(sink);
+        let finish = {
+            let mut state = state.lock().unwrap();
+            match launched {
+                Ok(call) if state.continuation.is_none() => {
+                    call.cancel();
+                    None
+                }
+                Ok(call) => {
+                    if state.results[index].is_none() {
+                        state.pending[index] = Some(call);
+                    }
+                    None
+                }
+                Err(e) => state.settle(index, Err(e)),
+            }
+        };
+        if let Some(finish) = finish {
+            return finish.run();
+        }
+    }
+    Ok(())
+}
+
+/// Continue once every sub-call has answered or failed, with one result per launcher in
+/// launcher order.
+pub fn join_all<T, F>(launchers: Vec<Launcher<T>>, continuation: F) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+    F: FnOnce(Vec<anyhow::Result<T>>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    gather(
+        launchers,
+        Completion::All,
+        Box::new(move |results| {
+            continuation(
+                results
+                    .into_iter()
+                    .map(|r| r.unwrap_or_else(|| Err(anyhow::anyhow!("call not settled"))))
+                    .collect(),
+            )
+        }),
+    )
+}
+
+/// Continue with the index and value of the first sub-call that answers successfully. The
+/// remaining calls are cancelled. Fails only if every sub-call fails.
+pub fn select_first<T, F>(launchers: Vec<Launcher<T>>, continuation: F) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+    F: FnOnce(anyhow::Result<(usize, T)>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    quorum(1, launchers, move |result| {
+        continuation(result.map(|mut winners| winners.remove(0)))
+    })
+}
+
+/// Continue as soon as `k` sub-calls have answered successfully, with their indices and values
+/// in launcher order. The remaining calls are cancelled. Fails as soon as too many sub-calls
+/// have failed for `k` successes to be possible.
+pub fn quorum<T, F>(k: usize, launchers: Vec<Launcher<T>>, continuation: F) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+    F: FnOnce(anyhow::Result<Vec<(usize, T)>>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let total = launchers.len();
+    if k == 0 || k > total {
+        return Err(anyhow::anyhow!(
+            "quorum of {} cannot be reached with {} calls",
+            k,
+            total
+        ));
+    }
+    gather(
+        launchers,
+        Completion::Successes(k),
+        Box::new(move |results| {
+            let mut winners = Vec::with_capacity(k);
+            let mut errors = Vec::new();
+            for (index, result) in results.into_iter().enumerate() {
+                match result {
+                    Some(Ok(value)) => winners.push((index, value)),
+                    Some(Err(e)) => errors.push(format!("#{}: {}", index, e)),
+                    None => {}
+                }
+            }
+            if winners.len() >= k {
+                continuation(Ok(winners))
+            } else {
+                continuation(Err(anyhow::anyhow!(
+                    "quorum of {} not reached, {} of {} calls failed: {}",
+                    k,
+                    errors.len(),
+                    total,
+                    errors.join("; ")
+                )))
+            }
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{join_all, quorum, select_first, Launcher, Sink};
+    use crate::action::{lock_pending_calls, pending_registry, PendingCall};
+    use crate::transport::{self, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+
+    type Sinks = Arc<Mutex<Vec<Option<Sink<u32>>>>>;
+
+    // launchers whose sinks are kept for the test to settle, each registered as a pending call
+    fn kept_launchers(prefix: &str, count: usize) -> (Vec<Launcher<u32>>, Sinks, Vec<String>) {
+        let sinks: Sinks = Arc::new(Mutex::new((0..count).map(|_| None).collect()));
+        let uuids: Vec<String> = (0..count).map(|i| format!("{}-{}", prefix, i)).collect();
+        let launchers = uuids
+            .iter()
+            .enumerate()
+            .map(|(i, uuid)| {
+                let sinks = sinks.clone();
+                let uuid = uuid.clone();
+                Box::new(move |sink: Sink<u32>| {
+                    sinks.lock().unwrap()[i] = Some(sink);
+                    pending_registry().insert(uuid.clone(), "", "", Box::new(|_| Ok(())), None);
+                    Ok(PendingCall::new(uuid))
+                }) as Launcher<u32>
+            })
+            .collect();
+        (launchers, sinks, uuids)
+    }
+
+    fn settle(sinks: &Sinks, index: usize, result: anyhow::Result<u32>) {
+        let mut sink = sinks.lock().unwrap()[index].take().unwrap();
+        sink(result).unwrap();
+    }
+
+    fn forget(uuids: &[String]) {
+        let mut registry = pending_registry();
+        for uuid in uuids {
+            registry.remove(uuid);
+            registry.take_cancelled(uuid);
+        }
+    }
+
+    #[test]
+    fn join_all_keeps_launcher_order() {
+        let _lock = lock_pending_calls();
+        let _guard = transport::replace(ScriptedTransport::new());
+        let (launchers, sinks, uuids) = kept_launchers("fanout-join", 3);
+        let joined = Arc::new(Mutex::new(None));
+        let output = joined.clone();
+        join_all(launchers, move |results| {
+            *output.lock().unwrap() = Some(
+                results
+                    .into_iter()
+                    .map(|r| r.map_err(|e| e.to_string()))
+                    .collect::<Vec<_>>(),
+            );
+            Ok(())
+        })
+        .unwrap();
+
+        settle(&sinks, 2, Ok(2));
+        settle(&sinks, 0, Err(anyhow::anyhow!("lost")));
+        assert!(joined.lock().unwrap().is_none());
+        settle(&sinks, 1, Ok(1));
+        assert_eq!(
+            joined.lock().unwrap().take().unwrap(),
+            vec![Err("lost".to_string()), Ok(1), Ok(2)]
+        );
+        forget(&uuids);
+        assert!(join_all(Vec::<Launcher<u32>>::new(), |results| {
+            assert!(results.is_empty());
+            Ok(())
+        })
+        .is_ok());
+    }
+
+    #[test]
+    fn quorum_completes_early_and_cancels_the_rest() {
+        let _lock = lock_pending_calls();
+        let _guard = transport::replace(ScriptedTransport::new());
+        let (launchers, sinks, uuids) = kept_launchers("fanout-quorum", 4);
+        let reached = Arc::new(Mutex::new(None));
+        let output = reached.clone();
+        quorum(2, launchers, move |result| {
+            *output.lock().unwrap() = Some(result.unwrap());
+            Ok(())
+        })
+        .unwrap();
+
+        settle(&sinks, 3, Ok(30));
+        settle(&sinks, 0, Err(anyhow::anyhow!("lost")));
+        settle(&sinks, 1, Ok(10));
+        assert_eq!(
+            reached.lock().unwrap().take().unwrap(),
+            vec![(1, 10), (3, 30)]
+        );
+        // the call still pending is cancelled, its late outcome is ignored
+        assert!(!pending_registry().contains(&uuids[2]));
+        assert!(pending_registry().take_cancelled(&uuids[2]));
+        settle(&sinks, 2, Ok(20));
+        assert!(reached.lock().unwrap().is_none());
+
+        forget(&uuids);
+        assert!(quorum(3, Vec::<Launcher<u32>>::new(), |_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn select_first_takes_the_first_success() {
+        let _lock = lock_pending_calls();
+        let _guard = transport::replace(ScriptedTransport::new());
+        let (launchers, sinks, uuids) = kept_launchers("fanout-first", 2);
+        let selected = Arc::new(Mutex::new(None));
+        let output = selected.clone();
+        select_first(launchers, move |result| {
+            *output.lock().unwrap() = Some(result.unwrap());
+            Ok(())
+        })
+        .unwrap();
+        settle(&sinks, 1, Ok(7));
+        assert_eq!(selected.lock().unwrap().take(), Some((1, 7)));
+        assert!(pending_registry().take_cancelled(&uuids[0]));
+
+        forget(&uuids);
+
+        let (launchers, sinks, uuids) = kept_launchers("fanout-none", 2);
+        let failed = Arc::new(Mutex::new(None));
+        let output = failed.clone();
+        select_first(launchers, move |result| {
+            *output.lock().unwrap() = Some(result.is_err());
+            Ok(())
+        })
+        .unwrap();
+        settle(&sinks, 0, Err(anyhow::anyhow!("lost")));
+        assert!(failed.lock().unwrap().is_none());
+        settle(&sinks, 1, Err(anyhow::anyhow!("lost")));
+        assert_eq!(failed.lock().unwrap().take(), Some(true));
+        forget(&uuids);
+    }
+}
@@ -0,0 +1,270 @@
+//! Run side-effecting calls at most once per idempotency key.
+//!
+//! The outcome of the first successful execution under a key is recorded in kvp for
+//! `ttl_seconds`. A duplicate, e.g. a retry after a timeout or a redelivered request, gets the
+//! recorded outcome back instead of applying the effect again. Failures are not recorded, so
+//! they can be retried under the same key.
+//!
+//! While the effect runs the key is reserved, a duplicate arriving meanwhile fails with
+//! `Error::InProgress` instead of applying the effect a second time. The caller can retry it
+//! later to get the recorded outcome.
+//!
+//! An effect failing with `Error::Timeout` may still have been applied, e.g. a transfer whose
+//! reply was lost. Its reservation is kept for `ttl_seconds` instead of being released, so
+//! duplicates keep failing with `Error::InProgress` until someone checks what happened and
+//! calls `forget`.
+//!
+//! ```ignore
+//! let ctx = idempotency::mov(BINDING, &format!("mov-{}", txn_id), DEFAULT_TTL_SECONDS, req)?;
+//!
+//! let receipt: Vec<u8> = idempotency::once(BINDING, &request_id, DEFAULT_TTL_SECONDS, || {
+//!     //code snipet...
+//! })?;
+//! ```
+//!
+//! Keys are shared by every actor using the same binding, prefix them with the operation.
+
+use crate::actor_kvp;
+use crate::actor_layer1;
+use crate::actor_raft;
+use crate::actor_statemachine;
+use crate::error::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use vmh_codec::message::structs_proto::tokenstate::{MoveRequest, TopupRequest};
+
+/// Seconds an outcome is remembered by default.
+pub const DEFAULT_TTL_SECONDS: i32 = 24 * 60 * 60;
+/// Seconds after which the reservation of a key is dropped, in case the actor running its effect
+/// stopped before releasing it.
+pub const IN_FLIGHT_TTL_SECONDS: i32 = 5 * 60;
+
+const CAPABILITY: &str = "tea:keyvalue";
+
+fn record_key(key: &str) -> String {
+    format!("idempotency_{}", key)
+}
+
+fn in_flight_key(key: &str) -> String {
+    format!("idempotency_in_flight_{}", key)
+}
+
+/// Run `effect` unless an outcome is recorded under `key`, in which case that outcome is
+/// returned. Fails with `Error::InProgress` if another execution under `key` has not finished
+/// yet, or timed out.
+pub fn once<T, F>(
+    binding: &'static str,
+    key: &str,
+    ttl_seconds: i32,
+    effect: F,
+) -> anyhow::Result<T>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> anyhow::Result<T>,
+{
+    let record_key = record_key(key);
+    let in_flight_key = in_flight_key(key);
+    {
+        let _lock = actor_kvp::ShabbyLock::lock(binding, &record_key);
+        if let Some(outcome) = actor_kvp::get(binding, &record_key)? {
+            debug!("idempotency key {} already applied", key);
+            return Ok(outcome);
+        }
+        if actor_kvp::exists(binding, &in_flight_key)? {
+            return Err(Error::in_progress(CAPABILITY, "Reserve", key).into());
+        }
+        actor_kvp::set(binding, &in_flight_key, &true, IN_FLIGHT_TTL_SECONDS)?;
+    }
+    let outcome = effect();
+    match &outcome {
+        // the effect is applied, failing now would only make the caller apply it again
+        Ok(outcome) => {
+            if let Err(e) = actor_kvp::set(binding, &record_key, outcome, ttl_seconds) {
+                warn!("cannot record outcome of idempotency key {}: {}", key, e);
+            }
+        }
+        Err(e) if e.downcast_ref::<Error>().map(|e| e.is_timeout()) == Some(true) => {
+            warn!(
+                "outcome of idempotency key {} is unknown, keeping it reserved: {}",
+                key, e
+            );
+            if let Err(e) = actor_kvp::set(binding, &in_flight_key, &true, ttl_seconds) {
+                warn!("cannot keep idempotency key {} reserved: {}", key, e);
+            }
+            return outcome;
+        }
+        Err(_) => {}
+    }
+    if let Err(e) = actor_kvp::del(binding, &in_flight_key) {
+        warn!("cannot release idempotency key {}: {}", key, e);
+    }
+    outcome
+}
+
+/// Returns true if an outcome is recorded under `key`.
+pub fn is_applied(binding: &'static str, key: &str) -> anyhow::Result<bool> {
+    Ok(actor_kvp::exists(binding, &record_key(key))?)
+}
+
+/// Drop the outcome recorded under `key`, or its reservation, the next execution applies the
+/// effect again.
+pub fn forget(binding: &'static str, key: &str) -> anyhow::Result<()> {
+    actor_kvp::del(binding, &record_key(key))?;
+    actor_kvp::del(binding, &in_flight_key(key))?;
+    Ok(())
+}
+
+pub fn transfer_balance(
+    binding: &'static str,
+    key: &str,
+    ttl_seconds: i32,
+    source_seed: Vec<u8>,
+    to_address: &str,
+    amount: u128,
+) -> anyhow::Result<()> {
+    once(binding, key, ttl_seconds, || {
+        Ok(actor_layer1::transfer_balance(
+            source_seed,
+            to_address,
+            amount,
+        )?)
+    })
+}
+
+pub fn topup(
+    binding: &'static str,
+    key: &str,
+    ttl_seconds: i32,
+    req: TopupRequest,
+) -> anyhow::Result<Vec<u8>> {
+    once(binding, key, ttl_seconds, || {
+        Ok(actor_statemachine::topup(req)?)
+    })
+}
+
+pub fn mov(
+    binding: &'static str,
+    key: &str,
+    ttl_seconds: i32,
+    req: MoveRequest,
+) -> anyhow::Result<Vec<u8>> {
+    once(binding, key, ttl_seconds, || {
+        Ok(actor_statemachine::mov(req)?)
+    })
+}
+
+pub fn raft_set_value(
+    binding: &'static str,
+    key: &str,
+    ttl_seconds: i32,
+    raft_key: &str,
+    value: &[u8],
+    storage_index: u32,
+    uuid: &str,
+) -> anyhow::Result<()> {
+    once(binding, key, ttl_seconds, || {
+        Ok(actor_raft::raft_set_value(
+            raft_key,
+            value,
+            storage_index,
+            uuid,
+        )?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{forget, is_applied, once, IN_FLIGHT_TTL_SECONDS};
+    use crate::error::Error;
+    use crate::transport::{self, KvpSimulator};
+    use std::cell::Cell;
+
+    #[test]
+    fn duplicates_get_the_recorded_outcome() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        let runs = Cell::new(0);
+        let effect = || {
+            runs.set(runs.get() + 1);
+            Ok(vec![runs.get() as u8])
+        };
+
+        assert!(
+            once::<Vec<u8>, _>("default", "mov-1", 60, || Err(anyhow::anyhow!("busy"))).is_err()
+        );
+        assert!(!is_applied("default", "mov-1").unwrap());
+        assert_eq!(once("default", "mov-1", 60, effect).unwrap(), vec![1]);
+        assert_eq!(once("default", "mov-1", 60, effect).unwrap(), vec![1]);
+        assert_eq!(runs.get(), 1);
+
+        kvp.advance(61);
+        assert_eq!(once("default", "mov-1", 60, effect).unwrap(), vec![2]);
+        forget("default", "mov-1").unwrap();
+        assert_eq!(once("default", "mov-1", 60, effect).unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn duplicates_fail_while_the_effect_runs() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+
+        let outcome = once("default", "mov-2", 60, || {
+            let duplicate = once("default", "mov-2", 60, || Ok(vec![2u8])).unwrap_err();
+            assert_eq!(
+                duplicate.downcast_ref::<Error>(),
+                Some(&Error::in_progress("tea:keyvalue", "Reserve", "mov-2"))
+            );
+            Ok(vec![1u8])
+        });
+        assert_eq!(outcome.unwrap(), vec![1]);
+        assert_eq!(
+            once("default", "mov-2", 60, || Ok(vec![3u8])).unwrap(),
+            vec![1]
+        );
+
+        // a failed effect releases the key as well
+        let failed = once::<Vec<u8>, _>("default", "mov-3", 60, || Err(anyhow::anyhow!("busy")));
+        assert!(failed.unwrap_err().downcast_ref::<Error>().is_none());
+        assert_eq!(
+            once("default", "mov-3", 60, || Ok(vec![4u8])).unwrap(),
+            vec![4]
+        );
+        assert!(kvp.keys("default").iter().all(|k| !k.contains("in_flight")));
+
+        // a reservation left behind by a stopped actor expires
+        let _ = once::<Vec<u8>, _>("default", "mov-4", 60, || {
+            kvp.advance(IN_FLIGHT_TTL_SECONDS as u64 + 1);
+            assert_eq!(
+                once("default", "mov-4", 60, || Ok(vec![5u8])).unwrap(),
+                vec![5]
+            );
+            Err(anyhow::anyhow!("stopped"))
+        });
+    }
+
+    #[test]
+    fn timed_out_effects_stay_reserved() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        let runs = Cell::new(0);
+        let transfer = || -> anyhow::Result<()> {
+            runs.set(runs.get() + 1);
+            Err(Error::timeout("tea:layer1", "TransferBalance", "uuid-1").into())
+        };
+
+        let timed_out = once("default", "transfer-1", 600, transfer).unwrap_err();
+        assert!(timed_out.downcast_ref::<Error>().unwrap().is_timeout());
+        kvp.advance(IN_FLIGHT_TTL_SECONDS as u64 + 1);
+        let duplicate = once("default", "transfer-1", 600, transfer).unwrap_err();
+        assert!(matches!(
+            duplicate.downcast_ref::<Error>(),
+            Some(Error::InProgress { .. })
+        ));
+        assert_eq!(runs.get(), 1);
+
+        // once checked, forgetting the key lets the effect run again
+        forget("default", "transfer-1").unwrap();
+        assert!(once("default", "transfer-1", 600, || Ok(())).is_ok());
+        assert!(is_applied("default", "transfer-1").unwrap());
+    }
+}
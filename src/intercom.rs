@@ -0,0 +1,333 @@
+//! Typed request/response services over intercom.
+//!
+//! `intercom_service!` declares the methods of an actor once and generates a module with the
+//! `Service` trait the serving actor implements, its `dispatch` function and a typed `Client`:
+//!
+//! ```ignore
+//! intercom_service! {
+//!     /// Answered by the pinner actor.
+//!     pub mod pinner_service for "pinner" at "actor.pinner.intercom" {
+//!         fn is_node_ready(()) -> bool;
+//!         fn get_key1(String) -> Option<Vec<u8>>;
+//!     }
+//! }
+//!
+//! // calling actor, replies are routed to `action::result_handler` as for any intercom call
+//! pinner_service::Client::new("me").get_key1(&deployment_id, |key1| {
+//!     //code snipet...
+//!     Ok(())
+//! })?;
+//!
+//! // serving actor, for each incoming intercom message
+//! if !pinner_service::dispatch(&Pinner, &msg)? {
+//!     // not a pinner_service subject
+//! }
+//! ```
+//!
+//! Method `m` is requested on subject `{prefix}.m`. Requests and responses are encoded with
+//! `tea_codec::serialize`, a response carries either the value or the error message of the
+//! serving actor, which the client receives as `Error::Provider`.
+//!
+//! `pinner_service` above only illustrates the syntax: the pinner actor of today answers the
+//! requests of `actor_pinner` on its own subjects with the bare value, not with this
+//! `Result<Resp, String>`, so both ends of a service must be built from the same declaration.
+
+pub mod broadcast;
+pub mod envelope;
+
+use crate::action::{self, CallOptions, PendingCall};
+use crate::error::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+pub const CAPABILITY: &str = "tea:intercom";
+
+/// Method name of `subject` if it is `{prefix}.{method}`.
+pub fn method_of<'a>(prefix: &str, subject: &'a str) -> Option<&'a str> {
+    let method = subject.strip_prefix(prefix)?.strip_prefix('.')?;
+    if method.is_empty() || method.contains('.') {
+        None
+    } else {
+        Some(method)
+    }
+}
+
+/// Send `req` on `subject` to `actor` and hand the decoded response to `callback`.
+pub fn call<Req, Resp, F>(
+    actor: &str,
+    reply_actor: &str,
+    subject: &str,
+    req: &Req,
+    options: CallOptions,
+    mut callback: F,
+) -> anyhow::Result<PendingCall>
+where
+    Req: Serialize,
+    Resp: DeserializeOwned,
+    F: FnMut(anyhow::Result<Resp>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let body =
+        tea_codec::serialize(req).map_err(|e| Error::invalid_input(CAPABILITY, subject, e))?;
+    let operation = subject.to_string();
+    action::call_async_intercom_ex(
+        actor,
+        reply_actor,
+        BrokerMessage {
+            subject: subject.to_string(),
+            reply_to: "".into(),
+            body,
+        },
+        options,
+        move |reply| callback(reply.and_then(|msg| decode_response(&operation, &msg.body))),
+    )
+}
+
+fn decode_response<Resp: DeserializeOwned>(operation: &str, body: &[u8]) -> anyhow::Result<Resp> {
+    let response: Result<Resp, String> =
+        tea_codec::deserialize(body).map_err(|e| Error::decode(CAPABILITY, operation, e))?;
+    Ok(response.map_err(|e| Error::provider(CAPABILITY, operation, e))?)
+}
+
+/// Decode the request in `msg`, run `handler` and answer with `action::reply_intercom`. A
+/// message without `reply_to` is handled without answer. Requests sent by `handler` continue the
+/// intercom chain of `msg`.
+pub fn serve<Req, Resp, H>(msg: &BrokerMessage, handler: H) -> anyhow::Result<()>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    H: FnOnce(Req) -> anyhow::Result<Resp>,
+{
+    let _scope = envelope::accept(msg);
+    let outcome = tea_codec::deserialize(msg.body.as_slice())
+        .map_err(|e| Error::decode(CAPABILITY, &msg.subject, e).into())
+        .and_then(handler);
+    if msg.reply_to.is_empty() {
+        return outcome.map(|_| ());
+    }
+    if let Err(e) = &outcome {
+        warn!("intercom request {} failed: {}", msg.subject, e);
+    }
+    let response: Result<Resp, String> = outcome.map_err(|e| format!("{:#}", e));
+    let body = tea_codec::serialize(&response)
+        .map_err(|e| Error::invalid_input(CAPABILITY, &msg.subject, e))?;
+    action::reply_intercom(&msg.reply_to, body)
+}
+
+#[macro_export]
+macro_rules! intercom_service {
+    (
+        $(#[$meta:meta])*
+        $vis:vis mod $name:ident for $actor:literal at $prefix:literal {
+            $(fn $method:ident($req:ty) -> $resp:ty;)*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis mod $name {
+            #[allow(unused_imports)]
+            use super::*;
+
+            pub const ACTOR_NAME: &str = $actor;
+            pub const SUBJECT_PREFIX: &str = $prefix;
+
+            pub trait Service {
+                $(fn $method(&self, req: $req) -> ::anyhow::Result<$resp>;)*
+            }
+
+            /// Subject on which `method` is requested.
+            pub fn subject(method: &str) -> String {
+                format!("{}.{}", SUBJECT_PREFIX, method)
+            }
+
+            /// Answer `msg` if its subject belongs to this service, returns false otherwise.
+            pub fn dispatch<S: Service>(
+                service: &S,
+                msg: &$crate::wascc_actor::prelude::codec::messaging::BrokerMessage,
+            ) -> ::anyhow::Result<bool> {
+                match $crate::intercom::method_of(SUBJECT_PREFIX, &msg.subject) {
+                    $(Some(stringify!($method)) => {
+                        $crate::intercom::serve(msg, |req: $req| service.$method(req))?;
+                        Ok(true)
+                    })*
+                    _ => Ok(false),
+                }
+            }
+
+            /// Calls the service on behalf of `reply_actor`.
+            #[derive(Debug, Clone, PartialEq)]
+            pub struct Client {
+                pub reply_actor: String,
+                pub options: $crate::action::CallOptions,
+            }
+
+            impl Client {
+                pub fn new(reply_actor: &str) -> Self {
+                    Client {
+                        reply_actor: reply_actor.to_string(),
+                        options: $crate::action::CallOptions::default(),
+                    }
+                }
+
+                pub fn with_options(mut self, options: $crate::action::CallOptions) -> Self {
+                    self.options = options;
+                    self
+                }
+
+                $(
+                pub fn $method<F>(
+                    &self,
+                    req: &$req,
+                    callback: F,
+                ) -> ::anyhow::Result<$crate::action::PendingCall>
+                where
+                    F: FnMut(::anyhow::Result<$resp>) -> ::anyhow::Result<()>
+                        + Sync
+                        + Send
+                        + 'static,
+                {
+                    $crate::intercom::call(
+                        ACTOR_NAME,
+                        &self.reply_actor,
+                        &subject(stringify!($method)),
+                        req,
+                        self.options.clone(),
+                        callback,
+                    )
+                }
+                )*
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_response;
+    use crate::action::{lock_pending_calls, result_handler, CallOptions};
+    use crate::error::Error;
+    use crate::transport::{self, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::deserialize;
+
+    intercom_service! {
+        mod echo for "echo" at "actor.echo.intercom" {
+            fn shout(String) -> String;
+            fn add((u32, u32)) -> u32;
+        }
+    }
+
+    struct Echo;
+
+    impl echo::Service for Echo {
+        fn shout(&self, req: String) -> anyhow::Result<String> {
+            Ok(req.to_uppercase())
+        }
+
+        fn add(&self, (a, b): (u32, u32)) -> anyhow::Result<u32> {
+            a.checked_add(b).ok_or_else(|| anyhow::anyhow!("overflow"))
+        }
+    }
+
+    // serve a request for `method` and return the body of the reply sent to the caller
+    fn serve<Req: serde::Serialize>(method: &str, req: Req) -> Vec<u8> {
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, vec![]);
+        let _guard = transport::replace(scripted.clone());
+        let request = BrokerMessage {
+            subject: echo::subject(method),
+            reply_to: "reply.me.me-intercom-1".into(),
+            body: tea_codec::serialize(req).unwrap(),
+        };
+        assert!(echo::dispatch(&Echo, &request).unwrap());
+        let reply: BrokerMessage = deserialize(&scripted.calls()[0].payload).unwrap();
+        assert_eq!(reply.subject, "reply.me.me-intercom-1");
+        reply.body
+    }
+
+    #[test]
+    fn dispatcher_answers_typed_requests() {
+        let body = serve("shout", "hi".to_string());
+        assert_eq!(decode_response::<String>("shout", &body).unwrap(), "HI");
+
+        let body = serve("add", (u32::MAX, 1u32));
+        let error = decode_response::<u32>("actor.echo.intercom.add", &body).unwrap_err();
+        match error.downcast::<Error>().unwrap() {
+            Error::Provider {
+                operation, message, ..
+            } => {
+                assert_eq!(operation, "actor.echo.intercom.add");
+                assert_eq!(message, "overflow");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        let unknown = BrokerMessage {
+            subject: "actor.other.intercom.add".into(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        assert!(!echo::dispatch(&Echo, &unknown).unwrap());
+    }
+
+    #[test]
+    fn client_receives_typed_responses() {
+        let _lock = lock_pending_calls();
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, vec![]);
+        scripted.respond("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, vec![]);
+        let _guard = transport::replace(scripted.clone());
+        let results = Arc::new(Mutex::new(Vec::new()));
+
+        let client = echo::Client::new("me").with_options(CallOptions::default());
+        let sink = results.clone();
+        client
+            .shout(&"hi".to_string(), move |res: anyhow::Result<String>| {
+                sink.lock().unwrap().push(res.map_err(|e| e.to_string()));
+                Ok(())
+            })
+            .unwrap();
+        let sink = results.clone();
+        client
+            .add(&(u32::MAX, 1), move |res: anyhow::Result<u32>| {
+                let res = res.map(|sum| sum.to_string());
+                sink.lock().unwrap().push(res.map_err(|e| e.to_string()));
+                Ok(())
+            })
+            .unwrap();
+
+        let requests: Vec<BrokerMessage> = scripted
+            .calls()
+            .iter()
+            .filter(|call| call.capid == "tea:intercom")
+            .map(|call| deserialize(&call.payload).unwrap())
+            .collect();
+        assert_eq!(requests[0].subject, "request.echo");
+        let sent: BrokerMessage = deserialize(&requests[0].body).unwrap();
+        assert_eq!(sent.subject, echo::subject("shout"));
+        let req: String = tea_codec::deserialize(&sent.body).unwrap();
+        assert_eq!(req, "hi");
+
+        let answers = vec![
+            tea_codec::serialize(Ok::<_, String>("HI".to_string())).unwrap(),
+            tea_codec::serialize(Err::<u32, _>("overflow".to_string())).unwrap(),
+        ];
+        for (request, body) in requests.iter().zip(answers) {
+            let reply = BrokerMessage {
+                subject: request.reply_to.clone(),
+                reply_to: "".into(),
+                body,
+            };
+            let token = request.reply_to.rsplit('.').next().unwrap();
+            result_handler(&reply, token).unwrap();
+        }
+        assert_eq!(
+            *results.lock().unwrap(),
+            vec![
+                Ok("HI".to_string()),
+                Err("tea:intercom actor.echo.intercom.add failed: overflow".to_string())
+            ]
+        );
+        assert_eq!(scripted.unanswered(), 0);
+    }
+}
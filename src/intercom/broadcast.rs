@@ -0,0 +1,241 @@
+//! Send one message to several actors.
+//!
+//! Targets are either a list of actor names or a role. Actors join a role with `join_role`,
+//! members are kept in a kvp set so that every actor of the node sees the same roles.
+//!
+//! ```ignore
+//! broadcast::join_role(BINDING, "health", "pinner")?;
+//!
+//! broadcast::gather_role(BINDING, "health", "me", msg, CallOptions::default(), |replies| {
+//!     for reply in replies {
+//!         match reply.result {
+//!             Ok(msg) => { /* code snipet... */ }
+//!             Err(e) => warn!("{} did not answer: {}", reply.actor, e),
+//!         }
+//!     }
+//!     Ok(())
+//! })?;
+//! ```
+
+use crate::action::{self, CallOptions};
+use crate::actor_kvp;
+use crate::error;
+use crate::fanout::{self, Launcher, Sink};
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+/// Outcome of the request sent to one actor.
+#[derive(Debug)]
+pub struct ActorReply {
+    pub actor: String,
+    /// The reply, or why there is none, e.g. `Error::Timeout` once `options.timeout_seconds`
+    /// passed for this actor.
+    pub result: anyhow::Result<BrokerMessage>,
+}
+
+fn role_key(role: &str) -> String {
+    format!("intercom_role_{}", role)
+}
+
+pub fn join_role(binding: &'static str, role: &str, actor: &str) -> error::Result<()> {
+    actor_kvp::set_add(binding, &role_key(role), &actor.to_string())?;
+    Ok(())
+}
+
+pub fn leave_role(binding: &'static str, role: &str, actor: &str) -> error::Result<()> {
+    actor_kvp::set_remove(binding, &role_key(role), &actor.to_string())?;
+    Ok(())
+}
+
+/// Actors registered under `role`, sorted by name.
+pub fn role_members(binding: &'static str, role: &str) -> error::Result<Vec<String>> {
+    let mut members: Vec<String> = actor_kvp::set_query(binding, &role_key(role))?;
+    members.sort();
+    Ok(members)
+}
+
+/// `action::post_intercom` to every actor of `actors`, returning the send result of each.
+pub fn broadcast(actors: &[String], msg: &BrokerMessage) -> Vec<(String, anyhow::Result<()>)> {
+    actors
+        .iter()
+        .map(|actor| {
+            let result = action::post_intercom(actor, msg).map(|_| ());
+            if let Err(e) = &result {
+                warn!("broadcast to {} failed: {}", actor, e);
+            }
+            (actor.clone(), result)
+        })
+        .collect()
+}
+
+pub fn broadcast_role(
+    binding: &'static str,
+    role: &str,
+    msg: &BrokerMessage,
+) -> anyhow::Result<Vec<(String, anyhow::Result<()>)>> {
+    Ok(broadcast(&role_members(binding, role)?, msg))
+}
+
+/// Request `msg` from every actor of `actors` and continue once each of them has answered,
+/// failed or timed out, with one `ActorReply` per actor in the order of `actors`.
+pub fn gather<F>(
+    actors: &[String],
+    reply_actor: &str,
+    msg: BrokerMessage,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(Vec<ActorReply>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let launchers: Vec<Launcher<BrokerMessage>> = actors
+        .iter()
+        .map(|actor| {
+            let actor = actor.clone();
+            let reply_actor = reply_actor.to_string();
+            let msg = msg.clone();
+            let options = options.clone();
+            Box::new(move |mut sink: Sink<BrokerMessage>| {
+                action::call_async_intercom_ex(&actor, &reply_actor, msg, options, move |r| {
+                    sink(r.cloned())
+                })
+            }) as Launcher<BrokerMessage>
+        })
+        .collect();
+    let actors = actors.to_vec();
+    fanout::join_all(launchers, move |results| {
+        callback(
+            actors
+                .into_iter()
+                .zip(results)
+                .map(|(actor, result)| ActorReply { actor, result })
+                .collect(),
+        )
+    })
+}
+
+pub fn gather_role<F>(
+    binding: &'static str,
+    role: &str,
+    reply_actor: &str,
+    msg: BrokerMessage,
+    options: CallOptions,
+    callback: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(Vec<ActorReply>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    gather(
+        &role_members(binding, role)?,
+        reply_actor,
+        msg,
+        options,
+        callback,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{broadcast, gather_role, join_role, leave_role, role_members};
+    use crate::action::{self, lock_pending_calls, CallOptions};
+    use crate::error::Error;
+    use crate::transport::{self, KvpSimulator, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::deserialize;
+
+    #[test]
+    fn roles_and_broadcast() {
+        {
+            let kvp = KvpSimulator::new();
+            let _guard = transport::replace(kvp);
+            join_role("default", "health", "pinner").unwrap();
+            join_role("default", "health", "delegate").unwrap();
+            join_role("default", "config", "pinner").unwrap();
+            leave_role("default", "config", "pinner").unwrap();
+            assert_eq!(
+                role_members("default", "health").unwrap(),
+                vec!["delegate", "pinner"]
+            );
+            assert!(role_members("default", "config").unwrap().is_empty());
+        }
+
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, vec![]);
+        scripted.fail("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, "no route");
+        let _guard = transport::replace(scripted);
+        let msg = BrokerMessage {
+            subject: "actor.config.push".into(),
+            reply_to: "".into(),
+            body: vec![1],
+        };
+        let results = broadcast(&["pinner".to_string(), "delegate".to_string()], &msg);
+        assert_eq!(results[0].0, "pinner");
+        assert!(results[0].1.is_ok());
+        assert_eq!(results[1].0, "delegate");
+        assert!(results[1].1.is_err());
+    }
+
+    #[test]
+    fn gather_waits_for_replies_and_timeouts() {
+        let _lock = lock_pending_calls();
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp.clone());
+        scripted.respond("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, vec![]);
+        scripted.respond("tea:intercom", tea_codec::OP_INTERCOM_MESSAGE, vec![]);
+        let _guard = transport::replace(scripted.clone());
+        join_role("default", "health", "pinner").unwrap();
+        join_role("default", "health", "delegate").unwrap();
+
+        let gathered = Arc::new(Mutex::new(Vec::new()));
+        let sink = gathered.clone();
+        let options = CallOptions {
+            timeout_seconds: 10,
+            ..CallOptions::default()
+        };
+        let msg = BrokerMessage {
+            subject: "actor.health.check".into(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        gather_role("default", "health", "me", msg, options, move |replies| {
+            let mut sink = sink.lock().unwrap();
+            for reply in replies {
+                let result = reply
+                    .result
+                    .map(|msg| msg.body)
+                    .map_err(|e| matches!(e.downcast_ref::<Error>(), Some(Error::Timeout { .. })));
+                sink.push((reply.actor, result));
+            }
+            Ok(())
+        })
+        .unwrap();
+
+        let requests: Vec<BrokerMessage> = scripted
+            .calls()
+            .iter()
+            .filter(|call| call.capid == "tea:intercom")
+            .map(|call| deserialize(&call.payload).unwrap())
+            .collect();
+        assert_eq!(requests[1].subject, "request.pinner");
+        let reply_to = &requests[1].reply_to;
+        let reply = BrokerMessage {
+            subject: reply_to.clone(),
+            reply_to: "".into(),
+            body: b"ok".to_vec(),
+        };
+        action::result_handler(&reply, reply_to.rsplit('.').next().unwrap()).unwrap();
+        assert!(gathered.lock().unwrap().is_empty());
+
+        kvp.advance(11);
+        assert_eq!(action::sweep_expired().unwrap(), 1);
+        assert_eq!(
+            *gathered.lock().unwrap(),
+            vec![
+                ("delegate".to_string(), Err(true)),
+                ("pinner".to_string(), Ok(b"ok".to_vec())),
+            ]
+        );
+        assert_eq!(action::outstanding(), 0);
+    }
+}
@@ -0,0 +1,268 @@
+//! Hop counter and deadline carried by intercom requests.
+//!
+//! `action::request_intercom` appends an envelope to the last token of the `reply_to` subject,
+//! e.g. `reply.a.a-intercom-l3~h2~d1600000000000`, so the body and subject of the message stay
+//! as they were. The serving actor reads it with `accept`, which also makes it the context of
+//! the requests sent while handling the message: each of them counts one more hop and keeps the
+//! earliest deadline. A request beyond `max_hops()` or past its deadline is refused, so chains
+//! like A→B→C→A end instead of bouncing forever.
+//!
+//! Replies go back to the `reply_to` subject unchanged and `action::result_handler` strips the
+//! envelope with `reply_uuid` before looking up the pending call. An actor built before
+//! envelopes answers in the same way, and a request from such an actor, or a post, which has no
+//! `reply_to`, starts a new chain.
+
+use crate::actor_env;
+use crate::error::{self, Error};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::UNIX_EPOCH;
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+pub const DEFAULT_MAX_HOPS: u32 = 8;
+
+const HOPS_MARK: &str = "~h";
+const DEADLINE_MARK: &str = "~d";
+
+static MAX_HOPS: AtomicU32 = AtomicU32::new(DEFAULT_MAX_HOPS);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope {
+    /// Number of intercom requests in the chain so far, 1 for the first one.
+    pub hops: u32,
+    /// Milliseconds since the epoch after which the chain must not be continued.
+    pub deadline_ms: Option<u64>,
+}
+
+impl Envelope {
+    /// Reply token of a request sent with this envelope and correlated by `uuid`.
+    pub fn reply_token(&self, uuid: &str) -> String {
+        match self.deadline_ms {
+            Some(deadline) => format!(
+                "{}{}{}{}{}",
+                uuid, HOPS_MARK, self.hops, DEADLINE_MARK, deadline
+            ),
+            None => format!("{}{}{}", uuid, HOPS_MARK, self.hops),
+        }
+    }
+
+    /// Split a reply token into the envelope and the uuid of the call, a token without envelope
+    /// is returned as it is.
+    pub fn parse(token: &str) -> (Option<Envelope>, &str) {
+        let (rest, deadline_ms) = match split_number(token, DEADLINE_MARK) {
+            Some((rest, deadline)) => (rest, Some(deadline)),
+            None => (token, None),
+        };
+        match split_number(rest, HOPS_MARK) {
+            Some((uuid, hops)) if hops <= u64::from(u32::MAX) => (
+                Some(Envelope {
+                    hops: hops as u32,
+                    deadline_ms,
+                }),
+                uuid,
+            ),
+            _ => (None, token),
+        }
+    }
+}
+
+fn split_number<'a>(token: &'a str, mark: &str) -> Option<(&'a str, u64)> {
+    let at = token.rfind(mark)?;
+    let number = &token[at + mark.len()..];
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((&token[..at], number.parse().ok()?))
+}
+
+/// Uuid of the call a reply token belongs to, see `Envelope::parse`.
+pub fn reply_uuid(token: &str) -> &str {
+    Envelope::parse(token).1
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<Envelope>> = const { Cell::new(None) };
+}
+
+pub fn max_hops() -> u32 {
+    MAX_HOPS.load(Ordering::Relaxed)
+}
+
+pub fn set_max_hops(hops: u32) {
+    MAX_HOPS.store(hops, Ordering::Relaxed);
+}
+
+/// Envelope of the intercom message being handled on this thread, if any.
+pub fn current() -> Option<Envelope> {
+    CURRENT.with(|current| current.get())
+}
+
+/// Read the envelope from the `reply_to` subject of an incoming intercom message and make it
+/// the context of the requests sent until the returned scope is dropped.
+pub fn accept(msg: &BrokerMessage) -> IntercomScope {
+    let token = msg.reply_to.rsplit('.').next().unwrap_or_default();
+    enter(Envelope::parse(token).0)
+}
+
+/// Make `envelope` the context of the requests sent until the returned scope is dropped.
+pub fn enter(envelope: Option<Envelope>) -> IntercomScope {
+    let previous = CURRENT.with(|current| current.replace(envelope));
+    IntercomScope { previous }
+}
+
+/// Restores the context in place before `accept` or `enter` when dropped.
+pub struct IntercomScope {
+    previous: Option<Envelope>,
+}
+
+impl Drop for IntercomScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| current.set(previous));
+    }
+}
+
+fn now_ms() -> Option<u64> {
+    let now = actor_env::get_system_time().ok()?;
+    Some(now.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+/// Envelope for a request sent now that waits at most `timeout_seconds` for its reply, or the
+/// reason why the chain must stop here.
+pub fn outgoing(operation: &str, timeout_seconds: u64) -> error::Result<Envelope> {
+    let current = current();
+    let hops = current.map(|c| c.hops).unwrap_or(0) + 1;
+    if hops > max_hops() {
+        return Err(Error::hop_limit(super::CAPABILITY, operation, max_hops()));
+    }
+    let inherited = current.and_then(|c| c.deadline_ms);
+    let now = now_ms();
+    if let (Some(deadline), Some(now)) = (inherited, now) {
+        if now > deadline {
+            return Err(Error::timeout(
+                super::CAPABILITY,
+                operation,
+                &format!("deadline {}", deadline),
+            ));
+        }
+    }
+    let own = now.map(|now| now.saturating_add(timeout_seconds.saturating_mul(1000)));
+    let deadline_ms = match (inherited, own) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    };
+    Ok(Envelope { hops, deadline_ms })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, current, outgoing, reply_uuid, Envelope};
+    use crate::error::Error;
+    use crate::transport::{self, ScriptedTransport};
+    use std::time::{Duration, UNIX_EPOCH};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::serialize;
+
+    fn clock_at(scripted: &ScriptedTransport, ms: u64) {
+        let now = UNIX_EPOCH + Duration::from_millis(ms);
+        scripted.respond("tea:env", "GetSystemTime", serialize(now).unwrap());
+    }
+
+    fn request(envelope: Envelope) -> BrokerMessage {
+        BrokerMessage {
+            subject: "actor.b.intercom.op".into(),
+            reply_to: format!("reply.a.{}", envelope.reply_token("a-intercom-1")),
+            body: b"body".to_vec(),
+        }
+    }
+
+    #[test]
+    fn reply_tokens_carry_the_envelope() {
+        let envelope = Envelope {
+            hops: 3,
+            deadline_ms: Some(5_000),
+        };
+        assert_eq!(
+            envelope.reply_token("a-intercom-1"),
+            "a-intercom-1~h3~d5000"
+        );
+        assert_eq!(
+            Envelope::parse("a-intercom-1~h3~d5000"),
+            (Some(envelope), "a-intercom-1")
+        );
+        let no_deadline = Envelope {
+            hops: 2,
+            deadline_ms: None,
+        };
+        assert_eq!(
+            Envelope::parse(&no_deadline.reply_token("a-intercom-1")),
+            (Some(no_deadline), "a-intercom-1")
+        );
+
+        for plain in &["a-intercom-1", "a~d5000", "a~h", "a~hx~d1", "a~h1~d"] {
+            assert_eq!(Envelope::parse(plain), (None, *plain));
+            assert_eq!(reply_uuid(plain), *plain);
+        }
+        assert_eq!(reply_uuid("a-intercom-1~h3~d5000"), "a-intercom-1");
+    }
+
+    #[test]
+    fn requests_inherit_hops_and_deadline() {
+        let scripted = ScriptedTransport::new();
+        let _guard = transport::replace(scripted.clone());
+
+        clock_at(&scripted, 1_000);
+        let first = outgoing("op", 10).unwrap();
+        assert_eq!(
+            first,
+            Envelope {
+                hops: 1,
+                deadline_ms: Some(11_000)
+            }
+        );
+        clock_at(&scripted, 1_000);
+        assert_eq!(
+            outgoing("op", u64::MAX).unwrap().deadline_ms,
+            Some(u64::MAX)
+        );
+
+        let msg = request(Envelope {
+            hops: 7,
+            deadline_ms: Some(5_000),
+        });
+        {
+            let _scope = accept(&msg);
+            assert_eq!(msg.body, b"body".to_vec());
+            clock_at(&scripted, 2_000);
+            assert_eq!(
+                outgoing("op", 60).unwrap(),
+                Envelope {
+                    hops: 8,
+                    deadline_ms: Some(5_000)
+                }
+            );
+            clock_at(&scripted, 6_000);
+            assert!(matches!(outgoing("op", 60), Err(Error::Timeout { .. })));
+        }
+        assert_eq!(current(), None);
+
+        let legacy = BrokerMessage {
+            reply_to: "reply.a.a-intercom-1".into(),
+            ..msg
+        };
+        {
+            let _scope = accept(&legacy);
+            assert_eq!(current(), None);
+        }
+
+        let msg = request(Envelope {
+            hops: 8,
+            deadline_ms: None,
+        });
+        let _scope = accept(&msg);
+        assert!(matches!(
+            outgoing("op", 60),
+            Err(Error::HopLimit { max_hops: 8, .. })
+        ));
+    }
+}
@@ -1,11 +1,15 @@
+use crate::error::{self, Error};
 use serde::{Deserialize, Serialize};
 use vmh_codec::message::structs_proto::p2p::GeneralMsg;
 use vmh_codec::message::{
     encode_protobuf,
     structs_proto::{p2p, rpc},
 };
+use vmh_codec::ADAPTER_RPC_CHANNEL_NAME;
 
 const PREFIX_P2P_REPLY: &str = "ipfs.p2p.reply";
+/// Reply subject of `send_message` in `router::SubjectRouter` pattern syntax.
+pub const SUBJECT_P2P_REPLY: &str = "ipfs.p2p.reply.{uuid}";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum P2pReplyType {
@@ -15,7 +19,7 @@ pub enum P2pReplyType {
     Error(String),
 }
 
-pub fn send_message(peer_id: &str, uuid: &str, msg: GeneralMsg) -> anyhow::Result<()> {
+pub fn send_message(peer_id: &str, uuid: &str, msg: GeneralMsg) -> error::Result<()> {
     let peer_id = peer_id.to_string();
     let reply = format!("{}.{}", PREFIX_P2P_REPLY, uuid);
     let _ = super::actor_rpc::call_adapter_rpc(
@@ -41,7 +45,7 @@ pub fn async_pull_cid_data(
     payload: &[u8],
     pin: bool,
     reply_to: &str,
-) -> anyhow::Result<()> {
+) -> error::Result<()> {
     let peer_id = peer_id.to_string();
     let payload = payload.to_vec();
     let reply = reply_to.to_string();
@@ -64,7 +68,7 @@ pub fn async_pull_cid_data(
     Ok(())
 }
 
-pub fn close_p2p(peer_id: &str, uuid: String) -> anyhow::Result<()> {
+pub fn close_p2p(peer_id: &str, uuid: String) -> error::Result<()> {
     let peer_id = peer_id.to_string();
     let _ = super::actor_rpc::call_adapter_rpc(
         rpc::AdapterClientRequest {
@@ -82,7 +86,7 @@ pub fn response_ipfs_p2p_with_error(
     peer_id: &str,
     uuid: &str,
     error: &str,
-) -> anyhow::Result<Vec<u8>> {
+) -> error::Result<Vec<u8>> {
     info!(
         "ipfs_p2p.rs peer_id:{}, uuid:{}, error:{}",
         peer_id, uuid, error
@@ -105,7 +109,7 @@ pub fn response_ipfs_p2p(
     uuid: &str,
     content: &str,
     ty: P2pReplyType,
-) -> anyhow::Result<Vec<u8>> {
+) -> error::Result<Vec<u8>> {
     let mut msg = p2p::P2pReplyMessage {
         peer_id: peer_id.to_string(),
         uuid: uuid.to_string(),
@@ -131,10 +135,11 @@ fn set_reply_message_type(msg: &mut p2p::P2pReplyMessage, ty: P2pReplyType) {
 
 pub fn response_ipfs_p2p_reply_with_subject(
     p2p_reply_message: p2p::P2pReplyMessage,
-) -> anyhow::Result<Vec<u8>> {
+) -> error::Result<Vec<u8>> {
     encode_protobuf(rpc::IpfsInboundP2pForwardResponse {
         p2p_reply_message: Some(p2p_reply_message),
     })
+    .map_err(|e| Error::invalid_input(ADAPTER_RPC_CHANNEL_NAME, "IpfsInboundP2pForward", e))
 }
 
 pub fn log_and_response(
@@ -142,7 +147,7 @@ pub fn log_and_response(
     uuid: &str,
     content: &str,
     ty: P2pReplyType,
-) -> anyhow::Result<Vec<u8>> {
+) -> error::Result<Vec<u8>> {
     match &ty {
         P2pReplyType::Success => {
             // if success do not print log locally
@@ -167,7 +172,7 @@ pub fn log_and_response_with_error(
     peer_id: &str,
     uuid: &str,
     error: &str,
-) -> anyhow::Result<Vec<u8>> {
+) -> error::Result<Vec<u8>> {
     error!("{}", error);
     response_ipfs_p2p_with_error(peer_id, uuid, error)
 }
@@ -6,15 +6,34 @@ use vmh_codec::message::structs_proto::ra;
 pub fn lookup_node_profile<F>(
     ephemeral_id: &[u8],
     reply_to: &str,
-    callback: F,
-) -> anyhow::Result<()>
+    mut callback: F,
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(&ra::NodeProfile) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    lookup_node_profile_ex(
+        ephemeral_id,
+        reply_to,
+        action::CallOptions::default(),
+        move |profile| callback(&profile?),
+    )
+}
+
+/// Like `lookup_node_profile`, but failures such as a timeout are handed to the callback too.
+pub fn lookup_node_profile_ex<F>(
+    ephemeral_id: &[u8],
+    reply_to: &str,
+    options: action::CallOptions,
+    callback: F,
+) -> anyhow::Result<action::PendingCall>
+where
+    F: FnMut(anyhow::Result<ra::NodeProfile>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
     lookup_node_profile_operation(
         ephemeral_id,
         "layer1.async.reply.lookup_node_profile",
         reply_to,
+        options,
         callback,
     )
 }
@@ -22,15 +41,33 @@ where
 pub fn lookup_node_profile_by_tea_id<F>(
     tea_id: &[u8],
     reply_to: &str,
-    callback: F,
-) -> anyhow::Result<()>
+    mut callback: F,
+) -> anyhow::Result<action::PendingCall>
 where
     F: FnMut(&ra::NodeProfile) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    lookup_node_profile_by_tea_id_ex(
+        tea_id,
+        reply_to,
+        action::CallOptions::default(),
+        move |profile| callback(&profile?),
+    )
+}
+
+pub fn lookup_node_profile_by_tea_id_ex<F>(
+    tea_id: &[u8],
+    reply_to: &str,
+    options: action::CallOptions,
+    callback: F,
+) -> anyhow::Result<action::PendingCall>
+where
+    F: FnMut(anyhow::Result<ra::NodeProfile>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
     lookup_node_profile_operation(
         tea_id,
         "layer1.async.reply.node_profile_by_tea_id",
         reply_to,
+        options,
         callback,
     )
 }
@@ -39,22 +76,26 @@ fn lookup_node_profile_operation<F>(
     param_bytes: &[u8],
     subject: &str,
     reply_to: &str,
+    options: action::CallOptions,
     mut callback: F,
-) -> anyhow::Result<()>
+) -> anyhow::Result<action::PendingCall>
 where
-    F: FnMut(&ra::NodeProfile) -> anyhow::Result<()> + Sync + Send + 'static,
+    F: FnMut(anyhow::Result<ra::NodeProfile>) -> anyhow::Result<()> + Sync + Send + 'static,
 {
-    action::call(
+    action::call_ex(
         subject,
         reply_to,
         base64::encode(&param_bytes).as_bytes().to_vec(),
-        move |msg| {
-            // info!("looup_node_profile returns msg_as string:{}", &String::from_utf8(msg.body.clone())?);
-            let buf = base64::decode(&String::from_utf8(msg.body.clone())?)?;
-            let profile = ra::NodeProfile::decode(buf.as_slice())?;
+        options,
+        move |reply| {
+            let profile = reply.and_then(|msg| {
+                // info!("looup_node_profile returns msg_as string:{}", &String::from_utf8(msg.body.clone())?);
+                let buf = base64::decode(&String::from_utf8(msg.body.clone())?)?;
+                Ok(ra::NodeProfile::decode(buf.as_slice())?)
+            });
 
             // info!("looup_node_profile returns profile:{:?}", &profile);
-            callback(&profile)
+            callback(profile)
         },
     )
 }
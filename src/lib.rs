@@ -18,7 +18,7 @@
 //! code clean. See README.md for code sample.
 //!
 //!The actor handler code will look like this
-//! ```
+//! ```ignore
 //! actor_handlers!{
 //!   codec::messaging::OP_DELIVER_MESSAGE => handle_nats_message,
 //!   codec::core::OP_HEALTH_REQUEST => health
@@ -67,6 +67,7 @@ pub mod action;
 pub mod actor_crypto;
 pub mod actor_enclave;
 pub mod actor_env;
+pub mod actor_extras;
 pub mod actor_ipfs;
 pub mod actor_kvp;
 pub mod actor_layer1;
@@ -79,9 +80,21 @@ pub mod actor_statemachine;
 pub mod actor_util;
 pub mod async_collector;
 pub mod common;
+pub mod correlation;
+pub mod error;
+pub mod executor;
+pub mod fanout;
+pub mod idempotency;
+pub mod intercom;
 pub mod ipfs_p2p;
 pub mod layer1;
+pub mod metrics;
 pub mod receipts;
+pub mod retry;
+pub mod router;
+pub mod scheduler;
+pub mod transport;
+pub mod workflow;
 
 #[macro_use]
 extern crate log;
@@ -0,0 +1,344 @@
+//! Counters and latency histograms of the host calls made by this actor.
+//!
+//! Install the interceptor once when the actor starts, then serve `render()` from the HTTP
+//! dispatcher with content type `text/plain; version=0.0.4`:
+//!
+//! ```ignore
+//! metrics::install();
+//! // later, for the "metrics" http action
+//! let body = metrics::render();
+//! ```
+//!
+//! Calls are keyed by capability and operation. VMH outbound messages are also keyed by channel,
+//! so that ipfs, adapter and layer1 traffic can be told apart.
+
+use crate::actor_env;
+use crate::transport::{self, CallContext, Interceptor, InterceptorId};
+use lazy_static::lazy_static;
+use prost::Message;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use vmh_codec::message::structs_proto::vmh;
+use wascc_actor::prelude::*;
+
+/// Upper bounds of the latency buckets, in milliseconds.
+pub const LATENCY_BUCKETS_MS: [u64; 12] =
+    [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Current time in milliseconds, `None` if unknown. The default clock asks `tea:env`, which
+/// costs two extra host calls per measured call.
+pub type Clock = Arc<dyn Fn() -> Option<u64> + Send + Sync + 'static>;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CallKey {
+    pub capability: String,
+    pub operation: String,
+    /// VMH outbound channel, empty for other calls.
+    pub channel: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Histogram {
+    /// Calls per bucket of `LATENCY_BUCKETS_MS`, not cumulative. The last entry counts the calls
+    /// slower than every bound.
+    pub buckets: Vec<u64>,
+    pub sum_ms: u64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, ms: u64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        }
+        let index = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[index] += 1;
+        self.sum_ms += ms;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CallMetrics {
+    pub calls: u64,
+    pub errors: u64,
+    /// Request payload bytes.
+    pub bytes_out: u64,
+    /// Response payload bytes of successful calls.
+    pub bytes_in: u64,
+    /// Only calls for which the clock answered are observed.
+    pub latency: Histogram,
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<CallKey, CallMetrics>> = Mutex::new(BTreeMap::new());
+}
+
+/// Feeds the metrics registry, see `install`.
+pub struct MetricsInterceptor {
+    clock: Clock,
+    started: Mutex<HashMap<u64, u64>>,
+}
+
+impl MetricsInterceptor {
+    pub fn new(clock: Clock) -> Arc<Self> {
+        Arc::new(MetricsInterceptor {
+            clock,
+            started: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Interceptor for MetricsInterceptor {
+    fn before(&self, call: &CallContext, _payload: &mut Vec<u8>) -> HandlerResult<()> {
+        if let (Some(now), Ok(mut started)) = ((self.clock)(), self.started.lock()) {
+            started.insert(call.id, now);
+        }
+        Ok(())
+    }
+
+    fn after(&self, call: &CallContext, request: &[u8], response: &mut HandlerResult<Vec<u8>>) {
+        let started = match self.started.lock() {
+            Ok(mut started) => started.remove(&call.id),
+            Err(_) => None,
+        };
+        let elapsed = started.and_then(|started| Some((self.clock)()?.saturating_sub(started)));
+        let key = call_key(call, request);
+        let mut registry = match REGISTRY.lock() {
+            Ok(registry) => registry,
+            Err(e) => {
+                error!("metrics registry lock failed: {:?}", e);
+                return;
+            }
+        };
+        let metrics = registry.entry(key).or_default();
+        metrics.calls += 1;
+        metrics.bytes_out += request.len() as u64;
+        match response {
+            Ok(bytes) => metrics.bytes_in += bytes.len() as u64,
+            Err(_) => metrics.errors += 1,
+        }
+        if let Some(ms) = elapsed {
+            metrics.latency.observe(ms);
+        }
+    }
+}
+
+fn call_key(call: &CallContext, request: &[u8]) -> CallKey {
+    let channel = if call.operation == vmh_codec::OP_OUTBOUND_MESSAGE {
+        vmh::OutboundRequest::decode(request)
+            .map(|req| req.channel)
+            .unwrap_or_default()
+    } else {
+        String::new()
+    };
+    CallKey {
+        capability: call.capid.to_string(),
+        operation: call.operation.to_string(),
+        channel,
+    }
+}
+
+/// Milliseconds since the epoch according to `tea:env`.
+pub fn system_clock() -> Option<u64> {
+    let now = actor_env::get_system_time().ok()?;
+    Some(now.duration_since(UNIX_EPOCH).ok()?.as_millis() as u64)
+}
+
+/// Start recording the host calls of this actor, timed by `system_clock`.
+pub fn install() -> InterceptorId {
+    install_with_clock(Arc::new(system_clock))
+}
+
+pub fn install_with_clock(clock: Clock) -> InterceptorId {
+    transport::add_interceptor(MetricsInterceptor::new(clock))
+}
+
+/// Metrics recorded so far, ordered by key.
+pub fn snapshot() -> Vec<(CallKey, CallMetrics)> {
+    match REGISTRY.lock() {
+        Ok(registry) => registry
+            .iter()
+            .map(|(key, metrics)| (key.clone(), metrics.clone()))
+            .collect(),
+        Err(e) => {
+            error!("metrics registry lock failed: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn reset() {
+    if let Ok(mut registry) = REGISTRY.lock() {
+        registry.clear();
+    }
+}
+
+/// `snapshot()` in the Prometheus text format.
+pub fn render() -> String {
+    render_prometheus(&snapshot())
+}
+
+pub fn render_prometheus(snapshot: &[(CallKey, CallMetrics)]) -> String {
+    let mut out = String::new();
+    counter(
+        &mut out,
+        snapshot,
+        "tea_host_calls_total",
+        "Host calls made.",
+        |m| m.calls,
+    );
+    counter(
+        &mut out,
+        snapshot,
+        "tea_host_call_errors_total",
+        "Host calls that failed.",
+        |m| m.errors,
+    );
+    counter(
+        &mut out,
+        snapshot,
+        "tea_host_call_request_bytes_total",
+        "Request payload bytes sent.",
+        |m| m.bytes_out,
+    );
+    counter(
+        &mut out,
+        snapshot,
+        "tea_host_call_response_bytes_total",
+        "Response payload bytes received.",
+        |m| m.bytes_in,
+    );
+
+    let name = "tea_host_call_duration_milliseconds";
+    let _ = writeln!(out, "# HELP {} Host call latency.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (key, metrics) in snapshot {
+        let labels = labels(key);
+        let latency = &metrics.latency;
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS_MS.iter().enumerate() {
+            cumulative += latency.buckets.get(i).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, latency.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, latency.sum_ms);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, latency.count);
+    }
+    out
+}
+
+fn counter(
+    out: &mut String,
+    snapshot: &[(CallKey, CallMetrics)],
+    name: &str,
+    help: &str,
+    value: fn(&CallMetrics) -> u64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (key, metrics) in snapshot {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels(key), value(metrics));
+    }
+}
+
+fn labels(key: &CallKey) -> String {
+    format!(
+        "capability=\"{}\",operation=\"{}\",channel=\"{}\"",
+        escape(&key.capability),
+        escape(&key.operation),
+        escape(&key.channel)
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{install_with_clock, render, snapshot, CallKey};
+    use crate::transport::{self, ScriptedTransport};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use vmh_codec::message::{encode_protobuf, structs_proto::vmh};
+
+    #[test]
+    fn records_calls_by_capability_operation_and_channel() {
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:keyvalue", "Get", vec![1, 2, 3]);
+        scripted.fail("tea:keyvalue", "Get", "boom");
+        scripted.respond(
+            vmh_codec::VMH_CAPABILITY_ID,
+            vmh_codec::OP_OUTBOUND_MESSAGE,
+            vec![],
+        );
+        let _guard = transport::replace(scripted);
+        // every reading advances the clock by 30ms
+        let ticks = Arc::new(AtomicU64::new(0));
+        let id = install_with_clock(Arc::new(move || {
+            Some(ticks.fetch_add(30, Ordering::SeqCst))
+        }));
+
+        let kvp = transport::host("metrics-test");
+        assert!(kvp.call("tea:keyvalue", "Get", vec![9]).is_ok());
+        assert!(kvp.call("tea:keyvalue", "Get", vec![9]).is_err());
+        let outbound = encode_protobuf(vmh::OutboundRequest {
+            uuid: "u".into(),
+            channel: "ipfs".into(),
+            msg: None,
+        })
+        .unwrap();
+        transport::default()
+            .call(
+                vmh_codec::VMH_CAPABILITY_ID,
+                vmh_codec::OP_OUTBOUND_MESSAGE,
+                outbound,
+            )
+            .unwrap();
+        transport::remove_interceptor(id);
+
+        let snapshot = snapshot();
+        let (_, get) = snapshot
+            .iter()
+            .find(|(key, _)| key.capability == "tea:keyvalue" && key.operation == "Get")
+            .unwrap();
+        assert_eq!((get.calls, get.errors), (2, 1));
+        assert_eq!((get.bytes_out, get.bytes_in), (2, 3));
+        assert_eq!((get.latency.count, get.latency.sum_ms), (2, 60));
+        assert_eq!(get.latency.buckets[4], 2);
+        assert!(snapshot.iter().any(|(key, _)| key
+            == &CallKey {
+                capability: vmh_codec::VMH_CAPABILITY_ID.into(),
+                operation: vmh_codec::OP_OUTBOUND_MESSAGE.into(),
+                channel: "ipfs".into(),
+            }));
+
+        let text = render();
+        assert!(text.contains(
+            "tea_host_call_errors_total{capability=\"tea:keyvalue\",operation=\"Get\",channel=\"\"} 1"
+        ));
+        assert!(text.contains(
+            "tea_host_call_duration_milliseconds_bucket{capability=\"tea:keyvalue\",operation=\"Get\",channel=\"\",le=\"25\"} 0"
+        ));
+        assert!(text.contains(
+            "tea_host_call_duration_milliseconds_bucket{capability=\"tea:keyvalue\",operation=\"Get\",channel=\"\",le=\"50\"} 2"
+        ));
+    }
+}
@@ -1,8 +1,8 @@
 use crate::actor_ipfs::ipfs_block_put;
-use prost::Message;
+use crate::error::{self, Error};
+use crate::transport;
 use vmh_codec::message::encode_protobuf;
 use vmh_codec::message::structs_proto::{env, kvp, receipt, vmh};
-use wascc_actor::prelude::*;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct PriceParams {
@@ -10,16 +10,15 @@ pub struct PriceParams {
     pub price_coefficient: u64,
 }
 
-pub fn start_task(uuid: &str) -> anyhow::Result<()> {
-    untyped::default()
-        .call(
-            vmh_codec::ENV_CAPABILITY_ID,
-            "TaskStart",
-            encode_protobuf(env::StartTasksRequest {
-                uuid: uuid.to_string(),
-            })?,
-        )
-        .map_err(|e| anyhow::anyhow!("{}", e))?;
+pub fn start_task(uuid: &str) -> error::Result<()> {
+    transport::default().request(
+        vmh_codec::ENV_CAPABILITY_ID,
+        "TaskStart",
+        encode_protobuf(env::StartTasksRequest {
+            uuid: uuid.to_string(),
+        })
+        .map_err(|e| Error::invalid_input(vmh_codec::ENV_CAPABILITY_ID, "TaskStart", e))?,
+    )?;
     Ok(())
 }
 
@@ -28,51 +27,16 @@ pub fn end_task(
     inbound_net_params: PriceParams,
     outbound_net_params: PriceParams,
     timespan_params: PriceParams,
-) -> anyhow::Result<String> {
-    let end_task_res = env::EndTasksResponse::decode(
-        untyped::default()
-            .call(
-                vmh_codec::ENV_CAPABILITY_ID,
-                "TaskEnd",
-                encode_protobuf(env::EndTasksRequest {
-                    uuid: uuid.to_string(),
-                })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
-
-    let inbound_net_res = vmh::NetworkDataLenResponse::decode(
-        untyped::default()
-            .call(
-                vmh_codec::VMH_CAPABILITY_ID,
-                vmh_codec::OP_INBOUND_NETWORK_DATA_LEN,
-                encode_protobuf(vmh::NetworkDataLenRequest {
-                    uuid: uuid.to_string(),
-                })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
+) -> error::Result<String> {
+    let end_task_res: env::EndTasksResponse = transport::default().request_proto(
+        vmh_codec::ENV_CAPABILITY_ID,
+        "TaskEnd",
+        env::EndTasksRequest {
+            uuid: uuid.to_string(),
+        },
     )?;
-    if !inbound_net_res.error.is_empty() {
-        return Err(anyhow::anyhow!("{}", inbound_net_res.error));
-    }
-
-    let outbound_net_res = vmh::NetworkDataLenResponse::decode(
-        untyped::default()
-            .call(
-                vmh_codec::VMH_CAPABILITY_ID,
-                vmh_codec::OP_OUTBOUND_NETWORK_DATA_LEN,
-                encode_protobuf(vmh::NetworkDataLenRequest {
-                    uuid: uuid.to_string(),
-                })?,
-            )
-            .map_err(|e| anyhow::anyhow!("{}", e))?
-            .as_slice(),
-    )?;
-    if !outbound_net_res.error.is_empty() {
-        return Err(anyhow::anyhow!("{}", outbound_net_res.error));
-    }
+    let inbound_net_res = network_data_len(vmh_codec::OP_INBOUND_NETWORK_DATA_LEN, uuid)?;
+    let outbound_net_res = network_data_len(vmh_codec::OP_OUTBOUND_NETWORK_DATA_LEN, uuid)?;
 
     let task_receipt = receipt::TaskReceipt {
         uuid: uuid.to_string(),
@@ -94,10 +58,38 @@ pub fn end_task(
             price_coefficient: timespan_params.price_coefficient,
         }),
     };
-    let (cid, _) = ipfs_block_put(encode_protobuf(task_receipt)?.as_slice(), true, uuid)?;
+    let (cid, _) = ipfs_block_put(encode_receipt(task_receipt)?.as_slice(), true, uuid)?;
     Ok(cid)
 }
 
+fn network_data_len(operation: &str, uuid: &str) -> error::Result<vmh::NetworkDataLenResponse> {
+    let res: vmh::NetworkDataLenResponse = transport::default().request_proto(
+        vmh_codec::VMH_CAPABILITY_ID,
+        operation,
+        vmh::NetworkDataLenRequest {
+            uuid: uuid.to_string(),
+        },
+    )?;
+    if !res.error.is_empty() {
+        return Err(Error::provider(
+            vmh_codec::VMH_CAPABILITY_ID,
+            operation,
+            res.error,
+        ));
+    }
+    Ok(res)
+}
+
+fn encode_receipt<T: prost::Message>(receipt: T) -> error::Result<Vec<u8>> {
+    encode_protobuf(receipt).map_err(|e| {
+        Error::invalid_input(
+            crate::actor_ipfs::CAPABILITY,
+            tea_codec::ipfs_codec::OP_BLOCK_PUT,
+            e,
+        )
+    })
+}
+
 /// get memory and disk usage receipt, `duration` is an u128 number with unit of millisecond
 pub fn get_storage_receipts(
     actors: &Vec<String>,
@@ -105,20 +97,15 @@ pub fn get_storage_receipts(
     duration: u128,
     memory_params: PriceParams,
     disk_params: PriceParams,
-) -> anyhow::Result<String> {
+) -> error::Result<String> {
     let mut total_memory_size = 0;
     for actor in actors {
-        let res = kvp::TaskMemorySizeResponse::decode(
-            untyped::host(actor)
-                .call(
-                    vmh_codec::KVP_CAPABILITY_ID,
-                    "GetTaskMemSize",
-                    encode_protobuf(kvp::TaskMemorySizeRequest {
-                        uuid: uuid.to_string(),
-                    })?,
-                )
-                .map_err(|e| anyhow::anyhow!("{}", e))?
-                .as_slice(),
+        let res: kvp::TaskMemorySizeResponse = transport::host(actor).request_proto(
+            vmh_codec::KVP_CAPABILITY_ID,
+            "GetTaskMemSize",
+            kvp::TaskMemorySizeRequest {
+                uuid: uuid.to_string(),
+            },
         )?;
         total_memory_size += res.size;
     }
@@ -139,6 +126,6 @@ pub fn get_storage_receipts(
         }),
     };
 
-    let (cid, _) = ipfs_block_put(encode_protobuf(storage_receipt)?.as_slice(), true, uuid)?;
+    let (cid, _) = ipfs_block_put(encode_receipt(storage_receipt)?.as_slice(), true, uuid)?;
     Ok(cid)
 }
@@ -0,0 +1,504 @@
+//! Retry failed calls with exponential backoff.
+//!
+//! Waits between attempts are scheduled with `action::delay_call_ex` on a caller supplied
+//! subject, so the actor keeps serving other messages meanwhile. As for any delayed call, the
+//! actor has to route `{delay_subject}.{uuid}` to `action::result_handler`.
+//!
+//! ```ignore
+//! let policy = RetryPolicy::default().retry_if(retry::is_timeout);
+//! retry::call_with_retry(policy, "actor.me.retry", "layer1.get", "actor.me.reply", body, |reply| {
+//!     //code snipet...
+//!     Ok(())
+//! })?;
+//!
+//! retry::retry_sync(RetryPolicy::default(), "actor.me.retry", move || {
+//!     Ok(actor_rpc::layer1::execute_http_request_ex(&url, uuid.clone(), headers.clone(), "GET".into(), None, None)?)
+//! }, |json| Ok(()))?;
+//! ```
+
+use crate::action::{self, CallOptions};
+use crate::actor_extras;
+use crate::error::Error;
+use crate::fanout::Sink;
+use std::sync::{Arc, Mutex};
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+/// Longest wait between two attempts a policy may ask for, a week.
+pub const MAX_DELAY_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+/// Decides whether a failed attempt is worth another one.
+pub type Retryable = Arc<dyn Fn(&anyhow::Error) -> bool + Sync + Send + 'static>;
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub max_attempts: u32,
+    /// Wait before the second attempt.
+    pub base_delay_seconds: u64,
+    /// Upper bound of the wait between two attempts, at most `MAX_DELAY_SECONDS`.
+    pub max_delay_seconds: u64,
+    /// Factor applied to the wait after each failed attempt.
+    pub multiplier: u64,
+    /// Wait a random time between half and all of the backoff, so that actors failing together
+    /// do not retry together.
+    pub jitter: bool,
+    /// See `retry_if`.
+    pub retryable: Retryable,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay_seconds: 1,
+            max_delay_seconds: 30,
+            multiplier: 2,
+            jitter: true,
+            retryable: Arc::new(|_| true),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Only retry errors for which `predicate` returns true. Every error is retried by default.
+    pub fn retry_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&anyhow::Error) -> bool + Sync + Send + 'static,
+    {
+        self.retryable = Arc::new(predicate);
+        self
+    }
+
+    pub fn is_retryable(&self, error: &anyhow::Error) -> bool {
+        (self.retryable)(error)
+    }
+
+    /// Refuse a policy that allows no attempt or waits longer than `MAX_DELAY_SECONDS`.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_attempts == 0 {
+            return Err(anyhow::anyhow!("retry policy allows no attempt"));
+        }
+        if self.max_delay_seconds > MAX_DELAY_SECONDS {
+            return Err(anyhow::anyhow!(
+                "retry policy waits up to {} seconds, more than {}",
+                self.max_delay_seconds,
+                MAX_DELAY_SECONDS
+            ));
+        }
+        Ok(())
+    }
+
+    /// Backoff after the failed attempt number `attempt` (starting at 1), before jitter. Never
+    /// more than `MAX_DELAY_SECONDS`.
+    pub fn backoff_seconds(&self, attempt: u32) -> u64 {
+        let factor = self
+            .multiplier
+            .checked_pow(attempt.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.base_delay_seconds
+            .saturating_mul(factor)
+            .min(self.max_delay_seconds)
+            .min(MAX_DELAY_SECONDS)
+    }
+
+    fn delay_seconds(&self, attempt: u32) -> u64 {
+        let backoff = self.backoff_seconds(attempt);
+        if !self.jitter || backoff == 0 {
+            return backoff;
+        }
+        // drawn from `low..high + 1`, `MAX_DELAY_SECONDS` fits in u32
+        let high = backoff as u32;
+        let low = high / 2;
+        match actor_extras::get_random(low, high + 1) {
+            Ok(delay) => delay as u64,
+            Err(e) => {
+                warn!("cannot get random jitter, waiting full backoff: {}", e);
+                backoff
+            }
+        }
+    }
+}
+
+/// Predicate for `RetryPolicy::retry_if` that only retries replies which did not arrive in time.
+pub fn is_timeout(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<Error>()
+        .map(|e| e.is_timeout())
+        .unwrap_or(false)
+}
+
+type Attempt<T> = Box<dyn FnMut(Sink<T>) -> anyhow::Result<()> + Sync + Send + 'static>;
+type Finish<T> = Box<dyn FnOnce(anyhow::Result<T>) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+struct RetryState<T> {
+    policy: RetryPolicy,
+    delay_subject: String,
+    // taken out while an attempt is being sent
+    attempt: Option<Attempt<T>>,
+    attempts: u32,
+    // the current attempt has already settled
+    settled: bool,
+    callback: Option<Finish<T>>,
+}
+
+/// Run `attempt` until it hands a success to its sink, the error is not retryable or
+/// `policy.max_attempts` is reached, then invoke `callback` with the last outcome.
+///
+/// An error returned by `attempt` itself counts as a failed attempt.
+pub fn retry<T, A, F>(
+    policy: RetryPolicy,
+    delay_subject: &str,
+    attempt: A,
+    callback: F,
+) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+    A: FnMut(Sink<T>) -> anyhow::Result<()> + Sync + Send + 'static,
+    F: FnOnce(anyhow::Result<T>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    policy.validate()?;
+    let state = Arc::new(Mutex::new(RetryState {
+        policy,
+        delay_subject: delay_subject.to_string(),
+        attempt: Some(Box::new(attempt)),
+        attempts: 0,
+        settled: false,
+        callback: Some(Box::new(callback)),
+    }));
+    run_attempt(state)
+}
+
+/// `retry` for a call answering synchronously, such as `actor_rpc::call_adapter_rpc` or
+/// `actor_rpc::layer1::execute_http_request_ex`.
+pub fn retry_sync<T, A, F>(
+    policy: RetryPolicy,
+    delay_subject: &str,
+    mut attempt: A,
+    callback: F,
+) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+    A: FnMut() -> anyhow::Result<T> + Sync + Send + 'static,
+    F: FnOnce(anyhow::Result<T>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    retry(
+        policy,
+        delay_subject,
+        move |mut sink: Sink<T>| sink(attempt()),
+        callback,
+    )
+}
+
+/// `action::call_ex` retried according to `policy`. Timeouts reach the policy as `Error::Timeout`.
+pub fn call_with_retry<F>(
+    policy: RetryPolicy,
+    delay_subject: &str,
+    subject: &str,
+    reply_to: &str,
+    param: Vec<u8>,
+    callback: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(anyhow::Result<BrokerMessage>) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let subject = subject.to_string();
+    let reply_to = reply_to.to_string();
+    retry(
+        policy,
+        delay_subject,
+        move |mut sink: Sink<BrokerMessage>| {
+            action::call_ex(
+                &subject,
+                &reply_to,
+                param.clone(),
+                CallOptions {
+                    errors_to_callback: true,
+                    ..CallOptions::default()
+                },
+                move |reply| sink(reply.cloned()),
+            )?;
+            Ok(())
+        },
+        callback,
+    )
+}
+
+fn run_attempt<T>(state: Arc<Mutex<RetryState<T>>>) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+{
+    let (mut attempt, number) = {
+        let mut state = lock(&state)?;
+        state.attempts += 1;
+        state.settled = false;
+        match state.attempt.take() {
+            Some(attempt) => (attempt, state.attempts),
+            None => return Err(anyhow::anyhow!("retry attempt is already running")),
+        }
+    };
+    let sink_state = state.clone();
+    let sent = attempt(Box::new(move |result| {
+        settle(sink_state.clone(), number, result)
+    }));
+    lock(&state)?.attempt = Some(attempt);
+    match sent {
+        Ok(()) => Ok(()),
+        Err(e) => settle(state, number, Err(e)),
+    }
+}
+
+fn settle<T>(
+    state: Arc<Mutex<RetryState<T>>>,
+    number: u32,
+    result: anyhow::Result<T>,
+) -> anyhow::Result<()>
+where
+    T: Send + 'static,
+{
+    let (callback, retry_after) = {
+        let mut state = lock(&state)?;
+        if state.attempts != number || state.settled || state.callback.is_none() {
+            return Ok(());
+        }
+        state.settled = true;
+        match &result {
+            Err(e) if number < state.policy.max_attempts && state.policy.is_retryable(e) => {
+                warn!(
+                    "attempt {} of {} failed, retrying: {}",
+                    number, state.policy.max_attempts, e
+                );
+                (None, Some(state.policy.delay_seconds(number)))
+            }
+            _ => (state.callback.take(), None),
+        }
+    };
+    if let Some(callback) = callback {
+        return callback(result.map_err(|e| {
+            let context = format!("gave up after {} attempt(s)", number);
+            e.context(context)
+        }));
+    }
+    let delay_seconds = retry_after.unwrap_or_default();
+    let subject = lock(&state)?.delay_subject.clone();
+    let wait_state = state.clone();
+    let scheduled = action::delay_call_ex(
+        &subject,
+        Vec::new(),
+        delay_seconds,
+        CallOptions::default(),
+        move |_| run_attempt(wait_state.clone()),
+    );
+    if let Err(e) = scheduled {
+        let callback = lock(&state)?.callback.take();
+        if let Some(callback) = callback {
+            return callback(Err(e.context("cannot schedule retry")));
+        }
+    }
+    Ok(())
+}
+
+fn lock<T>(
+    state: &Arc<Mutex<RetryState<T>>>,
+) -> anyhow::Result<std::sync::MutexGuard<'_, RetryState<T>>> {
+    state
+        .lock()
+        .map_err(|e| anyhow::anyhow!("retry state lock failed: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{call_with_retry, is_timeout, retry, retry_sync, RetryPolicy, MAX_DELAY_SECONDS};
+    use crate::action::{self, lock_pending_calls};
+    use crate::error::Error;
+    use crate::fanout::Sink;
+    use crate::transport::{self, KvpSimulator, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::codec::extras::{GeneratorRequest, GeneratorResult};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::*;
+
+    #[test]
+    fn backoff_grows_and_is_clamped() {
+        let policy = RetryPolicy::default();
+        let backoffs: Vec<u64> = (1..=7).map(|a| policy.backoff_seconds(a)).collect();
+        assert_eq!(backoffs, vec![1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(policy.backoff_seconds(200), 30);
+        assert_eq!(policy.backoff_seconds(0), 1);
+
+        let unbounded = RetryPolicy {
+            base_delay_seconds: u64::MAX / 2,
+            max_delay_seconds: u64::MAX,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(unbounded.backoff_seconds(3), MAX_DELAY_SECONDS);
+        assert!(unbounded.validate().is_err());
+        let no_attempt = RetryPolicy {
+            max_attempts: 0,
+            ..RetryPolicy::default()
+        };
+        assert!(no_attempt.validate().is_err());
+        assert!(retry_sync(no_attempt, "actor.me.retry", || Ok(()), |_| Ok(())).is_err());
+
+        let scripted = ScriptedTransport::new();
+        scripted.respond(
+            "wascc:extras",
+            "RequestRandom",
+            serialize(GeneratorResult {
+                random_number: 7,
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let _guard = transport::replace(scripted.clone());
+        assert_eq!(policy.delay_seconds(6), 7);
+        // without random number the full backoff is waited
+        assert_eq!(policy.delay_seconds(6), 30);
+        let req: GeneratorRequest = deserialize(&scripted.calls()[0].payload).unwrap();
+        assert_eq!((req.min, req.max), (15, 31));
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            jitter: false,
+            ..RetryPolicy::default()
+        }
+    }
+
+    // fire the delayed call armed by the `n`th `DelayPublish`, with the wait it asked for
+    fn fire_delay(scripted: &ScriptedTransport, n: usize) -> u64 {
+        let delayed: tea_codec::DelayMessage = scripted
+            .calls()
+            .iter()
+            .filter(|call| call.operation == tea_codec::OP_DELAY_PUBLISH)
+            .nth(n)
+            .map(|call| deserialize(&call.payload).unwrap())
+            .unwrap();
+        let fired = BrokerMessage {
+            subject: delayed.subject.clone(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        action::result_handler(&fired, delayed.subject.rsplit('.').next().unwrap()).unwrap();
+        delayed.delay_seconds
+    }
+
+    #[test]
+    fn failed_calls_are_sent_again_once_the_delay_fires() {
+        let _lock = lock_pending_calls();
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp);
+        scripted.fail("wascc:messaging", "Publish", "no responder");
+        scripted.respond("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, vec![]);
+        scripted.respond("wascc:messaging", "Publish", vec![]);
+        let _guard = transport::replace(scripted.clone());
+
+        let outcome = Arc::new(Mutex::new(None));
+        let sink = outcome.clone();
+        call_with_retry(
+            policy(3),
+            "actor.me.retry",
+            "layer1.get",
+            "actor.me.reply",
+            vec![1],
+            move |reply| {
+                *sink.lock().unwrap() = Some(reply.map(|msg| msg.body));
+                Ok(())
+            },
+        )
+        .unwrap();
+        let published = || {
+            scripted
+                .calls()
+                .iter()
+                .filter(|call| call.operation == "Publish")
+                .count()
+        };
+        assert_eq!(published(), 1);
+
+        assert_eq!(fire_delay(&scripted, 0), 1);
+        assert_eq!(published(), 2);
+        assert!(outcome.lock().unwrap().is_none());
+        let resent: BrokerMessage = scripted
+            .calls()
+            .iter()
+            .filter(|call| call.operation == "Publish")
+            .nth(1)
+            .map(|call| deserialize(&call.payload).unwrap())
+            .unwrap();
+        assert_eq!(
+            (resent.subject.as_str(), resent.body.clone()),
+            ("layer1.get", vec![1])
+        );
+        let reply = BrokerMessage {
+            subject: resent.reply_to.clone(),
+            reply_to: "".into(),
+            body: vec![2],
+        };
+        action::result_handler(&reply, resent.reply_to.rsplit('.').next().unwrap()).unwrap();
+        assert_eq!(outcome.lock().unwrap().take().unwrap().unwrap(), vec![2]);
+        assert_eq!(scripted.unanswered(), 0);
+    }
+
+    #[test]
+    fn retries_stop_at_non_retryable_errors_and_max_attempts() {
+        let _lock = lock_pending_calls();
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp);
+        scripted.respond("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, vec![]);
+        let _guard = transport::replace(scripted.clone());
+        let attempts = Arc::new(Mutex::new(0));
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+        let (counter, sink) = (attempts.clone(), outcomes.clone());
+        retry(
+            policy(3).retry_if(is_timeout),
+            "actor.me.retry",
+            move |mut attempt: Sink<()>| {
+                *counter.lock().unwrap() += 1;
+                attempt(Err(Error::not_found("tea:keyvalue", "Get", "key").into()))
+            },
+            move |result| {
+                sink.lock()
+                    .unwrap()
+                    .push(result.unwrap_err().root_cause().to_string());
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(*attempts.lock().unwrap(), 1);
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            vec!["tea:keyvalue Get found no key"]
+        );
+
+        let (counter, sink) = (attempts.clone(), outcomes.clone());
+        retry_sync(
+            policy(2),
+            "actor.me.retry",
+            move || {
+                let mut counter = counter.lock().unwrap();
+                *counter += 1;
+                Err::<(), _>(anyhow::anyhow!("failure {}", *counter))
+            },
+            move |result| {
+                sink.lock()
+                    .unwrap()
+                    .push(format!("{:#}", result.unwrap_err()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(*attempts.lock().unwrap(), 2);
+        assert_eq!(outcomes.lock().unwrap().len(), 1);
+        fire_delay(&scripted, 0);
+        assert_eq!(*attempts.lock().unwrap(), 3);
+        assert_eq!(
+            outcomes.lock().unwrap()[1],
+            "gave up after 2 attempt(s): failure 3"
+        );
+        assert_eq!(scripted.unanswered(), 0);
+    }
+}
@@ -0,0 +1,402 @@
+//! Subject routing for incoming `BrokerMessage`s.
+//!
+//! The `actor_messaging_handlers!` macro splits the subject on `.` and tries each tuple pattern
+//! in order. Inside a pattern `_` matches exactly one token (like the Nats `*` wildcard) and `..`
+//! matches whatever is left (like the Nats `>` wildcard, except that it also matches when nothing
+//! is left). Each token matched by `_` and the remainder matched by `..` are handed to the
+//! handler as captures, in pattern order.
+//!
+//! ```ignore
+//! actor_messaging_handlers! {
+//!     ("http", _, "bootstrap", "sync_from_other_actor", ..) => sync_from_other_actor,
+//!     ("http", _, "api", "request_active_nodes", ..) => request_active_nodes,
+//! }
+//!
+//! fn sync_from_other_actor(msg: &BrokerMessage, captures: &[&str]) -> HandlerResult<()> {
+//!     let node = captures[0];
+//!     //code snipet...
+//! }
+//! ```
+//! The macro generates `fn handle_nats_message(msg: BrokerMessage) -> HandlerResult<()>`, ready
+//! to be registered in `actor_handlers!` for `codec::messaging::OP_DELIVER_MESSAGE`. A subject
+//! that matches no pattern is answered with `RouteError::NoRoute`.
+//!
+//! When patterns are only known at runtime use `SubjectRouter` instead. Its patterns are written
+//! in Nats syntax plus named captures, e.g. `actor.pinner.intercom.get_key1.{deployment_id}` or
+//! `ipfs.p2p.reply.>`.
+
+use std::collections::HashMap;
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+/// One segment of a subject pattern.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    /// Matches exactly this token.
+    Literal(&'static str),
+    /// Matches any single token and captures it.
+    Any,
+    /// Matches all remaining tokens (possibly none) and captures them as one string.
+    /// Segments after `Rest` are never looked at.
+    Rest,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum RouteError {
+    #[error("no route matches subject \"{0}\"")]
+    NoRoute(String),
+    #[error("invalid subject pattern \"{0}\": {1}")]
+    InvalidPattern(String, String),
+}
+
+/// Match `subject` against `pattern`, returning the captured tokens if it matches.
+pub fn match_subject<'a>(pattern: &[Segment], subject: &'a str) -> Option<Vec<&'a str>> {
+    let mut captures = Vec::new();
+    // byte offset of the next unconsumed token, past the end once every token is consumed
+    let mut offset = 0;
+    for segment in pattern {
+        if let Segment::Rest = segment {
+            captures.push(&subject[offset.min(subject.len())..]);
+            return Some(captures);
+        }
+        if offset > subject.len() {
+            return None;
+        }
+        let token = subject[offset..].split('.').next().unwrap_or_default();
+        offset += token.len() + 1;
+        match segment {
+            Segment::Literal(literal) if *literal == token => {}
+            Segment::Any => captures.push(token),
+            _ => return None,
+        }
+    }
+    if offset > subject.len() {
+        Some(captures)
+    } else {
+        None
+    }
+}
+
+/// Values captured while matching a `SubjectRouter` pattern.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RouteParams {
+    named: HashMap<String, String>,
+    tail: Option<String>,
+}
+
+impl RouteParams {
+    /// Token captured by `{name}`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.named.get(name).map(|v| v.as_str())
+    }
+
+    /// Tokens matched by a trailing `>`, joined with `.`.
+    pub fn tail(&self) -> Option<&str> {
+        self.tail.as_deref()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum PatternToken {
+    Literal(String),
+    Named(String),
+    Any,
+    Tail,
+}
+
+type RouteHandler =
+    Box<dyn Fn(&BrokerMessage, &RouteParams) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+/// Dispatches a `BrokerMessage` to the first registered pattern matching its subject.
+///
+/// Pattern tokens are literals, `*` (any single token), `{name}` (any single token, captured as
+/// `name`) or a final `>` (one or more tokens, captured as the tail).
+#[derive(Default)]
+pub struct SubjectRouter {
+    routes: Vec<(Vec<PatternToken>, RouteHandler)>,
+}
+
+impl SubjectRouter {
+    pub fn new() -> Self {
+        SubjectRouter { routes: Vec::new() }
+    }
+
+    pub fn route<F>(&mut self, pattern: &str, handler: F) -> anyhow::Result<&mut SubjectRouter>
+    where
+        F: Fn(&BrokerMessage, &RouteParams) -> anyhow::Result<()> + Sync + Send + 'static,
+    {
+        self.routes
+            .push((parse_pattern(pattern)?, Box::new(handler)));
+        Ok(self)
+    }
+
+    pub fn dispatch(&self, msg: &BrokerMessage) -> anyhow::Result<()> {
+        for (pattern, handler) in self.routes.iter() {
+            if let Some(params) = match_pattern(pattern, &msg.subject) {
+                return handler(msg, &params);
+            }
+        }
+        Err(RouteError::NoRoute(msg.subject.clone()).into())
+    }
+}
+
+/// Build the subject matched by `pattern`, replacing each `{name}` with its value in `params`.
+/// Wildcards cannot be formatted, and values must be single non-empty tokens.
+pub fn format_subject(pattern: &str, params: &[(&str, &str)]) -> Result<String, RouteError> {
+    let invalid = |reason: String| RouteError::InvalidPattern(pattern.to_string(), reason);
+    let mut tokens = Vec::new();
+    for token in parse_pattern(pattern)? {
+        tokens.push(match token {
+            PatternToken::Literal(literal) => literal,
+            PatternToken::Named(name) => {
+                let value = params
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, v)| *v)
+                    .ok_or_else(|| invalid(format!("no value for {{{}}}", name)))?;
+                if value.is_empty() || value.contains('.') {
+                    return Err(invalid(format!("{{{}}} is not a single token", name)));
+                }
+                value.to_string()
+            }
+            PatternToken::Any | PatternToken::Tail => {
+                return Err(invalid("wildcards cannot be formatted".into()))
+            }
+        });
+    }
+    Ok(tokens.join("."))
+}
+
+fn parse_pattern(pattern: &str) -> Result<Vec<PatternToken>, RouteError> {
+    let invalid = |reason: &str| RouteError::InvalidPattern(pattern.to_string(), reason.into());
+    let raw: Vec<&str> = pattern.split('.').collect();
+    let mut tokens = Vec::with_capacity(raw.len());
+    for (i, token) in raw.iter().enumerate() {
+        tokens.push(match *token {
+            "" => return Err(invalid("empty token")),
+            ">" if i + 1 != raw.len() => return Err(invalid("'>' must be the last token")),
+            ">" => PatternToken::Tail,
+            "*" => PatternToken::Any,
+            t if t.starts_with('{') && t.ends_with('}') && t.len() > 2 => {
+                PatternToken::Named(t[1..t.len() - 1].to_string())
+            }
+            t => PatternToken::Literal(t.to_string()),
+        });
+    }
+    Ok(tokens)
+}
+
+fn match_pattern(pattern: &[PatternToken], subject: &str) -> Option<RouteParams> {
+    let tokens: Vec<&str> = subject.split('.').collect();
+    let mut params = RouteParams::default();
+    for (i, expected) in pattern.iter().enumerate() {
+        if let PatternToken::Tail = expected {
+            if i >= tokens.len() {
+                return None;
+            }
+            params.tail = Some(tokens[i..].join("."));
+            return Some(params);
+        }
+        let token = tokens.get(i)?;
+        match expected {
+            PatternToken::Literal(literal) if literal == token => {}
+            PatternToken::Named(name) => {
+                params.named.insert(name.clone(), token.to_string());
+            }
+            PatternToken::Any => {}
+            _ => return None,
+        }
+    }
+    if pattern.len() == tokens.len() {
+        Some(params)
+    } else {
+        None
+    }
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! subject_segment {
+    (_) => {
+        $crate::router::Segment::Any
+    };
+    (..) => {
+        $crate::router::Segment::Rest
+    };
+    ($literal:literal) => {
+        $crate::router::Segment::Literal($literal)
+    };
+}
+
+#[macro_export]
+macro_rules! actor_messaging_handlers {
+    ($(($($segment:tt),+ $(,)?) => $handler:path),* $(,)?) => {
+        fn handle_nats_message(
+            msg: $crate::wascc_actor::prelude::codec::messaging::BrokerMessage,
+        ) -> $crate::wascc_actor::prelude::HandlerResult<()> {
+            $(
+                if let Some(captures) = $crate::router::match_subject(
+                    &[$($crate::subject_segment!($segment)),+],
+                    &msg.subject,
+                ) {
+                    return $handler(&msg, &captures);
+                }
+            )*
+            Err(Box::new($crate::router::RouteError::NoRoute(msg.subject.clone())))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_subject, match_subject, RouteError, Segment, SubjectRouter};
+    use std::cell::RefCell;
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::HandlerResult;
+
+    thread_local! {
+        static ROUTED: RefCell<Vec<(String, Vec<String>)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(handler: &str, captures: &[&str]) -> HandlerResult<()> {
+        ROUTED.with(|routed| {
+            routed.borrow_mut().push((
+                handler.to_string(),
+                captures.iter().map(|c| c.to_string()).collect(),
+            ))
+        });
+        Ok(())
+    }
+
+    fn bootstrap(_: &BrokerMessage, captures: &[&str]) -> HandlerResult<()> {
+        record("bootstrap", captures)
+    }
+
+    fn reply(_: &BrokerMessage, captures: &[&str]) -> HandlerResult<()> {
+        record("reply", captures)
+    }
+
+    fn key(_: &BrokerMessage, captures: &[&str]) -> HandlerResult<()> {
+        record("key", captures)
+    }
+
+    actor_messaging_handlers! {
+        ("http", _, "bootstrap", ..) => bootstrap,
+        ("ipfs", "p2p", "reply", ..) => reply,
+        ("actor", "pinner", "intercom", "get_key1", _) => key,
+    }
+
+    #[test]
+    fn wildcards_capture_in_order() {
+        let pattern = [
+            Segment::Literal("http"),
+            Segment::Any,
+            Segment::Literal("bootstrap"),
+            Segment::Rest,
+        ];
+        assert_eq!(
+            match_subject(&pattern, "http.node1.bootstrap.sync.more"),
+            Some(vec!["node1", "sync.more"])
+        );
+        assert_eq!(
+            match_subject(&pattern, "http.node1.bootstrap"),
+            Some(vec!["node1", ""])
+        );
+        assert_eq!(match_subject(&pattern, "http.node1.api.x"), None);
+        assert_eq!(match_subject(&pattern[..2], "http.node1.bootstrap"), None);
+        assert_eq!(match_subject(&pattern[..2], "http"), None);
+    }
+
+    #[test]
+    fn router_captures_named_tokens_and_tail() {
+        let mut router = SubjectRouter::new();
+        router
+            .route(
+                "actor.pinner.intercom.get_key1.{deployment_id}",
+                |_, params| {
+                    assert_eq!(params.get("deployment_id"), Some("d1"));
+                    Ok(())
+                },
+            )
+            .unwrap()
+            .route("ipfs.p2p.reply.>", |_, params| {
+                assert_eq!(params.tail(), Some("42.extra"));
+                Ok(())
+            })
+            .unwrap();
+        assert!(SubjectRouter::new().route("a.>.b", |_, _| Ok(())).is_err());
+
+        let msg = |subject: &str| BrokerMessage {
+            subject: subject.into(),
+            reply_to: "".into(),
+            body: Vec::new(),
+        };
+        assert!(router
+            .dispatch(&msg("actor.pinner.intercom.get_key1.d1"))
+            .is_ok());
+        assert!(router.dispatch(&msg("ipfs.p2p.reply.42.extra")).is_ok());
+        assert!(router.dispatch(&msg("ipfs.p2p.reply")).is_err());
+        assert!(router
+            .dispatch(&msg("actor.pinner.intercom.get_key1"))
+            .is_err());
+
+        let subject = format_subject(
+            "actor.pinner.intercom.get_key1.{deployment_id}",
+            &[("deployment_id", "d1")],
+        )
+        .unwrap();
+        assert_eq!(subject, "actor.pinner.intercom.get_key1.d1");
+        assert!(router.dispatch(&msg(&subject)).is_ok());
+        assert!(format_subject("a.{id}", &[]).is_err());
+        assert!(format_subject("a.{id}", &[("id", "x.y")]).is_err());
+        assert!(format_subject("ipfs.p2p.reply.>", &[]).is_err());
+    }
+
+    #[test]
+    fn generated_handler_dispatches_in_pattern_order() {
+        let msg = |subject: &str| BrokerMessage {
+            subject: subject.into(),
+            reply_to: "".into(),
+            body: Vec::new(),
+        };
+        let routed = |subject: &str| {
+            handle_nats_message(msg(subject)).unwrap();
+            ROUTED.with(|routed| routed.borrow_mut().pop().unwrap())
+        };
+        let expect = |handler: &str, captures: &[&str]| {
+            (
+                handler.to_string(),
+                captures.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            )
+        };
+
+        // `_` matches one token, like `*`
+        assert_eq!(
+            routed("actor.pinner.intercom.get_key1.d1"),
+            expect("key", &["d1"])
+        );
+        assert!(handle_nats_message(msg("actor.pinner.intercom.get_key1.d1.x")).is_err());
+        assert!(handle_nats_message(msg("actor.pinner.intercom.get_key1")).is_err());
+
+        // `..` matches the remaining tokens, like `>`
+        assert_eq!(
+            routed("ipfs.p2p.reply.42.extra"),
+            expect("reply", &["42.extra"])
+        );
+        assert_eq!(
+            routed("http.node1.bootstrap.sync"),
+            expect("bootstrap", &["node1", "sync"])
+        );
+
+        // unlike `>`, `..` also matches when no token is left
+        assert_eq!(routed("ipfs.p2p.reply"), expect("reply", &[""]));
+        let mut nats = SubjectRouter::new();
+        nats.route("ipfs.p2p.reply.>", |_, _| Ok(())).unwrap();
+        assert!(nats.dispatch(&msg("ipfs.p2p.reply")).is_err());
+
+        let unrouted = handle_nats_message(msg("http.node1")).unwrap_err();
+        assert_eq!(
+            unrouted.downcast_ref::<RouteError>(),
+            Some(&RouteError::NoRoute("http.node1".into()))
+        );
+    }
+}
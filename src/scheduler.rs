@@ -0,0 +1,574 @@
+//! Recurring jobs on top of `action::delay_call`.
+//!
+//! A job fires at fixed intervals or on a cron schedule, each firing arms the next one. Job
+//! definitions are persisted in kvp, handlers are not: a restarted actor registers its handlers
+//! again with `set_handler` and calls `resume` to arm the persisted jobs. As for any delayed
+//! call, the actor has to route `{delay_subject}.{uuid}` to `action::result_handler`.
+//!
+//! ```ignore
+//! scheduler::schedule(BINDING, "gc", Schedule::Every(600), 30, "actor.me.timer", |job| {
+//!     //code snipet...
+//!     Ok(())
+//! })?;
+//! scheduler::schedule(BINDING, "report", Schedule::Cron("0 */6 * * *".into()), 0, "actor.me.timer", report)?;
+//!
+//! // after a restart
+//! scheduler::set_handler("gc", gc)?;
+//! scheduler::set_handler("report", report)?;
+//! scheduler::resume(BINDING)?;
+//! ```
+//!
+//! Times are the seconds of `actor_env::current_timestamp`, cron schedules are evaluated in UTC.
+
+use crate::action::{self, CallOptions, PendingCall};
+use crate::actor_env;
+use crate::actor_extras;
+use crate::actor_kvp;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use wascc_actor::prelude::codec::messaging::BrokerMessage;
+
+const JOBS_KEY: &str = "scheduler_jobs";
+const SECONDS_PER_DAY: i64 = 86400;
+// a schedule that does not fire within this many days never fires, e.g. "0 0 30 2 *"
+const CRON_HORIZON_DAYS: i64 = 5 * 366;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Every so many seconds, counted from the moment the job was scheduled.
+    Every(u64),
+    /// `minute hour day-of-month month day-of-week`, each field being `*`, a value, a range
+    /// `a-b`, a step `*/n` or `a-b/n`, or a comma separated list of those.
+    Cron(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub name: String,
+    pub schedule: Schedule,
+    /// Each firing is delayed by a random number of seconds up to this bound.
+    pub jitter_seconds: u64,
+    /// Subject the delayed calls are published on.
+    pub delay_subject: String,
+    /// Timestamp at which the job was scheduled.
+    pub anchor: i64,
+}
+
+pub type JobHandler = Arc<dyn Fn(&Job) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+struct ArmedJob {
+    job: Job,
+    // firings armed before the latest one are ignored
+    generation: u64,
+    pending: PendingCall,
+}
+
+lazy_static! {
+    static ref JOBS: Mutex<HashMap<String, ArmedJob>> = Mutex::new(HashMap::new());
+    static ref HANDLERS: Mutex<HashMap<String, JobHandler>> = Mutex::new(HashMap::new());
+}
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn lock<T>(mutex: &Mutex<T>) -> anyhow::Result<MutexGuard<'_, T>> {
+    mutex
+        .lock()
+        .map_err(|e| anyhow::anyhow!("scheduler lock failed: {:?}", e))
+}
+
+fn job_key(name: &str) -> String {
+    format!("scheduler_job_{}", name)
+}
+
+/// Handler invoked each time job `name` fires, replaces the previous one.
+pub fn set_handler<F>(name: &str, handler: F) -> anyhow::Result<()>
+where
+    F: Fn(&Job) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    lock(&HANDLERS)?.insert(name.to_string(), Arc::new(handler));
+    Ok(())
+}
+
+/// Persist job `name` and arm its first firing. A job already scheduled under that name is
+/// replaced.
+pub fn schedule<F>(
+    binding: &'static str,
+    name: &str,
+    schedule: Schedule,
+    jitter_seconds: u64,
+    delay_subject: &str,
+    handler: F,
+) -> anyhow::Result<Job>
+where
+    F: Fn(&Job) -> anyhow::Result<()> + Sync + Send + 'static,
+{
+    let now = actor_env::current_timestamp()?;
+    let job = Job {
+        name: name.to_string(),
+        schedule,
+        jitter_seconds,
+        delay_subject: delay_subject.to_string(),
+        anchor: now,
+    };
+    next_fire(&job, now)?;
+    disarm(name)?;
+    store(binding, &job)?;
+    set_handler(name, handler)?;
+    arm(binding, job.clone(), now)?;
+    Ok(job)
+}
+
+/// Stop job `name` and forget its definition. Returns false if no such job is armed.
+pub fn cancel(binding: &'static str, name: &str) -> anyhow::Result<bool> {
+    let armed = disarm(name)?;
+    actor_kvp::del(binding, &job_key(name))?;
+    actor_kvp::set_remove(binding, JOBS_KEY, &name.to_string())?;
+    Ok(armed)
+}
+
+/// Arm every persisted job that is not armed yet, returns the number of jobs armed.
+pub fn resume(binding: &'static str) -> anyhow::Result<usize> {
+    let now = actor_env::current_timestamp()?;
+    let mut armed = 0;
+    for job in load(binding)? {
+        if lock(&JOBS)?.contains_key(&job.name) {
+            continue;
+        }
+        if !lock(&HANDLERS)?.contains_key(&job.name) {
+            warn!("resume job {} without handler", job.name);
+        }
+        arm(binding, job, now)?;
+        armed += 1;
+    }
+    Ok(armed)
+}
+
+/// Jobs armed in this actor, ordered by name.
+pub fn jobs() -> Vec<Job> {
+    let mut jobs: Vec<Job> = match JOBS.lock() {
+        Ok(jobs) => jobs.values().map(|armed| armed.job.clone()).collect(),
+        Err(e) => {
+            error!("scheduler lock failed: {:?}", e);
+            Vec::new()
+        }
+    };
+    jobs.sort_by(|a, b| a.name.cmp(&b.name));
+    jobs
+}
+
+/// Timestamp of the first firing of `job` strictly after `now`, before jitter.
+pub fn next_fire(job: &Job, now: i64) -> anyhow::Result<i64> {
+    match &job.schedule {
+        Schedule::Every(0) => Err(anyhow::anyhow!("job {} has a zero interval", job.name)),
+        Schedule::Every(seconds) if *seconds > i64::MAX as u64 => Err(anyhow::anyhow!(
+            "job {} has an interval longer than {} seconds",
+            job.name,
+            i64::MAX
+        )),
+        Schedule::Every(seconds) => {
+            let seconds = *seconds as i64;
+            let elapsed = now.saturating_sub(job.anchor).max(0);
+            (elapsed / seconds + 1)
+                .checked_mul(seconds)
+                .and_then(|offset| job.anchor.checked_add(offset))
+                .ok_or_else(|| anyhow::anyhow!("job {} never fires again", job.name))
+        }
+        Schedule::Cron(expr) => CronSpec::parse(expr)?.next_after(now),
+    }
+}
+
+fn store(binding: &'static str, job: &Job) -> anyhow::Result<()> {
+    actor_kvp::set_forever(binding, &job_key(&job.name), job)?;
+    actor_kvp::set_add(binding, JOBS_KEY, &job.name)?;
+    Ok(())
+}
+
+fn load(binding: &'static str) -> anyhow::Result<Vec<Job>> {
+    let mut names: Vec<String> = actor_kvp::set_query(binding, JOBS_KEY)?;
+    names.sort();
+    let mut jobs = Vec::new();
+    for name in names {
+        match actor_kvp::get(binding, &job_key(&name))? {
+            Some(job) => jobs.push(job),
+            None => warn!("scheduled job {} has no definition", name),
+        }
+    }
+    Ok(jobs)
+}
+
+fn disarm(name: &str) -> anyhow::Result<bool> {
+    match lock(&JOBS)?.remove(name) {
+        Some(armed) => {
+            armed.pending.cancel();
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn jitter(seconds: u64) -> u64 {
+    if seconds == 0 {
+        return 0;
+    }
+    // drawn in u32, longer jitters are clamped
+    let high = seconds.min(u64::from(u32::MAX) - 1);
+    match actor_extras::get_random(0, high as u32 + 1) {
+        Ok(jitter) => jitter as u64,
+        Err(e) => {
+            warn!("cannot get random jitter, firing without: {}", e);
+            0
+        }
+    }
+}
+
+fn arm(binding: &'static str, job: Job, now: i64) -> anyhow::Result<()> {
+    let delay = (next_fire(&job, now)? - now) as u64;
+    let delay = delay.saturating_add(jitter(job.jitter_seconds));
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    let name = job.name.clone();
+    let pending = action::delay_call_ex(
+        &job.delay_subject,
+        vec![],
+        delay,
+        CallOptions::default(),
+        move |reply| fire(binding, &name, generation, reply),
+    )?;
+    lock(&JOBS)?.insert(
+        job.name.clone(),
+        ArmedJob {
+            job,
+            generation,
+            pending,
+        },
+    );
+    Ok(())
+}
+
+fn armed_job(name: &str, generation: u64) -> anyhow::Result<Option<Job>> {
+    Ok(match lock(&JOBS)?.get(name) {
+        Some(armed) if armed.generation == generation => Some(armed.job.clone()),
+        _ => None,
+    })
+}
+
+fn fire(
+    binding: &'static str,
+    name: &str,
+    generation: u64,
+    reply: anyhow::Result<&BrokerMessage>,
+) -> anyhow::Result<()> {
+    let job = match armed_job(name, generation)? {
+        Some(job) => job,
+        None => {
+            debug!("drop stale firing of job {}", name);
+            return Ok(());
+        }
+    };
+    match reply {
+        Ok(_) => {
+            let handler = lock(&HANDLERS)?.get(name).cloned();
+            match handler {
+                Some(handler) => {
+                    if let Err(e) = handler(&job) {
+                        warn!("job {} failed: {}", name, e);
+                    }
+                }
+                None => warn!("job {} fired without handler", name),
+            }
+        }
+        Err(e) => warn!("job {} missed its firing: {}", name, e),
+    }
+    // the handler may have cancelled or replaced the job
+    if armed_job(name, generation)?.is_none() {
+        return Ok(());
+    }
+    arm(binding, job, actor_env::current_timestamp()?).map_err(|e| {
+        error!("cannot re-arm job {}: {}", name, e);
+        e
+    })
+}
+
+struct CronSpec {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // both day fields restricted: either of them matching is enough, as in cron
+    either_day: bool,
+}
+
+impl CronSpec {
+    fn parse(expr: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(anyhow::anyhow!("cron expression {} needs 5 fields", expr));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // both 0 and 7 are sunday
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(CronSpec {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            either_day: fields[2] != "*" && fields[4] != "*",
+        })
+    }
+
+    fn matches_day(&self, days: i64) -> bool {
+        let (month, day) = month_day(days);
+        let weekday = (days + 4).rem_euclid(7); // 1970-01-01 was a thursday
+        let by_date = self.days & 1 << day != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        self.months & 1 << month != 0
+            && if self.either_day {
+                by_date || by_weekday
+            } else {
+                by_date && by_weekday
+            }
+    }
+
+    fn next_after(&self, now: i64) -> anyhow::Result<i64> {
+        let mut t = (now.div_euclid(60) + 1) * 60;
+        let horizon = now + CRON_HORIZON_DAYS * SECONDS_PER_DAY;
+        while t < horizon {
+            let days = t.div_euclid(SECONDS_PER_DAY);
+            let seconds = t.rem_euclid(SECONDS_PER_DAY);
+            let hour = seconds / 3600;
+            let minute = seconds % 3600 / 60;
+            if !self.matches_day(days) {
+                t = (days + 1) * SECONDS_PER_DAY;
+            } else if self.hours & 1 << hour == 0 {
+                t = days * SECONDS_PER_DAY + (hour + 1) * 3600;
+            } else if self.minutes & 1 << minute == 0 {
+                t += 60;
+            } else {
+                return Ok(t);
+            }
+        }
+        Err(anyhow::anyhow!("cron schedule never fires"))
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid cron field {}", field);
+    let mut mask = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], part[i + 1..].parse().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (low, high) = if range == "*" {
+            (min, max)
+        } else if let Some(i) = range.find('-') {
+            (
+                range[..i].parse().map_err(|_| invalid())?,
+                range[i + 1..].parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let value = range.parse().map_err(|_| invalid())?;
+            (value, if step > 1 { max } else { value })
+        };
+        if step == 0 || low < min || high > max || low > high {
+            return Err(invalid());
+        }
+        for value in (low..=high).step_by(step as usize) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+// month (1-12) and day of month of a day counted from the epoch
+fn month_day(days: i64) -> (i64, i64) {
+    let z = days + 719468;
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        cancel, fire, jobs, load, next_fire, resume, schedule, set_handler, store, Job, Schedule,
+        JOBS,
+    };
+    use crate::action::{self, lock_pending_calls};
+    use crate::transport::{self, KvpSimulator, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::codec::messaging::BrokerMessage;
+    use wascc_actor::prelude::deserialize;
+
+    fn job(schedule: Schedule) -> Job {
+        Job {
+            name: "job".into(),
+            schedule,
+            jitter_seconds: 0,
+            delay_subject: "actor.me.timer".into(),
+            anchor: 1_000,
+        }
+    }
+
+    #[test]
+    fn computes_next_firings() {
+        let every = job(Schedule::Every(60));
+        assert_eq!(next_fire(&every, 500).unwrap(), 1_060);
+        assert_eq!(next_fire(&every, 1_060).unwrap(), 1_120);
+        assert!(next_fire(&job(Schedule::Every(0)), 0).is_err());
+        assert!(next_fire(&job(Schedule::Every(u64::MAX)), 0).is_err());
+        assert!(next_fire(&job(Schedule::Every(i64::MAX as u64)), 0).is_err());
+
+        // 2021-03-05 10:07:30 UTC, a friday
+        let now = 1_614_938_850;
+        let cron = |expr: &str| next_fire(&job(Schedule::Cron(expr.into())), now).unwrap();
+        assert_eq!(cron("* * * * *"), 1_614_938_880);
+        assert_eq!(cron("*/15 * * * *"), 1_614_939_300);
+        assert_eq!(cron("0 9 * * *"), 1_614_934_800 + 86_400);
+        // next monday 09:00, when both day fields are set the first of them matching wins
+        assert_eq!(cron("0 9 * * 1"), 1_615_194_000);
+        assert_eq!(cron("0 9 10 * 1"), 1_615_194_000);
+        assert_eq!(cron("0 9 6 * 1"), 1_615_021_200);
+        assert_eq!(cron("30 8 29 2 *"), 1_709_195_400);
+        assert!(next_fire(&job(Schedule::Cron("0 0 30 2 *".into())), now).is_err());
+        assert!(next_fire(&job(Schedule::Cron("61 * * * *".into())), now).is_err());
+        assert!(next_fire(&job(Schedule::Cron("* * *".into())), now).is_err());
+    }
+
+    #[test]
+    fn job_definitions_survive_in_kvp() {
+        let _guard = transport::replace(KvpSimulator::new());
+        let every = job(Schedule::Every(60));
+        let cron = Job {
+            name: "cron".into(),
+            ..job(Schedule::Cron("0 * * * *".into()))
+        };
+        store("default", &every).unwrap();
+        store("default", &cron).unwrap();
+        assert_eq!(load("default").unwrap(), vec![cron, every]);
+    }
+
+    fn timers(scripted: &ScriptedTransport) -> Vec<tea_codec::DelayMessage> {
+        scripted
+            .calls()
+            .iter()
+            .filter(|call| call.operation == tea_codec::OP_DELAY_PUBLISH)
+            .map(|call| deserialize(&call.payload).unwrap())
+            .collect()
+    }
+
+    fn fire_timer(timer: &tea_codec::DelayMessage) {
+        let fired = BrokerMessage {
+            subject: timer.subject.clone(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        action::result_handler(&fired, timer.subject.rsplit('.').next().unwrap()).unwrap();
+    }
+
+    fn scripted_timers(count: usize) -> (Arc<KvpSimulator>, Arc<ScriptedTransport>) {
+        let kvp = KvpSimulator::new();
+        let scripted = ScriptedTransport::new();
+        scripted.fall_back_to(kvp.clone());
+        for _ in 0..count {
+            scripted.respond("wascc:messaging", tea_codec::OP_DELAY_PUBLISH, vec![]);
+        }
+        (kvp, scripted)
+    }
+
+    #[test]
+    fn firings_run_the_handler_and_rearm() {
+        let _lock = lock_pending_calls();
+        let (kvp, scripted) = scripted_timers(2);
+        let _guard = transport::replace(scripted.clone());
+        let fired = Arc::new(Mutex::new(0));
+        let counter = fired.clone();
+        schedule(
+            "default",
+            "tick",
+            Schedule::Every(60),
+            0,
+            "actor.me.timer",
+            move |job| {
+                let mut fired = counter.lock().unwrap();
+                *fired += 1;
+                if *fired == 2 {
+                    cancel("default", &job.name)?;
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(timers(&scripted)[0].delay_seconds, 60);
+
+        kvp.advance(60);
+        fire_timer(&timers(&scripted)[0]);
+        assert_eq!(*fired.lock().unwrap(), 1);
+        let rearmed = timers(&scripted);
+        assert_eq!(rearmed.len(), 2);
+        assert_eq!(rearmed[1].delay_seconds, 60);
+
+        // a firing of an earlier generation is ignored
+        let generation = JOBS.lock().unwrap()["tick"].generation;
+        let msg = BrokerMessage {
+            subject: "actor.me.timer".into(),
+            reply_to: "".into(),
+            body: vec![],
+        };
+        fire("default", "tick", generation - 1, Ok(&msg)).unwrap();
+        assert_eq!(*fired.lock().unwrap(), 1);
+
+        // the handler cancels the job on its second firing
+        kvp.advance(60);
+        fire_timer(&rearmed[1]);
+        assert_eq!(*fired.lock().unwrap(), 2);
+        assert_eq!(timers(&scripted).len(), 2);
+        assert!(jobs().iter().all(|job| job.name != "tick"));
+        assert!(load("default").unwrap().is_empty());
+        assert_eq!(scripted.unanswered(), 0);
+    }
+
+    #[test]
+    fn resume_arms_persisted_jobs() {
+        let _lock = lock_pending_calls();
+        let (kvp, scripted) = scripted_timers(2);
+        let _guard = transport::replace(scripted.clone());
+        let persisted = Job {
+            name: "persisted".into(),
+            anchor: 0,
+            ..job(Schedule::Every(30))
+        };
+        store("default", &persisted).unwrap();
+        let fired = Arc::new(Mutex::new(0));
+        let counter = fired.clone();
+        set_handler("persisted", move |_| {
+            *counter.lock().unwrap() += 1;
+            Ok(())
+        })
+        .unwrap();
+
+        kvp.advance(40);
+        assert_eq!(resume("default").unwrap(), 1);
+        assert_eq!(resume("default").unwrap(), 0);
+        assert!(jobs().contains(&persisted));
+        let armed = timers(&scripted);
+        assert_eq!(armed.len(), 1);
+        assert_eq!(armed[0].delay_seconds, 20);
+
+        kvp.advance(20);
+        fire_timer(&armed[0]);
+        assert_eq!(*fired.lock().unwrap(), 1);
+        assert!(cancel("default", "persisted").unwrap());
+        assert_eq!(scripted.unanswered(), 0);
+    }
+}
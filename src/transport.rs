@@ -0,0 +1,189 @@
+//! Route of every host call made by this crate.
+//!
+//! Wrappers call `transport::default().call(...)` and `transport::host(binding).call(...)` the
+//! same way they would call `untyped`. By default the calls go to the waSCC host, a test can swap
+//! in another `HostTransport` for the current thread, e.g. a `ScriptedTransport`:
+//!
+//! ```ignore
+//! let transport = ScriptedTransport::new();
+//! transport.respond("tea:env", "GetEnvVar", encode_protobuf(env::GetResponse { .. })?);
+//! let _guard = transport::replace(transport.clone());
+//! assert_eq!(actor_env::get_env_var("HOME")?, "/root");
+//! assert_eq!(transport.calls()[0].operation, "GetEnvVar");
+//! ```
+//!
+//! Wrapping `WasccTransport` in a `RecordingTransport` captures the calls of a real session, a
+//! `ReplayTransport` later serves them back in a test and reports any divergence.
+//!
+//! Interceptors added with `add_interceptor` see every call before it reaches the transport,
+//! for tracing, accounting, signing or fault injection.
+
+pub mod interceptor;
+pub mod kvp;
+pub mod replay;
+pub mod scripted;
+
+pub use interceptor::{
+    add_interceptor, remove_interceptor, CallContext, Interceptor, InterceptorId,
+};
+pub use kvp::KvpSimulator;
+pub use replay::{RecordingTransport, ReplayTransport};
+pub use scripted::{RecordedCall, ScriptedTransport};
+
+use crate::error::{Error, Result};
+use prost::Message;
+use std::cell::RefCell;
+use std::sync::Arc;
+use wascc_actor::prelude::*;
+
+pub trait HostTransport: Send + Sync {
+    /// Invoke `operation` of capability `capid`. `binding` is `None` for the default binding.
+    fn call(
+        &self,
+        binding: Option<&str>,
+        capid: &str,
+        operation: &str,
+        payload: Vec<u8>,
+    ) -> HandlerResult<Vec<u8>>;
+}
+
+/// Sends calls to the waSCC host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WasccTransport;
+
+impl HostTransport for WasccTransport {
+    fn call(
+        &self,
+        binding: Option<&str>,
+        capid: &str,
+        operation: &str,
+        payload: Vec<u8>,
+    ) -> HandlerResult<Vec<u8>> {
+        match binding {
+            Some(binding) => untyped::host(binding).call(capid, operation, payload),
+            None => untyped::default().call(capid, operation, payload),
+        }
+    }
+}
+
+thread_local! {
+    static TRANSPORT: RefCell<Option<Arc<dyn HostTransport>>> = const { RefCell::new(None) };
+}
+
+/// Use `transport` for the host calls of the current thread until the returned guard is dropped.
+pub fn replace(transport: Arc<dyn HostTransport>) -> TransportGuard {
+    let previous = TRANSPORT.with(|current| current.borrow_mut().replace(transport));
+    TransportGuard { previous }
+}
+
+/// Restores the transport in place before `replace` when dropped.
+pub struct TransportGuard {
+    previous: Option<Arc<dyn HostTransport>>,
+}
+
+impl Drop for TransportGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        TRANSPORT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+fn current() -> Arc<dyn HostTransport> {
+    TRANSPORT
+        .with(|current| current.borrow().clone())
+        .unwrap_or_else(|| Arc::new(WasccTransport))
+}
+
+/// Host calls on one binding, the counterpart of `untyped::UntypedHostBinding`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostCall {
+    binding: Option<String>,
+}
+
+impl HostCall {
+    pub fn call(&self, capid: &str, operation: &str, payload: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        let binding = self.binding.as_deref();
+        interceptor::run(binding, capid, operation, payload, |payload| {
+            current().call(binding, capid, operation, payload)
+        })
+    }
+
+    /// Like `call`, a failure becomes `Error::HostCall`.
+    pub fn request(&self, capid: &str, operation: &str, payload: Vec<u8>) -> Result<Vec<u8>> {
+        self.call(capid, operation, payload)
+            .map_err(|e| Error::host_call(capid, operation, e))
+    }
+
+    /// Send a protobuf request and decode the protobuf response.
+    pub fn request_proto<Req, Res>(&self, capid: &str, operation: &str, req: Req) -> Result<Res>
+    where
+        Req: Message,
+        Res: Message + Default,
+    {
+        let mut buf = Vec::with_capacity(req.encoded_len());
+        req.encode(&mut buf)
+            .map_err(|e| Error::invalid_input(capid, operation, e))?;
+        let res = self.request(capid, operation, buf)?;
+        Res::decode(res.as_slice()).map_err(|e| Error::decode(capid, operation, e))
+    }
+}
+
+/// Host calls on the default binding.
+pub fn default() -> HostCall {
+    HostCall { binding: None }
+}
+
+/// Host calls on the named binding.
+pub fn host(binding: &str) -> HostCall {
+    HostCall {
+        binding: Some(binding.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replace, ScriptedTransport};
+    use crate::actor_env;
+    use crate::error::Error;
+    use vmh_codec::message::{encode_protobuf, structs_proto::env};
+
+    #[test]
+    fn wrappers_go_through_the_replaced_transport() {
+        let transport = ScriptedTransport::new();
+        transport.respond(
+            "tea:env",
+            "GetEnvVar",
+            encode_protobuf(env::GetResponse {
+                value: "/root".into(),
+                exists: true,
+            })
+            .unwrap(),
+        );
+        transport.fail("tea:env", "GetEnvVar", "host unavailable");
+        let _guard = replace(transport.clone());
+
+        assert_eq!(actor_env::get_env_var("HOME").unwrap(), "/root");
+        match actor_env::get_env_var("HOME") {
+            Err(Error::HostCall {
+                capability,
+                operation,
+                message,
+            }) => {
+                assert_eq!(capability, "tea:env");
+                assert_eq!(operation, "GetEnvVar");
+                assert_eq!(message, "host unavailable");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(actor_env::get_env_var("HOME").is_err());
+
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0].binding, None);
+        assert_eq!(calls[0].capid, "tea:env");
+        assert_eq!(
+            calls[0].payload,
+            encode_protobuf(env::GetRequest { key: "HOME".into() }).unwrap()
+        );
+    }
+}
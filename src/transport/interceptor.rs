@@ -0,0 +1,197 @@
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use wascc_actor::prelude::*;
+
+/// The host call an interceptor is looking at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallContext<'a> {
+    /// Unique per call on this thread, lets an interceptor pair `before` with `after`.
+    pub id: u64,
+    pub binding: Option<&'a str>,
+    pub capid: &'a str,
+    pub operation: &'a str,
+}
+
+/// Hooks around every host call made through `transport`.
+///
+/// `before` hooks run in registration order and `after` hooks in reverse order. A failing
+/// `before` hook cancels the call, the error then goes through the `after` hooks of the
+/// interceptors already entered. Host calls made from inside a hook bypass the chain.
+pub trait Interceptor: Send + Sync {
+    /// May rewrite the request, e.g. to sign it, or fail the call.
+    fn before(&self, _call: &CallContext, _payload: &mut Vec<u8>) -> HandlerResult<()> {
+        Ok(())
+    }
+
+    /// Sees the request as sent and may replace the response.
+    fn after(&self, _call: &CallContext, _request: &[u8], _response: &mut HandlerResult<Vec<u8>>) {}
+}
+
+/// Handle returned by `add_interceptor`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InterceptorId(u64);
+
+type Chain = Vec<(InterceptorId, Arc<dyn Interceptor>)>;
+
+thread_local! {
+    static CHAIN: RefCell<Chain> = const { RefCell::new(Vec::new()) };
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+}
+
+fn next_id() -> u64 {
+    NEXT_ID.with(|id| {
+        let next = id.get() + 1;
+        id.set(next);
+        next
+    })
+}
+
+/// Append `interceptor` to the chain of the current thread.
+pub fn add_interceptor(interceptor: Arc<dyn Interceptor>) -> InterceptorId {
+    let id = InterceptorId(next_id());
+    CHAIN.with(|chain| chain.borrow_mut().push((id, interceptor)));
+    id
+}
+
+/// Returns false if `id` is not in the chain.
+pub fn remove_interceptor(id: InterceptorId) -> bool {
+    CHAIN.with(|chain| {
+        let mut chain = chain.borrow_mut();
+        let len = chain.len();
+        chain.retain(|(i, _)| *i != id);
+        chain.len() != len
+    })
+}
+
+fn in_hook<R>(f: impl FnOnce() -> R) -> R {
+    IN_HOOK.with(|flag| flag.set(true));
+    let result = f();
+    IN_HOOK.with(|flag| flag.set(false));
+    result
+}
+
+/// Run `send` wrapped in the interceptor chain.
+pub(crate) fn run<F>(
+    binding: Option<&str>,
+    capid: &str,
+    operation: &str,
+    mut payload: Vec<u8>,
+    send: F,
+) -> HandlerResult<Vec<u8>>
+where
+    F: FnOnce(Vec<u8>) -> HandlerResult<Vec<u8>>,
+{
+    if IN_HOOK.with(|flag| flag.get()) {
+        return send(payload);
+    }
+    let chain: Vec<Arc<dyn Interceptor>> =
+        CHAIN.with(|chain| chain.borrow().iter().map(|(_, i)| i.clone()).collect());
+    if chain.is_empty() {
+        return send(payload);
+    }
+
+    let call = CallContext {
+        id: next_id(),
+        binding,
+        capid,
+        operation,
+    };
+    let mut entered = 0;
+    let mut refused = None;
+    for interceptor in chain.iter() {
+        if let Err(e) = in_hook(|| interceptor.before(&call, &mut payload)) {
+            refused = Some(e);
+            break;
+        }
+        entered += 1;
+    }
+    let mut response = match refused {
+        Some(e) => Err(e),
+        None => send(payload.clone()),
+    };
+    for interceptor in chain[..entered].iter().rev() {
+        in_hook(|| interceptor.after(&call, &payload, &mut response));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_interceptor, remove_interceptor, CallContext, Interceptor};
+    use crate::transport::{self, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use wascc_actor::prelude::*;
+
+    struct Tagging {
+        tag: u8,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Interceptor for Tagging {
+        fn before(&self, call: &CallContext, payload: &mut Vec<u8>) -> HandlerResult<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("before {} {}", self.tag, call.operation));
+            if call.operation == "Refused" && self.tag == 2 {
+                return Err("refused".into());
+            }
+            payload.push(self.tag);
+            // calls from a hook bypass the chain
+            let _ = transport::default().call("tea:env", "Nested", vec![]);
+            Ok(())
+        }
+
+        fn after(&self, _: &CallContext, request: &[u8], response: &mut HandlerResult<Vec<u8>>) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("after {} {:?}", self.tag, request));
+            if let Ok(bytes) = response {
+                bytes.push(self.tag);
+            }
+        }
+    }
+
+    #[test]
+    fn hooks_wrap_the_call_in_onion_order() {
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:env", "Op", vec![0]);
+        let _guard = transport::replace(scripted.clone());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let first = add_interceptor(Arc::new(Tagging {
+            tag: 1,
+            log: log.clone(),
+        }));
+        let second = add_interceptor(Arc::new(Tagging {
+            tag: 2,
+            log: log.clone(),
+        }));
+
+        let response = transport::default().call("tea:env", "Op", vec![]).unwrap();
+        assert_eq!(response, vec![0, 2, 1]);
+        assert_eq!(scripted.calls()[2].payload, vec![1, 2]);
+        assert!(transport::default()
+            .call("tea:env", "Refused", vec![])
+            .is_err());
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "before 1 Op",
+                "before 2 Op",
+                "after 2 [1, 2]",
+                "after 1 [1, 2]",
+                "before 1 Refused",
+                "before 2 Refused",
+                "after 1 [1]",
+            ]
+        );
+        let operations: Vec<String> = scripted.calls().into_iter().map(|c| c.operation).collect();
+        assert_eq!(operations, vec!["Nested", "Nested", "Op", "Nested"]);
+
+        assert!(remove_interceptor(first));
+        assert!(remove_interceptor(second));
+        assert!(!remove_interceptor(second));
+    }
+}
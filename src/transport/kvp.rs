@@ -0,0 +1,481 @@
+use super::HostTransport;
+use prost::Message;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use vmh_codec::message::{
+    encode_protobuf,
+    structs_proto::{env, kvp},
+};
+use wascc_actor::prelude::*;
+
+const CAPABILITY: &str = "tea:keyvalue";
+
+/// Binding name under which calls on the default binding are stored.
+pub const DEFAULT_BINDING: &str = "default";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bytes(Vec<u8>),
+    Int(i32),
+    List(Vec<Vec<u8>>),
+    Set(BTreeSet<Vec<u8>>),
+    // kept sorted by key
+    KeyVec(Vec<(i32, Vec<u8>)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    value: Value,
+    // virtual second at which the entry disappears
+    expires_at: Option<u64>,
+}
+
+type Store = HashMap<String, Entry>;
+
+#[derive(Default)]
+struct State {
+    bindings: HashMap<String, Store>,
+    task_mem_sizes: HashMap<String, u64>,
+}
+
+/// In-memory `tea:keyvalue` provider answering every operation sent by `actor_kvp` and the
+/// `GetTaskMemSize` query of `receipts`, with one store per binding.
+///
+/// Expiry is measured on a virtual clock that only moves with `advance`, so TTLs can be tested
+/// without waiting. `GetSystemTime` and `CurrentTimestamp` of `tea:env` read the same clock, so
+/// do the deadlines of pending calls. Calls to any other capability fail.
+#[derive(Clone, Default)]
+pub struct KvpSimulator {
+    state: Arc<Mutex<State>>,
+    now: Arc<AtomicU64>,
+}
+
+impl KvpSimulator {
+    pub fn new() -> Arc<Self> {
+        Arc::new(KvpSimulator::default())
+    }
+
+    /// Current second of the virtual clock, starting at 0.
+    pub fn now(&self) -> u64 {
+        self.now.load(Ordering::SeqCst)
+    }
+
+    /// Move the virtual clock forward.
+    pub fn advance(&self, seconds: u64) {
+        self.now.fetch_add(seconds, Ordering::SeqCst);
+    }
+
+    /// Answer `GetTaskMemSize` for `uuid` with `size`, 0 otherwise.
+    pub fn set_task_mem_size(&self, uuid: &str, size: u64) {
+        self.state
+            .lock()
+            .unwrap()
+            .task_mem_sizes
+            .insert(uuid.to_string(), size);
+    }
+
+    /// Keys currently alive in `binding`, sorted.
+    pub fn keys(&self, binding: &str) -> Vec<String> {
+        let now = self.now();
+        let state = self.state.lock().unwrap();
+        let mut keys: Vec<String> = state
+            .bindings
+            .get(binding)
+            .map(|store| {
+                store
+                    .iter()
+                    .filter(|(_, entry)| alive(entry, now))
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            })
+            .unwrap_or_default();
+        keys.sort();
+        keys
+    }
+
+    fn handle(&self, binding: &str, operation: &str, payload: &[u8]) -> HandlerResult<Vec<u8>> {
+        let now = self.now();
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| format!("kvp simulator lock failed: {:?}", e))?;
+        if operation == "GetTaskMemSize" {
+            let req = kvp::TaskMemorySizeRequest::decode(payload)?;
+            let size = state.task_mem_sizes.get(&req.uuid).copied().unwrap_or(0);
+            return Ok(encode_protobuf(kvp::TaskMemorySizeResponse { size })?);
+        }
+        let store = state.bindings.entry(binding.to_string()).or_default();
+        store.retain(|_, entry| alive(entry, now));
+
+        let response = match operation {
+            "Get" => {
+                let req = kvp::GetRequest::decode(payload)?;
+                let value = match store.get(&req.key).map(|entry| &entry.value) {
+                    Some(Value::Bytes(bytes)) => Some(bytes.clone()),
+                    Some(Value::Int(value)) => Some(tea_codec::serialize(value)?),
+                    Some(_) => return Err(wrong_type(&req.key, "Get")),
+                    None => None,
+                };
+                encode_protobuf(kvp::GetResponse {
+                    exists: value.is_some(),
+                    value: value.map(kvp::get_response::Value::V),
+                })?
+            }
+            "Set" => {
+                let req = kvp::SetRequest::decode(payload)?;
+                let expires_at = if req.expires_s > 0 {
+                    Some(now + req.expires_s as u64)
+                } else {
+                    None
+                };
+                store.insert(
+                    req.key,
+                    Entry {
+                        value: Value::Bytes(req.value.clone()),
+                        expires_at,
+                    },
+                );
+                encode_protobuf(kvp::SetResponse { value: req.value })?
+            }
+            "Add" => {
+                let req = kvp::AddRequest::decode(payload)?;
+                let entry = store.entry(req.key.clone()).or_insert(Entry {
+                    value: Value::Int(0),
+                    expires_at: None,
+                });
+                let value = match &mut entry.value {
+                    Value::Int(value) => {
+                        *value += req.value;
+                        *value
+                    }
+                    _ => return Err(wrong_type(&req.key, "Add")),
+                };
+                encode_protobuf(kvp::AddResponse { value })?
+            }
+            "Del" => {
+                let req = kvp::DelRequest::decode(payload)?;
+                store.remove(&req.key);
+                encode_protobuf(kvp::DelResponse { key: req.key })?
+            }
+            "Push" => {
+                let req = kvp::ListPushRequest::decode(payload)?;
+                let list = list_mut(store, &req.key)?;
+                list.push(req.value);
+                let new_count = list.len() as i32;
+                encode_protobuf(kvp::ListResponse { new_count })?
+            }
+            "Range" => {
+                let req = kvp::ListRangeRequest::decode(payload)?;
+                let values = match store.get(&req.key).map(|entry| &entry.value) {
+                    Some(Value::List(list)) => range(list, req.start, req.stop),
+                    Some(_) => return Err(wrong_type(&req.key, "Range")),
+                    None => Vec::new(),
+                };
+                encode_protobuf(kvp::ListRangeResponse { values })?
+            }
+            "ListItemDelete" => {
+                let req = kvp::ListDelItemRequest::decode(payload)?;
+                let list = list_mut(store, &req.key)?;
+                list.retain(|item| *item != req.value);
+                let new_count = list.len() as i32;
+                encode_protobuf(kvp::ListResponse { new_count })?
+            }
+            "SetAdd" => {
+                let req = kvp::SetAddRequest::decode(payload)?;
+                let set = set_mut(store, &req.key)?;
+                set.insert(req.value);
+                let new_count = set.len() as i32;
+                encode_protobuf(kvp::SetOperationResponse { new_count })?
+            }
+            "SetRemove" => {
+                let req = kvp::SetRemoveRequest::decode(payload)?;
+                let set = set_mut(store, &req.key)?;
+                set.remove(&req.value);
+                let new_count = set.len() as i32;
+                encode_protobuf(kvp::SetOperationResponse { new_count })?
+            }
+            "SetUnion" => {
+                let req = kvp::SetUnionRequest::decode(payload)?;
+                let mut union = BTreeSet::new();
+                for key in req.keys.iter() {
+                    union.extend(set_of(store, key)?);
+                }
+                encode_protobuf(kvp::SetQueryResponse {
+                    values: union.into_iter().collect(),
+                })?
+            }
+            "SetIntersection" => {
+                let req = kvp::SetIntersectionRequest::decode(payload)?;
+                let mut intersection: Option<BTreeSet<Vec<u8>>> = None;
+                for key in req.keys.iter() {
+                    let set = set_of(store, key)?;
+                    intersection = Some(match intersection {
+                        Some(acc) => acc.intersection(&set).cloned().collect(),
+                        None => set,
+                    });
+                }
+                encode_protobuf(kvp::SetQueryResponse {
+                    values: intersection.unwrap_or_default().into_iter().collect(),
+                })?
+            }
+            "SetQuery" => {
+                let req = kvp::SetQueryRequest::decode(payload)?;
+                encode_protobuf(kvp::SetQueryResponse {
+                    values: set_of(store, &req.key)?.into_iter().collect(),
+                })?
+            }
+            "KeyExists" => {
+                let req = kvp::KeyExistsQuery::decode(payload)?;
+                encode_protobuf(kvp::GetResponse {
+                    exists: store.contains_key(&req.key),
+                    value: None,
+                })?
+            }
+            "KeyVecInsert" => {
+                let req = kvp::KeyVecInsertQuery::decode(payload)?;
+                let tuple = req.value.ok_or("KeyVecInsert without value")?;
+                let vec = keyvec_mut(store, &req.key)?;
+                let success = match vec.binary_search_by_key(&tuple.k, |(k, _)| *k) {
+                    Ok(i) if req.overwrite => {
+                        vec[i].1 = tuple.v;
+                        true
+                    }
+                    Ok(_) => false,
+                    Err(i) => {
+                        vec.insert(i, (tuple.k, tuple.v));
+                        true
+                    }
+                };
+                encode_protobuf(kvp::KeyVecInsertResponse { success })?
+            }
+            "KeyVecGet" => {
+                let req = kvp::KeyVecGetQuery::decode(payload)?;
+                let values = match store.get(&req.key).map(|entry| &entry.value) {
+                    Some(Value::KeyVec(vec)) => vec
+                        .iter()
+                        .map(|(k, v)| kvp::TupleKeyValue {
+                            k: *k,
+                            v: v.clone(),
+                        })
+                        .collect(),
+                    Some(_) => return Err(wrong_type(&req.key, "KeyVecGet")),
+                    None => Vec::new(),
+                };
+                encode_protobuf(kvp::KeyVecGetResponse { values })?
+            }
+            "KeyVecRemoveItem" => {
+                let req = kvp::KeyVecRemoveItemQuery::decode(payload)?;
+                let vec = keyvec_mut(store, &req.key)?;
+                if req.value_idx < 0 || req.value_idx as usize >= vec.len() {
+                    return Err(format!(
+                        "index {} out of range for {} items of {}",
+                        req.value_idx,
+                        vec.len(),
+                        req.key
+                    )
+                    .into());
+                }
+                vec.remove(req.value_idx as usize);
+                encode_protobuf(kvp::KeyVecRemoveItemResponse {})?
+            }
+            "KeyVecTailOff" => {
+                // keeps the `remain` smallest keys
+                let req = kvp::KeyVecTailOffQuery::decode(payload)?;
+                let vec = keyvec_mut(store, &req.key)?;
+                vec.truncate(req.remain as usize);
+                let len = vec.len() as u32;
+                encode_protobuf(kvp::KeyVecTailOffResponse { len })?
+            }
+            _ => return Err(format!("kvp simulator does not support {}", operation).into()),
+        };
+        Ok(response)
+    }
+}
+
+impl HostTransport for KvpSimulator {
+    fn call(
+        &self,
+        binding: Option<&str>,
+        capid: &str,
+        operation: &str,
+        payload: Vec<u8>,
+    ) -> HandlerResult<Vec<u8>> {
+        if capid == "tea:env" && operation == "GetSystemTime" {
+            let now = UNIX_EPOCH + Duration::from_secs(self.now());
+            return serialize(now);
+        }
+        if capid == "tea:env" && operation == tea_codec::OP_CURRENT_TIMESTAMP {
+            let timestamp = self.now() as i64;
+            return Ok(encode_protobuf(env::GetCurrentTimestampResponse {
+                timestamp,
+            })?);
+        }
+        if capid != CAPABILITY && capid != vmh_codec::KVP_CAPABILITY_ID {
+            return Err(format!("kvp simulator cannot answer {} {}", capid, operation).into());
+        }
+        self.handle(binding.unwrap_or(DEFAULT_BINDING), operation, &payload)
+    }
+}
+
+fn alive(entry: &Entry, now: u64) -> bool {
+    entry.expires_at.map(|at| at > now).unwrap_or(true)
+}
+
+fn wrong_type(key: &str, operation: &str) -> Box<dyn std::error::Error + Send + Sync> {
+    format!("{} cannot be applied to the value of {}", operation, key).into()
+}
+
+/// Redis style inclusive range, negative indices count from the end.
+fn range(list: &[Vec<u8>], start: i32, stop: i32) -> Vec<Vec<u8>> {
+    let len = list.len() as i64;
+    let resolve = |i: i32| {
+        let i = i as i64;
+        if i < 0 {
+            (len + i).max(0)
+        } else {
+            i
+        }
+    };
+    let (start, stop) = (resolve(start), resolve(stop).min(len - 1));
+    if start > stop {
+        return Vec::new();
+    }
+    list[start as usize..=stop as usize].to_vec()
+}
+
+fn entry_mut<'a>(store: &'a mut Store, key: &str, empty: Value) -> &'a mut Value {
+    &mut store
+        .entry(key.to_string())
+        .or_insert(Entry {
+            value: empty,
+            expires_at: None,
+        })
+        .value
+}
+
+fn list_mut<'a>(store: &'a mut Store, key: &str) -> HandlerResult<&'a mut Vec<Vec<u8>>> {
+    match entry_mut(store, key, Value::List(Vec::new())) {
+        Value::List(list) => Ok(list),
+        _ => Err(wrong_type(key, "list operation")),
+    }
+}
+
+fn set_mut<'a>(store: &'a mut Store, key: &str) -> HandlerResult<&'a mut BTreeSet<Vec<u8>>> {
+    match entry_mut(store, key, Value::Set(BTreeSet::new())) {
+        Value::Set(set) => Ok(set),
+        _ => Err(wrong_type(key, "set operation")),
+    }
+}
+
+fn set_of(store: &Store, key: &str) -> HandlerResult<BTreeSet<Vec<u8>>> {
+    match store.get(key).map(|entry| &entry.value) {
+        Some(Value::Set(set)) => Ok(set.clone()),
+        Some(_) => Err(wrong_type(key, "set operation")),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+fn keyvec_mut<'a>(store: &'a mut Store, key: &str) -> HandlerResult<&'a mut Vec<(i32, Vec<u8>)>> {
+    match entry_mut(store, key, Value::KeyVec(Vec::new())) {
+        Value::KeyVec(vec) => Ok(vec),
+        _ => Err(wrong_type(key, "keyvec operation")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KvpSimulator;
+    use crate::{actor_kvp, transport};
+    use prost::Message;
+    use vmh_codec::message::{encode_protobuf, structs_proto::kvp};
+
+    #[test]
+    fn values_expire_on_the_virtual_clock() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+
+        actor_kvp::set("a", "session", &"token".to_string(), 10).unwrap();
+        actor_kvp::set_forever("b", "session", &"other".to_string()).unwrap();
+        assert_eq!(
+            actor_kvp::get::<String>("a", "session").unwrap(),
+            Some("token".into())
+        );
+        kvp.advance(10);
+        assert_eq!(actor_kvp::get::<String>("a", "session").unwrap(), None);
+        assert!(!actor_kvp::exists("a", "session").unwrap());
+        assert_eq!(kvp.keys("b"), vec!["session".to_string()]);
+
+        assert_eq!(actor_kvp::add("a", "counter", 3).unwrap(), 3);
+        assert_eq!(actor_kvp::add("a", "counter", -1).unwrap(), 2);
+        actor_kvp::del("a", "counter").unwrap();
+        assert_eq!(actor_kvp::add("a", "counter", 1).unwrap(), 1);
+    }
+
+    #[test]
+    fn collections() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+
+        for i in 0..4 {
+            actor_kvp::list_push("a", "list", &i).unwrap();
+        }
+        assert_eq!(actor_kvp::list_del_item("a", "list", &1).unwrap(), 3);
+        assert_eq!(
+            actor_kvp::list_range::<i32>("a", "list", 0, -1).unwrap(),
+            vec![0, 2, 3]
+        );
+        assert_eq!(
+            actor_kvp::list_range::<i32>("a", "list", -2, 10).unwrap(),
+            vec![2, 3]
+        );
+
+        actor_kvp::set_add("a", "s1", &1).unwrap();
+        actor_kvp::set_add("a", "s1", &2).unwrap();
+        actor_kvp::set_add("a", "s2", &2).unwrap();
+        assert_eq!(actor_kvp::set_add("a", "s2", &3).unwrap(), 2);
+        assert_eq!(
+            actor_kvp::set_union::<i32>("a", vec!["s1", "s2"]).unwrap(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            actor_kvp::set_intersect::<i32>("a", vec!["s1", "s2"]).unwrap(),
+            vec![2]
+        );
+        assert_eq!(actor_kvp::set_remove("a", "s1", &1).unwrap(), 1);
+        assert_eq!(actor_kvp::set_query::<i32>("a", "s1").unwrap(), vec![2]);
+
+        assert!(actor_kvp::keyvec_insert("a", "kv", (3, &"c"), false).unwrap());
+        assert!(actor_kvp::keyvec_insert("a", "kv", (1, &"a"), false).unwrap());
+        assert!(!actor_kvp::keyvec_insert("a", "kv", (1, &"x"), false).unwrap());
+        assert!(actor_kvp::keyvec_insert("a", "kv", (2, &"b"), true).unwrap());
+        actor_kvp::keyvec_remove_item("a", "kv", 1).unwrap();
+        assert_eq!(
+            actor_kvp::keyvec_get::<String>("a", "kv").unwrap(),
+            vec![(1, "a".to_string()), (3, "c".to_string())]
+        );
+        assert_eq!(actor_kvp::keyvec_tail_off("a", "kv", 1).unwrap(), 1);
+
+        assert!(actor_kvp::list_push("a", "s1", &0).is_err());
+        assert!(kvp.keys("b").is_empty());
+
+        kvp.set_task_mem_size("task", 42);
+        let res = transport::host("a")
+            .call(
+                vmh_codec::KVP_CAPABILITY_ID,
+                "GetTaskMemSize",
+                encode_protobuf(kvp::TaskMemorySizeRequest {
+                    uuid: "task".into(),
+                })
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(
+            kvp::TaskMemorySizeResponse::decode(res.as_slice())
+                .unwrap()
+                .size,
+            42
+        );
+    }
+}
@@ -0,0 +1,283 @@
+use super::{HostTransport, RecordedCall};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use wascc_actor::prelude::*;
+
+/// One host call and its outcome.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exchange {
+    pub call: RecordedCall,
+    /// Response bytes, or the error message returned by the host.
+    pub response: Result<Vec<u8>, String>,
+}
+
+// line format of a capture, binary fields are base64 encoded
+#[derive(Serialize, Deserialize)]
+struct ExchangeLine {
+    binding: Option<String>,
+    capid: String,
+    operation: String,
+    request: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Exchange {
+    fn to_line(&self) -> anyhow::Result<String> {
+        let (response, error) = match &self.response {
+            Ok(bytes) => (Some(base64::encode(bytes)), None),
+            Err(e) => (None, Some(e.clone())),
+        };
+        Ok(serde_json::to_string(&ExchangeLine {
+            binding: self.call.binding.clone(),
+            capid: self.call.capid.clone(),
+            operation: self.call.operation.clone(),
+            request: base64::encode(&self.call.payload),
+            response,
+            error,
+        })?)
+    }
+
+    fn from_line(line: &str) -> anyhow::Result<Exchange> {
+        let line: ExchangeLine = serde_json::from_str(line)?;
+        let response = match (line.response, line.error) {
+            (_, Some(error)) => Err(error),
+            (Some(response), None) => Ok(base64::decode(&response)?),
+            (None, None) => Ok(Vec::new()),
+        };
+        Ok(Exchange {
+            call: RecordedCall {
+                binding: line.binding,
+                capid: line.capid,
+                operation: line.operation,
+                payload: base64::decode(&line.request)?,
+            },
+            response,
+        })
+    }
+}
+
+/// Parse a capture written by `RecordingTransport`, one JSON object per line.
+pub fn parse_capture(capture: &str) -> anyhow::Result<Vec<Exchange>> {
+    capture
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            Exchange::from_line(line)
+                .map_err(|e| anyhow::anyhow!("invalid capture line {}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+/// Forwards host calls to another transport and records every exchange.
+pub struct RecordingTransport {
+    inner: Arc<dyn HostTransport>,
+    exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl RecordingTransport {
+    pub fn new(inner: Arc<dyn HostTransport>) -> Arc<Self> {
+        Arc::new(RecordingTransport {
+            inner,
+            exchanges: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().clone()
+    }
+
+    /// The capture in the line format read by `ReplayTransport`.
+    pub fn capture(&self) -> anyhow::Result<String> {
+        let mut capture = String::new();
+        for exchange in self.exchanges.lock().unwrap().iter() {
+            capture.push_str(&exchange.to_line()?);
+            capture.push('\n');
+        }
+        Ok(capture)
+    }
+
+    /// Write the capture to `path`. Only available where the actor has a file system, otherwise
+    /// ship `capture()` elsewhere.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        std::fs::write(path, self.capture()?)?;
+        Ok(())
+    }
+}
+
+impl HostTransport for RecordingTransport {
+    fn call(
+        &self,
+        binding: Option<&str>,
+        capid: &str,
+        operation: &str,
+        payload: Vec<u8>,
+    ) -> HandlerResult<Vec<u8>> {
+        let call = RecordedCall {
+            binding: binding.map(|b| b.to_string()),
+            capid: capid.to_string(),
+            operation: operation.to_string(),
+            payload: payload.clone(),
+        };
+        let result = self.inner.call(binding, capid, operation, payload);
+        let response = match &result {
+            Ok(bytes) => Ok(bytes.clone()),
+            Err(e) => Err(e.to_string()),
+        };
+        match self.exchanges.lock() {
+            Ok(mut exchanges) => exchanges.push(Exchange { call, response }),
+            Err(e) => error!("Recording transport lock failed, details: {:?}", e),
+        }
+        result
+    }
+}
+
+#[derive(Default)]
+struct Replay {
+    remaining: VecDeque<Exchange>,
+    divergences: Vec<String>,
+}
+
+/// Answers host calls from a capture, in order. A call that differs from the captured one in
+/// binding, capability, operation or request bytes fails, and so does `verify` afterwards.
+pub struct ReplayTransport {
+    replay: Mutex<Replay>,
+}
+
+impl ReplayTransport {
+    pub fn new(exchanges: Vec<Exchange>) -> Arc<Self> {
+        Arc::new(ReplayTransport {
+            replay: Mutex::new(Replay {
+                remaining: exchanges.into(),
+                divergences: Vec::new(),
+            }),
+        })
+    }
+
+    pub fn from_capture(capture: &str) -> anyhow::Result<Arc<Self>> {
+        Ok(ReplayTransport::new(parse_capture(capture)?))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Arc<Self>> {
+        ReplayTransport::from_capture(&std::fs::read_to_string(path)?)
+    }
+
+    /// Fails if a call diverged from the capture or captured calls were never made.
+    pub fn verify(&self) -> anyhow::Result<()> {
+        let replay = self.replay.lock().unwrap();
+        let mut problems = replay.divergences.clone();
+        if !replay.remaining.is_empty() {
+            problems.push(format!(
+                "{} captured call(s) not replayed, next is {} {}",
+                replay.remaining.len(),
+                replay.remaining[0].call.capid,
+                replay.remaining[0].call.operation
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("replay diverged: {}", problems.join("; ")))
+        }
+    }
+}
+
+impl HostTransport for ReplayTransport {
+    fn call(
+        &self,
+        binding: Option<&str>,
+        capid: &str,
+        operation: &str,
+        payload: Vec<u8>,
+    ) -> HandlerResult<Vec<u8>> {
+        let mut replay = self
+            .replay
+            .lock()
+            .map_err(|e| format!("replay transport lock failed: {:?}", e))?;
+        let divergence = match replay.remaining.front() {
+            None => Some(format!(
+                "unexpected call {} {} after the end of the capture",
+                capid, operation
+            )),
+            Some(expected) => {
+                let call = &expected.call;
+                if call.binding.as_deref() != binding
+                    || call.capid != capid
+                    || call.operation != operation
+                {
+                    Some(format!(
+                        "expected {} {} on {:?}, got {} {} on {:?}",
+                        call.capid, call.operation, call.binding, capid, operation, binding
+                    ))
+                } else if call.payload != payload {
+                    Some(format!("request of {} {} differs", capid, operation))
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(divergence) = divergence {
+            error!("{}", divergence);
+            replay.divergences.push(divergence.clone());
+            return Err(divergence.into());
+        }
+        match replay
+            .remaining
+            .pop_front()
+            .map(|exchange| exchange.response)
+        {
+            Some(Ok(bytes)) => Ok(bytes),
+            Some(Err(e)) => Err(e.into()),
+            None => Err("capture exhausted".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordingTransport, ReplayTransport};
+    use crate::transport::{self, ScriptedTransport};
+
+    #[test]
+    fn replays_a_recorded_session() {
+        let scripted = ScriptedTransport::new();
+        scripted.respond("tea:env", "Op", vec![1, 2]);
+        scripted.fail("tea:env", "Op", "host unavailable");
+        let recorder = RecordingTransport::new(scripted);
+        {
+            let _guard = transport::replace(recorder.clone());
+            assert_eq!(
+                transport::default().call("tea:env", "Op", vec![9]).unwrap(),
+                vec![1, 2]
+            );
+            assert!(transport::host("b").call("tea:env", "Op", vec![]).is_err());
+        }
+        let capture = recorder.capture().unwrap();
+
+        let replay = ReplayTransport::from_capture(&capture).unwrap();
+        {
+            let _guard = transport::replace(replay.clone());
+            assert_eq!(
+                transport::default().call("tea:env", "Op", vec![9]).unwrap(),
+                vec![1, 2]
+            );
+            let error = transport::host("b")
+                .call("tea:env", "Op", vec![])
+                .unwrap_err();
+            assert_eq!(error.to_string(), "host unavailable");
+        }
+        assert!(replay.verify().is_ok());
+
+        let replay = ReplayTransport::from_capture(&capture).unwrap();
+        {
+            let _guard = transport::replace(replay.clone());
+            assert!(transport::default().call("tea:env", "Op", vec![8]).is_err());
+        }
+        assert!(replay.verify().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use super::HostTransport;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use wascc_actor::prelude::*;
+
+/// A host call as seen by a `ScriptedTransport`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedCall {
+    pub binding: Option<String>,
+    pub capid: String,
+    pub operation: String,
+    pub payload: Vec<u8>,
+}
+
+type Responder = Box<dyn FnMut(&RecordedCall) -> HandlerResult<Vec<u8>> + Sync + Send + 'static>;
+
+#[derive(Default)]
+struct Script {
+    // queued answers per (capid, operation), consumed in order
+    answers: HashMap<(String, String), VecDeque<Responder>>,
+    calls: Vec<RecordedCall>,
+    fallback: Option<Arc<dyn HostTransport>>,
+}
+
+/// In-memory transport answering host calls from a script and recording every call it receives.
+///
+/// A call without a scripted answer goes to the `fall_back_to` transport, or fails with an error
+/// naming its capability and operation.
+#[derive(Clone, Default)]
+pub struct ScriptedTransport {
+    script: Arc<Mutex<Script>>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> Arc<Self> {
+        Arc::new(ScriptedTransport::default())
+    }
+
+    /// Answer the next call of `operation` on `capid` with `response`.
+    pub fn respond(&self, capid: &str, operation: &str, response: Vec<u8>) -> &Self {
+        self.respond_with(capid, operation, move |_| Ok(response.clone()))
+    }
+
+    /// Fail the next call of `operation` on `capid` with `error`.
+    pub fn fail(&self, capid: &str, operation: &str, error: &str) -> &Self {
+        let error = error.to_string();
+        self.respond_with(capid, operation, move |_| Err(error.clone().into()))
+    }
+
+    /// Answer the next call of `operation` on `capid` with whatever `responder` returns for it.
+    pub fn respond_with<F>(&self, capid: &str, operation: &str, responder: F) -> &Self
+    where
+        F: FnMut(&RecordedCall) -> HandlerResult<Vec<u8>> + Sync + Send + 'static,
+    {
+        self.script
+            .lock()
+            .unwrap()
+            .answers
+            .entry((capid.to_string(), operation.to_string()))
+            .or_default()
+            .push_back(Box::new(responder));
+        self
+    }
+
+    /// Send calls without a scripted answer to `transport`, e.g. a `KvpSimulator`.
+    pub fn fall_back_to(&self, transport: Arc<dyn HostTransport>) -> &Self {
+        self.script.lock().unwrap().fallback = Some(transport);
+        self
+    }
+
+    /// Every call received so far, oldest first.
+    pub fn calls(&self) -> Vec<RecordedCall> {
+        self.script.lock().unwrap().calls.clone()
+    }
+
+    /// Number of scripted answers not consumed yet.
+    pub fn unanswered(&self) -> usize {
+        self.script
+            .lock()
+            .unwrap()
+            .answers
+            .values()
+            .map(|queue| queue.len())
+            .sum()
+    }
+}
+
+impl HostTransport for ScriptedTransport {
+    fn call(
+        &self,
+        binding: Option<&str>,
+        capid: &str,
+        operation: &str,
+        payload: Vec<u8>,
+    ) -> HandlerResult<Vec<u8>> {
+        let call = RecordedCall {
+            binding: binding.map(|b| b.to_string()),
+            capid: capid.to_string(),
+            operation: operation.to_string(),
+            payload,
+        };
+        let (responder, fallback) = {
+            let mut script = self
+                .script
+                .lock()
+                .map_err(|e| format!("scripted transport lock failed: {:?}", e))?;
+            script.calls.push(call.clone());
+            let responder = script
+                .answers
+                .get_mut(&(call.capid.clone(), call.operation.clone()))
+                .and_then(|queue| queue.pop_front());
+            (responder, script.fallback.clone())
+        };
+        match (responder, fallback) {
+            (Some(mut responder), _) => responder(&call),
+            (None, Some(fallback)) => fallback.call(binding, capid, operation, call.payload),
+            (None, None) => {
+                Err(format!("no scripted answer for {} {}", call.capid, call.operation).into())
+            }
+        }
+    }
+}
@@ -0,0 +1,701 @@
+//! Workflows whose steps are undone in reverse order when a later step fails.
+//!
+//! Each step has an action and a compensating action. Both hand their outcome to a sink, so
+//! they may complete synchronously or from the callback of an asynchronous call. Progress is
+//! saved through a `Checkpoint` after every step, running a workflow again with the same id,
+//! e.g. from a restarted actor, continues where the saved progress stops.
+//!
+//! ```ignore
+//! Workflow::new(&txn_id, Arc::new(KvpCheckpoint::new(BINDING)))
+//!     .step("transfer", transfer, refund)
+//!     .step_sync("state", move || write_state(&txn), move || revert_state(&txn))
+//!     .step_sync("receipt", pin_receipt, || Ok(()))
+//!     .run(|outcome| {
+//!         match outcome {
+//!             Outcome::Completed => { /* code snipet... */ }
+//!             other => warn!("transaction not applied: {:?}", other),
+//!         }
+//!         Ok(())
+//!     })?;
+//! ```
+
+use crate::actor_kvp;
+use crate::actor_raft;
+use crate::error::Error;
+use crate::fanout::Sink;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Action or compensating action of a step.
+pub type StepFn = Box<dyn FnMut(Sink<()>) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+type Finish = Box<dyn FnOnce(Outcome) -> anyhow::Result<()> + Sync + Send + 'static>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Phase {
+    Running,
+    /// `failed_step` failed with `error`, completed steps are being compensated.
+    Compensating {
+        failed_step: String,
+        error: String,
+    },
+    /// Compensating `step` failed too, the remaining completed steps are left as they are.
+    CompensationFailed {
+        failed_step: String,
+        error: String,
+        step: String,
+        compensation_error: String,
+    },
+}
+
+/// What a `Checkpoint` saves for a workflow.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Steps whose action completed and that are not compensated yet, in order.
+    pub completed: Vec<String>,
+    pub phase: Phase,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Completed,
+    /// Every completed step was compensated after `failed_step` failed.
+    Compensated {
+        failed_step: String,
+        error: String,
+    },
+    CompensationFailed {
+        failed_step: String,
+        error: String,
+        step: String,
+        compensation_error: String,
+    },
+}
+
+/// Where the progress of workflows is kept.
+pub trait Checkpoint: Send + Sync {
+    fn save(&self, workflow_id: &str, progress: &Progress) -> anyhow::Result<()>;
+
+    fn load(&self, workflow_id: &str) -> anyhow::Result<Option<Progress>>;
+
+    /// Called once the workflow is completed or compensated.
+    fn clear(&self, workflow_id: &str) -> anyhow::Result<()>;
+}
+
+fn checkpoint_key(workflow_id: &str) -> String {
+    format!("workflow_{}", workflow_id)
+}
+
+/// Progress kept in a kvp binding, local to the node.
+pub struct KvpCheckpoint {
+    binding: &'static str,
+}
+
+impl KvpCheckpoint {
+    pub fn new(binding: &'static str) -> Self {
+        KvpCheckpoint { binding }
+    }
+}
+
+impl Checkpoint for KvpCheckpoint {
+    fn save(&self, workflow_id: &str, progress: &Progress) -> anyhow::Result<()> {
+        actor_kvp::set_forever(self.binding, &checkpoint_key(workflow_id), progress)?;
+        Ok(())
+    }
+
+    fn load(&self, workflow_id: &str) -> anyhow::Result<Option<Progress>> {
+        Ok(actor_kvp::get(self.binding, &checkpoint_key(workflow_id))?)
+    }
+
+    fn clear(&self, workflow_id: &str) -> anyhow::Result<()> {
+        actor_kvp::del(self.binding, &checkpoint_key(workflow_id))?;
+        Ok(())
+    }
+}
+
+/// Progress replicated through raft storage `storage_index`.
+pub struct RaftCheckpoint {
+    storage_index: u32,
+}
+
+impl RaftCheckpoint {
+    pub fn new(storage_index: u32) -> Self {
+        RaftCheckpoint { storage_index }
+    }
+}
+
+impl Checkpoint for RaftCheckpoint {
+    fn save(&self, workflow_id: &str, progress: &Progress) -> anyhow::Result<()> {
+        let value = tea_codec::serialize(progress)?;
+        actor_raft::raft_set_value(
+            &checkpoint_key(workflow_id),
+            &value,
+            self.storage_index,
+            workflow_id,
+        )?;
+        Ok(())
+    }
+
+    fn load(&self, workflow_id: &str) -> anyhow::Result<Option<Progress>> {
+        match actor_raft::raft_get_value(
+            &checkpoint_key(workflow_id),
+            self.storage_index,
+            workflow_id,
+        ) {
+            Ok(value) => Ok(Some(tea_codec::deserialize(value.as_slice())?)),
+            Err(Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn clear(&self, workflow_id: &str) -> anyhow::Result<()> {
+        actor_raft::raft_delete_value(
+            &checkpoint_key(workflow_id),
+            self.storage_index,
+            workflow_id,
+        )?;
+        Ok(())
+    }
+}
+
+struct Step {
+    name: String,
+    // taken out while running
+    action: Option<StepFn>,
+    compensate: Option<StepFn>,
+}
+
+pub struct Workflow {
+    id: String,
+    checkpoint: Arc<dyn Checkpoint>,
+    steps: Vec<Step>,
+}
+
+impl Workflow {
+    pub fn new(id: &str, checkpoint: Arc<dyn Checkpoint>) -> Self {
+        Workflow {
+            id: id.to_string(),
+            checkpoint,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Append step `name`. Names identify steps in the saved progress and must be unique.
+    pub fn step<A, C>(mut self, name: &str, action: A, compensate: C) -> Self
+    where
+        A: FnMut(Sink<()>) -> anyhow::Result<()> + Sync + Send + 'static,
+        C: FnMut(Sink<()>) -> anyhow::Result<()> + Sync + Send + 'static,
+    {
+        self.steps.push(Step {
+            name: name.to_string(),
+            action: Some(Box::new(action)),
+            compensate: Some(Box::new(compensate)),
+        });
+        self
+    }
+
+    /// `step` for actions completing synchronously.
+    pub fn step_sync<A, C>(self, name: &str, mut action: A, mut compensate: C) -> Self
+    where
+        A: FnMut() -> anyhow::Result<()> + Sync + Send + 'static,
+        C: FnMut() -> anyhow::Result<()> + Sync + Send + 'static,
+    {
+        self.step(
+            name,
+            move |mut sink: Sink<()>| sink(action()),
+            move |mut sink: Sink<()>| sink(compensate()),
+        )
+    }
+
+    /// Run the steps not completed yet according to the saved progress, then hand the outcome
+    /// to `callback`. A workflow saved as `CompensationFailed` attempts its compensation again.
+    pub fn run<F>(self, callback: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(Outcome) -> anyhow::Result<()> + Sync + Send + 'static,
+    {
+        let mut names = HashSet::new();
+        if let Some(step) = self.steps.iter().find(|s| !names.insert(s.name.as_str())) {
+            return Err(anyhow::anyhow!(
+                "workflow {} has two steps named {}",
+                self.id,
+                step.name
+            ));
+        }
+        let mut progress = self.checkpoint.load(&self.id)?.unwrap_or_else(|| Progress {
+            completed: Vec::new(),
+            phase: Phase::Running,
+        });
+        let resumable = progress
+            .completed
+            .iter()
+            .zip(self.steps.iter())
+            .all(|(done, step)| *done == step.name)
+            && progress.completed.len() <= self.steps.len();
+        if !resumable {
+            return Err(anyhow::anyhow!(
+                "saved progress of workflow {} does not match its steps: {:?}",
+                self.id,
+                progress.completed
+            ));
+        }
+        if let Phase::CompensationFailed {
+            failed_step, error, ..
+        } = progress.phase
+        {
+            progress.phase = Phase::Compensating { failed_step, error };
+        }
+        let state = Arc::new(Mutex::new(RunState {
+            workflow: self,
+            progress,
+            attempt: 0,
+            current: None,
+            driving: false,
+            followup: None,
+            callback: Some(Box::new(callback)),
+        }));
+        next(state)
+    }
+}
+
+struct RunState {
+    workflow: Workflow,
+    progress: Progress,
+    // number of the action or compensation in flight, later sink calls are ignored
+    attempt: u64,
+    // index of the step in flight, until it settles
+    current: Option<usize>,
+    // `next` is running a step on this stack, a step settling synchronously leaves what comes
+    // next in `followup` instead of recursing
+    driving: bool,
+    followup: Option<Followup>,
+    callback: Option<Finish>,
+}
+
+enum Followup {
+    Next,
+    Finish(Outcome),
+}
+
+impl RunState {
+    fn save(&self) -> anyhow::Result<()> {
+        self.workflow
+            .checkpoint
+            .save(&self.workflow.id, &self.progress)
+    }
+
+    fn index_of(&self, name: &str) -> anyhow::Result<usize> {
+        self.workflow
+            .steps
+            .iter()
+            .position(|step| step.name == name)
+            .ok_or_else(|| anyhow::anyhow!("workflow {} has no step {}", self.workflow.id, name))
+    }
+}
+
+fn lock(state: &Arc<Mutex<RunState>>) -> anyhow::Result<MutexGuard<'_, RunState>> {
+    state
+        .lock()
+        .map_err(|e| anyhow::anyhow!("workflow lock failed: {:?}", e))
+}
+
+// Steps completing synchronously are run in a loop, an asynchronous step resumes the loop from
+// its sink.
+fn next(state: Arc<Mutex<RunState>>) -> anyhow::Result<()> {
+    loop {
+        let (mut f, index, compensating, attempt) = {
+            let mut run = lock(&state)?;
+            let next = match run.progress.phase.clone() {
+                Phase::Running if run.progress.completed.len() == run.workflow.steps.len() => {
+                    Err(Outcome::Completed)
+                }
+                Phase::Running => Ok((run.progress.completed.len(), false)),
+                Phase::Compensating { failed_step, error } => match run.progress.completed.last() {
+                    Some(name) => Ok((run.index_of(name)?, true)),
+                    None => Err(Outcome::Compensated { failed_step, error }),
+                },
+                Phase::CompensationFailed { .. } => return Ok(()),
+            };
+            let (index, compensating) = match next {
+                Ok(next) => next,
+                Err(outcome) => {
+                    drop(run);
+                    return finish(state, outcome);
+                }
+            };
+            let step = &mut run.workflow.steps[index];
+            let f = if compensating {
+                step.compensate.take()
+            } else {
+                step.action.take()
+            };
+            let f =
+                f.ok_or_else(|| anyhow::anyhow!("workflow step {} is already running", step.name))?;
+            run.attempt += 1;
+            run.current = Some(index);
+            run.driving = true;
+            (f, index, compensating, run.attempt)
+        };
+        let sink_state = state.clone();
+        let sent = f(Box::new(move |result| {
+            settle(sink_state.clone(), attempt, result)
+        }));
+        {
+            let mut run = lock(&state)?;
+            let step = &mut run.workflow.steps[index];
+            if compensating {
+                step.compensate = Some(f);
+            } else {
+                step.action = Some(f);
+            }
+        }
+        if let Err(e) = sent {
+            settle(state.clone(), attempt, Err(e))?;
+        }
+        let followup = {
+            let mut run = lock(&state)?;
+            run.driving = false;
+            run.followup.take()
+        };
+        match followup {
+            Some(Followup::Next) => continue,
+            Some(Followup::Finish(outcome)) => return finish(state, outcome),
+            // the step settles later from its sink
+            None => return Ok(()),
+        }
+    }
+}
+
+fn settle(
+    state: Arc<Mutex<RunState>>,
+    attempt: u64,
+    result: anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let followup = {
+        let mut run = lock(&state)?;
+        let index = match run.current {
+            Some(index) if run.attempt == attempt => index,
+            _ => return Ok(()),
+        };
+        run.current = None;
+        let name = run.workflow.steps[index].name.clone();
+        let failed = match (result, run.progress.phase.clone()) {
+            (Ok(()), Phase::Running) => {
+                run.progress.completed.push(name.clone());
+                if let Err(e) = run.save() {
+                    // the step committed, so it is compensated as well
+                    run.progress.phase = Phase::Compensating {
+                        failed_step: name,
+                        error: format!("checkpoint failed: {:#}", e),
+                    };
+                    save_or_warn(&run);
+                }
+                None
+            }
+            (Err(e), Phase::Running) => {
+                warn!("workflow {} step {} failed: {:#}", run.workflow.id, name, e);
+                run.progress.phase = Phase::Compensating {
+                    failed_step: name,
+                    error: format!("{:#}", e),
+                };
+                save_or_warn(&run);
+                None
+            }
+            (Ok(()), _) => {
+                run.progress.completed.pop();
+                save_or_warn(&run);
+                None
+            }
+            (Err(e), Phase::Compensating { failed_step, error }) => {
+                error!(
+                    "workflow {} cannot compensate step {}: {:#}",
+                    run.workflow.id, name, e
+                );
+                run.progress.phase = Phase::CompensationFailed {
+                    failed_step: failed_step.clone(),
+                    error: error.clone(),
+                    step: name.clone(),
+                    compensation_error: format!("{:#}", e),
+                };
+                save_or_warn(&run);
+                Some(Outcome::CompensationFailed {
+                    failed_step,
+                    error,
+                    step: name,
+                    compensation_error: format!("{:#}", e),
+                })
+            }
+            (Err(_), Phase::CompensationFailed { .. }) => return Ok(()),
+        };
+        let followup = match failed {
+            Some(outcome) => Followup::Finish(outcome),
+            None => Followup::Next,
+        };
+        if run.driving {
+            run.followup = Some(followup);
+            return Ok(());
+        }
+        followup
+    };
+    match followup {
+        Followup::Finish(outcome) => finish(state, outcome),
+        Followup::Next => next(state),
+    }
+}
+
+fn save_or_warn(run: &RunState) {
+    if let Err(e) = run.save() {
+        warn!(
+            "cannot save progress of workflow {}: {}",
+            run.workflow.id, e
+        );
+    }
+}
+
+fn finish(state: Arc<Mutex<RunState>>, outcome: Outcome) -> anyhow::Result<()> {
+    let callback = {
+        let mut run = lock(&state)?;
+        if outcome == Outcome::Completed || matches!(outcome, Outcome::Compensated { .. }) {
+            if let Err(e) = run.workflow.checkpoint.clear(&run.workflow.id) {
+                warn!(
+                    "cannot clear progress of workflow {}: {}",
+                    run.workflow.id, e
+                );
+            }
+        }
+        run.callback.take()
+    };
+    match callback {
+        Some(callback) => callback(outcome),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, KvpCheckpoint, Outcome, Phase, Progress, RaftCheckpoint, Workflow};
+    use crate::fanout::Sink;
+    use crate::transport::{self, KvpSimulator, ScriptedTransport};
+    use std::sync::{Arc, Mutex};
+    use vmh_codec::message::{encode_protobuf, structs_proto::raft};
+
+    type Outcomes = Arc<Mutex<Option<Outcome>>>;
+
+    fn workflow(
+        log: &Arc<Mutex<Vec<String>>>,
+        fail: &'static str,
+        fail_undo: &'static str,
+    ) -> Workflow {
+        let mut workflow = Workflow::new("txn-1", Arc::new(KvpCheckpoint::new("default")));
+        for name in &["transfer", "state", "receipt"] {
+            let (action_log, undo_log) = (log.clone(), log.clone());
+            workflow = workflow.step_sync(
+                name,
+                move || {
+                    action_log.lock().unwrap().push(format!("do {}", name));
+                    if *name == fail {
+                        return Err(anyhow::anyhow!("{} unavailable", name));
+                    }
+                    Ok(())
+                },
+                move || {
+                    undo_log.lock().unwrap().push(format!("undo {}", name));
+                    if *name == fail_undo {
+                        return Err(anyhow::anyhow!("{} locked", name));
+                    }
+                    Ok(())
+                },
+            );
+        }
+        workflow
+    }
+
+    #[test]
+    fn failed_step_compensates_completed_ones_in_reverse_order() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let outcome = Arc::new(Mutex::new(None));
+
+        let result = outcome.clone();
+        workflow(&log, "receipt", "none")
+            .run(move |o| {
+                *result.lock().unwrap() = Some(o);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "do transfer",
+                "do state",
+                "do receipt",
+                "undo state",
+                "undo transfer"
+            ]
+        );
+        assert_eq!(
+            outcome.lock().unwrap().take(),
+            Some(Outcome::Compensated {
+                failed_step: "receipt".into(),
+                error: "receipt unavailable".into(),
+            })
+        );
+        assert!(kvp.keys("default").is_empty());
+
+        // a restarted actor continues after the saved progress
+        log.lock().unwrap().clear();
+        KvpCheckpoint::new("default")
+            .save(
+                "txn-1",
+                &Progress {
+                    completed: vec!["transfer".into()],
+                    phase: Phase::Running,
+                },
+            )
+            .unwrap();
+        let result = outcome.clone();
+        workflow(&log, "none", "none")
+            .run(move |o| {
+                *result.lock().unwrap() = Some(o);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["do state", "do receipt"]);
+        assert_eq!(outcome.lock().unwrap().take(), Some(Outcome::Completed));
+    }
+
+    fn run(workflow: Workflow, outcome: &Outcomes) {
+        let result = outcome.clone();
+        workflow
+            .run(move |o| {
+                *result.lock().unwrap() = Some(o);
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn failed_compensation_is_kept_and_retried() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let outcome = Arc::new(Mutex::new(None));
+
+        run(workflow(&log, "receipt", "state"), &outcome);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["do transfer", "do state", "do receipt", "undo state"]
+        );
+        assert_eq!(
+            outcome.lock().unwrap().take(),
+            Some(Outcome::CompensationFailed {
+                failed_step: "receipt".into(),
+                error: "receipt unavailable".into(),
+                step: "state".into(),
+                compensation_error: "state locked".into(),
+            })
+        );
+        let saved = KvpCheckpoint::new("default").load("txn-1").unwrap();
+        assert_eq!(
+            saved.map(|p| p.completed),
+            Some(vec!["transfer".to_string(), "state".to_string()])
+        );
+
+        // running it again compensates what is left instead of failing straight away
+        log.lock().unwrap().clear();
+        run(workflow(&log, "none", "none"), &outcome);
+        assert_eq!(*log.lock().unwrap(), vec!["undo state", "undo transfer"]);
+        assert_eq!(
+            outcome.lock().unwrap().take(),
+            Some(Outcome::Compensated {
+                failed_step: "receipt".into(),
+                error: "receipt unavailable".into(),
+            })
+        );
+        assert!(kvp.keys("default").is_empty());
+    }
+
+    #[test]
+    fn asynchronous_steps_settle_once() {
+        let kvp = KvpSimulator::new();
+        let _guard = transport::replace(kvp.clone());
+        let sinks: Arc<Mutex<Vec<Sink<()>>>> = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let outcome = Arc::new(Mutex::new(None));
+
+        let mut workflow = Workflow::new("txn-2", Arc::new(KvpCheckpoint::new("default")));
+        for name in &["reserve", "charge"] {
+            let (pending, action_log, undo_log) = (sinks.clone(), log.clone(), log.clone());
+            workflow = workflow.step(
+                name,
+                move |sink| {
+                    action_log.lock().unwrap().push(format!("do {}", name));
+                    pending.lock().unwrap().push(sink);
+                    Ok(())
+                },
+                move |mut sink| {
+                    undo_log.lock().unwrap().push(format!("undo {}", name));
+                    sink(Ok(()))
+                },
+            );
+        }
+        run(workflow, &outcome);
+        assert_eq!(*log.lock().unwrap(), vec!["do reserve"]);
+        assert_eq!(*outcome.lock().unwrap(), None);
+
+        let mut reserved = sinks.lock().unwrap().remove(0);
+        reserved(Ok(())).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["do reserve", "do charge"]);
+
+        // a late answer for the settled step does not touch the one in flight
+        reserved(Err(anyhow::anyhow!("late"))).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["do reserve", "do charge"]);
+        assert_eq!(*outcome.lock().unwrap(), None);
+
+        let mut charged = sinks.lock().unwrap().remove(0);
+        charged(Ok(())).unwrap();
+        assert_eq!(outcome.lock().unwrap().take(), Some(Outcome::Completed));
+        assert!(kvp.keys("default").is_empty());
+
+        // neither does a duplicate once the workflow finished
+        charged(Err(anyhow::anyhow!("duplicate"))).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["do reserve", "do charge"]);
+        assert_eq!(*outcome.lock().unwrap(), None);
+        assert!(kvp.keys("default").is_empty());
+    }
+
+    #[test]
+    fn missing_raft_progress_is_none() {
+        let scripted = ScriptedTransport::new();
+        let _guard = transport::replace(scripted.clone());
+        let checkpoint = RaftCheckpoint::new(1);
+
+        scripted.respond(
+            tea_codec::RAFT_CAPABILITY_ID,
+            "RaftGet",
+            encode_protobuf(raft::GetValueResponse::default()).unwrap(),
+        );
+        assert_eq!(checkpoint.load("txn-3").unwrap(), None);
+
+        let progress = Progress {
+            completed: vec!["transfer".into()],
+            phase: Phase::Running,
+        };
+        scripted.respond(
+            tea_codec::RAFT_CAPABILITY_ID,
+            "RaftGet",
+            encode_protobuf(raft::GetValueResponse {
+                value: Some(raft::ValueItem {
+                    value: tea_codec::serialize(&progress).unwrap(),
+                }),
+                values: None,
+            })
+            .unwrap(),
+        );
+        assert_eq!(checkpoint.load("txn-3").unwrap(), Some(progress));
+
+        scripted.fail(tea_codec::RAFT_CAPABILITY_ID, "RaftGet", "raft down");
+        assert!(checkpoint.load("txn-3").is_err());
+    }
+}